@@ -35,15 +35,114 @@ local http = {}
 ---@field set_file fun(self: HTTPClientRequest, file_path: string): HTTPClientRequest Sets the for-upload file path
 ---@field execute fun(self: HTTPClientRequest): HTTPClientResponse Executes the request and returns the response
 ---@field execute_streaming fun(self: HTTPClientRequest, callback: http_client_callback) Executes the request in a streaming manner
----@field execute_websocket fun(self: HTTPClientRequest, callback: wscallback) Executes the request as an async task
+---@field execute_websocket fun(self: HTTPClientRequest, callback: wscallback, options: HTTPWebSocketOptions?) Executes the request as an async task
 
 ---@diagnostic disable-next-line: duplicate-doc-alias
 ---@alias callback fun(request: HTTPServerRequest, response: HTTPServerResponse): any
+---@alias middleware_callback fun(request: HTTPServerRequest, response: HTTPServerResponse, next: fun()): any
+
+---@class HTTPCorsConfiguration
+---@field origins? string[] Origins allowed to make requests, `"*"` allows any. Defaults to any
+---@field methods? string[] Allowed request methods, `"*"` allows any. Defaults to any
+---@field headers? string[] Allowed request headers, `"*"` allows any. Defaults to any
+---@field credentials? boolean Allows cookies and authorization headers to be sent along
+---@field max_age? number How many seconds browsers may cache the preflight response for
+
+---@class HTTPRateLimitConfiguration
+---@field requests number How many requests are allowed per window
+---@field window? number Length of the window in seconds, defaults to a second
+---@field burst? number How many requests can be made at once before being throttled, defaults to `requests`
+---@field header? string Counts the requests per value of this header instead of per IP address when present
+---Counts the requests per returned key instead of per IP address, returning `nil` skips the limit
+---@field key? fun(request: HTTPServerRequest): string?
+
+---@class HTTPSessionConfiguration
+---Where the sessions are kept: `"memory"`, `"file"` or an SQLite database. Defaults to memory
+---@field store? "memory"|"file"|Database
+---@field path? string Directory of the file store, defaults to `sessions`
+---@field table? string Table of the SQLite store, created when missing. Defaults to `sessions`
+---@field cookie? string Name of the session cookie, defaults to `astra_session`
+---@field max_age? number Seconds a session lives after it was last used, defaults to a day
+---@field secure? boolean Only sends the session cookie over HTTPS
+
+---@class HTTPCsrfConfiguration
+---@field cookie? string Name of the cookie holding the token, defaults to `csrf_token`
+---@field header? string Header the token can be sent in, defaults to `X-CSRF-Token`
+---@field field? string Form, multipart or JSON field the token can be sent in, defaults to `csrf_token`
+---@field secure? boolean Only sends the token cookie over HTTPS
 
 ---@class HTTPRouteConfiguration
 ---@field body_limit? number
 ---@field compression? boolean
 ---@field headers? table<string, string>
+---Replaces the server wide CORS configuration for this route
+---@field cors? HTTPCorsConfiguration
+---Responds with 429 once a client goes over the limit
+---@field rate_limit? HTTPRateLimitConfiguration
+---Seconds the handler gets before the request is answered with 504 Gateway Timeout
+---@field timeout? number
+---A `validation` schema the path parameters are checked against, failing ones get 400 Bad Request
+---@field params? table
+---A `validation` schema the JSON body is checked against before the handler runs, failing ones get 422
+---@field body_schema? table
+---A `validation` schema the query string is checked against before the handler runs, failing ones get 422
+---@field query_schema? table
+---Set to `false` to skip the CSRF check of the server, for webhooks and other cross-site callers
+---@field csrf? boolean
+---Runs before a WebSocket is accepted. Returning a value rejects the upgrade with the status set on
+---the response, `403 Forbidden` unless it is an error or redirect
+---@field upgrade? fun(request: HTTPServerRequest, response: HTTPServerResponse): any
+---Subprotocols a WebSocket accepts, picked from those the client offers
+---@field protocols? string[]
+---Largest WebSocket message in bytes, `recv` fails on bigger ones
+---@field max_message_size? integer
+---Largest WebSocket frame in bytes
+---@field max_frame_size? integer
+---Seconds between the pings that keep a WebSocket alive
+---@field ping_interval? number
+---Seconds a WebSocket can go without receiving anything before `recv` closes it and returns `nil`
+---@field idle_timeout? number
+---Describes the route in the OpenAPI document, or leaves it out when `false`. Groups pass their tags on
+---@field openapi? HTTPOpenAPIOperation|false
+
+---@class HTTPOpenAPIOperation
+---@field summary? string
+---@field description? string
+---@field tags? string[]
+---@field operation_id? string
+---@field deprecated? boolean
+---Responses by status code, given as their description or with the schema of their body
+---@field responses? table<number|string, string|HTTPOpenAPIResponse>
+
+---@class HTTPOpenAPIResponse
+---@field description string
+---A `validation` schema or a JSON Schema table for the body
+---@field schema? table
+---@field content_type? string Defaults to `application/json`
+
+---@class HTTPOpenAPIInfo
+---@field title? string Defaults to `Astra`
+---@field version? string Defaults to the version of the server
+---@field description? string
+---@field servers? { url: string, description: string? }[]
+
+---@class HTTPOpenAPIDocsOptions
+---@field path? string Path of the JSON document, defaults to `/openapi.json`
+---@field docs? string|false Path of the docs page, defaults to `/docs`
+
+---@class HTTPStaticDirConfiguration: HTTPRouteConfiguration
+---Serves the `index.html` of the directory for paths that do not exist, for client side routing
+---@field spa? boolean
+---Serves the `.br`, `.gz` and `.zst` variants of files to clients that accept them
+---@field precompressed? boolean
+---`Cache-Control` header of the files that were found
+---@field cache_control? string
+---Regex for the paths of content hashed assets, which are cached for a year as immutable
+---@field immutable? string
+---Lists the contents of directories without an `index.html`
+---@field listing? boolean
+---File within the directory served with 404 Not Found for paths that do not exist
+---@field not_found? string
 
 ---@class HTTPRoute
 ---@field path string
@@ -52,6 +151,8 @@ local http = {}
 ---@field static_dir string?
 ---@field static_file string?
 ---@field config HTTPRouteConfiguration?
+---@field group HTTPServer? The routes of a group
+---@field methods string[]? The HTTP methods of a `route` entry
 
 ---@class IPAddress
 ---@field address string
@@ -74,33 +175,133 @@ local http = {}
 ---@field fields fun(): table Returns all multipart fields as an array
 ---@field get_field fun(name: string): HTTPMultipartField Returns a specific field by name
 ---@field file_name fun(): string|nil Returns the first filename found in the multipart data
----@field save_file fun(multipart: HTTPMultipart, file_path: string | nil): string | nil Saves the multipart into disk
+---Saves the named field, or the first file, to the given path. Given a directory or no path, the file keeps the
+---last component of the name the client sent. Returns the path
+---@field save_file fun(multipart: HTTPMultipart, file_path: string | nil, field_name: string | nil): string
+
+---@class HTTPMultipartStreamOptions
+---@field max_size? number Bytes all of the fields can take up together
+---@field max_field_size? number Bytes a single field can take up
+---@field field_sizes? table<string, number> Bytes specific fields can take up, by name
+---@field content_types? string[] Content types files can have, such as `image/png` or `image/*`
+
+---@class HTTPMultipartStreamField
+---@field name fun(self: HTTPMultipartStreamField): string
+---@field file_name fun(self: HTTPMultipartStreamField): string|nil
+---@field content_type fun(self: HTTPMultipartStreamField): string|nil
+---@field headers fun(self: HTTPMultipartStreamField): table
+---Returns the next chunk of the field as it arrives, `nil` once the field is done
+---@field chunk fun(self: HTTPMultipartStreamField): string|nil
+---@field text fun(self: HTTPMultipartStreamField): string
+---@field bytes fun(self: HTTPMultipartStreamField): table Returns the field data as bytes (table of numbers)
+---Writes the field to disk as it arrives, returning the amount of bytes written
+---@field save_file fun(self: HTTPMultipartStreamField, file_path: string): number
+
+---@class HTTPMultipartStream
+---Moves on to the next field, skipping whatever is left of the previous one. `nil` once the upload is done
+---@field next_field fun(self: HTTPMultipartStream): HTTPMultipartStreamField|nil
+
+---@class HTTPSession
+---Values can also be read and written as fields, `session.user_id = 5`
+---@field [string] any
+---@field get fun(self: HTTPSession, key: string): any
+---Stores a JSON serializable value, `nil` removes the key
+---@field set fun(self: HTTPSession, key: string, value: any)
+---@field remove fun(self: HTTPSession, key: string)
+---@field all fun(self: HTTPSession): table
+---@field clear fun(self: HTTPSession)
+---@field id fun(self: HTTPSession): string
+---Moves the session to a new id, which should be done whenever a user logs in
+---@field regenerate fun(self: HTTPSession): string
+---Removes the session from the store and clears the cookie
+---@field destroy fun(self: HTTPSession)
+---Keeps a value for the next request only
+---@field flash fun(self: HTTPSession, key: string, value: any)
+---Flash values set by the previous request, all of them when no key is given
+---@field get_flash fun(self: HTTPSession, key: string?): any
 
 ---@class HTTPServerRequest
 ---@field method fun(self: HTTPServerRequest): string Returns the HTTP method (e.g., "GET", "POST").
 ---@field uri fun(self: HTTPServerRequest): string
 ---@field queries fun(self: HTTPServerRequest): table
+---Path parameters converted to their declared types, `{id:int}` for example
 ---@field params fun(self: HTTPServerRequest): table
 ---@field headers fun(self: HTTPServerRequest): table
 ---@field form fun(self: HTTPServerRequest): table
 ---@field body fun(self: HTTPServerRequest): Buffer Returns the body of the request, which can be a table or a string.
----@field ip_address fun(self: HTTPServerRequest): IPAddress
+---Returns an iterator over the body chunks as they arrive, without buffering the whole body
+---@field body_stream fun(self: HTTPServerRequest): fun(): string|nil
+---Returns `nil` for clients connected over a Unix socket
+---@field ip_address fun(self: HTTPServerRequest): IPAddress|nil
 ---@field multipart fun(self: HTTPServerRequest): HTTPMultipart
+---Reads the multipart fields one by one as they arrive, without buffering the whole upload
+---@field multipart_stream fun(self: HTTPServerRequest, options: HTTPMultipartStreamOptions?): HTTPMultipartStream
 ---@field get_cookie fun(self: HTTPServerRequest, name: string): Cookie
+---Returns the cookie only if its signature checks out against one of the server's `cookie_key`s
+---@field get_signed_cookie fun(self: HTTPServerRequest, name: string): Cookie?
+---Returns the decrypted cookie only if it was encrypted with one of the server's `cookie_key`s
+---@field get_private_cookie fun(self: HTTPServerRequest, name: string): Cookie?
 ---@field new_cookie fun(self: HTTPServerRequest, name: string, value: string): Cookie
+---The CSRF token to send back with forms and scripts, `nil` when `server.csrf` is not set
+---@field csrf_token fun(self: HTTPServerRequest): string?
+---The session of the client, only available when `server.session` is set
+---@field session fun(self: HTTPServerRequest): HTTPSession
+---Seconds left before the handler times out, `nil` when there is no timeout
+---@field time_remaining fun(self: HTTPServerRequest): number?
+
+---@class HTTPResponseStream
+---Writes a chunk of text or bytes to the client. Returns `false` once the client has disconnected
+---@field write fun(self: HTTPResponseStream, chunk: string|number[]): boolean
+---@field is_closed fun(self: HTTPResponseStream): boolean
+
+---@class HTTPServerSentEvent
+---@field data? string|table Tables are sent as JSON
+---@field event? string
+---@field id? string
+---@field retry? number Reconnection time in milliseconds
+---@field comment? string
+
+---@class HTTPServerSentEventsOptions
+---Seconds between keep-alive comments, `false` to disable them. Defaults to 15 seconds
+---@field keep_alive? number|boolean
+
+---@class HTTPServerSentEvents
+---Sends an event to the client. Returns `false` once the client has disconnected
+---@field send fun(self: HTTPServerSentEvents, event: string|HTTPServerSentEvent): boolean
+---@field comment fun(self: HTTPServerSentEvents, comment: string): boolean
+---@field is_closed fun(self: HTTPServerSentEvents): boolean
+
+---@class HTTPSendFileOptions
+---Name the client saves the file under, sent as `Content-Disposition`
+---@field filename? string
+---Asks the client to download the file rather than display it, defaults to `true` when a filename is given
+---@field attachment? boolean
+---Replaces the type guessed from the extension of the file
+---@field content_type? string
 
 ---@class HTTPServerResponse
 ---Sets the HTTP status code of the response
 ---@field set_status_code fun(self: HTTPServerResponse, new_status_code: number)
+---@field get_status_code fun(self: HTTPServerResponse): number
 ---@field set_header fun(self: HTTPServerResponse, key: string, value: string)
 ---Returns the entire headers list that so far has been set for the response
 ---@field get_headers fun(self: HTTPServerResponse): table|nil
 ---@field remove_header fun(self: HTTPServerResponse, key: string)
 ---@field set_cookie fun(self: HTTPServerResponse, cookie: Cookie)
+---Signs the cookie with the server's `cookie_key` so it cannot be tampered with
+---@field set_signed_cookie fun(self: HTTPServerResponse, cookie: Cookie)
+---Encrypts the cookie with the server's `cookie_key` so it can neither be read nor tampered with
+---@field set_private_cookie fun(self: HTTPServerResponse, cookie: Cookie)
 ---@field remove_cookie fun(self: HTTPServerResponse, cookie: Cookie)
 ---@field redirect_to fun(self: HTTPServerResponse, redirect_uri: string)
 ---@field redirect_temporary fun(self: HTTPServerResponse, redirect_uri: string)
 ---@field redirect_permanent fun(self: HTTPServerResponse, redirect_uri: string)
+---Streams the body through the callback, which runs after the route returns
+---@field stream fun(self: HTTPServerResponse, callback: fun(stream: HTTPResponseStream))
+---Responds with Server-Sent Events produced by the callback, which runs after the route returns
+---@field sse fun(self: HTTPServerResponse, callback: fun(sse: HTTPServerSentEvents), options: HTTPServerSentEventsOptions?)
+---Streams the file from disk once the route returns, answering range and conditional requests
+---@field send_file fun(self: HTTPServerResponse, path: string, options: HTTPSendFileOptions?)
 
 ---@class Cookie
 ---@field set_name fun(self: Cookie, name: string)
@@ -139,15 +340,99 @@ local http = {}
 ---A binary WebSocket message
 ---@field send_bytes fun(socket: WebSocket, bytes: table)
 ---@field send_close fun(socket: WebSocket, close_frame: CloseFrame?)
+---The subprotocol agreed on during the handshake
+---@field protocol fun(socket: WebSocket): string?
 
 ---@alias wscallback fun(socket: WebSocket): any
 
+---@class HTTPWebSocketOptions
+---Subprotocols offered to the server, in order of preference
+---@field protocols? string[]
+---Largest message in bytes, `recv` fails on bigger ones
+---@field max_message_size? integer
+---Largest frame in bytes
+---@field max_frame_size? integer
+---Seconds between the pings that keep the connection alive
+---@field ping_interval? number
+---Seconds the connection can go without receiving anything before `recv` closes it and returns `nil`
+---@field idle_timeout? number
+
+---@class HTTPServerWebSocket: WebSocket
+---A ping WebSocket message
+---@field send_ping fun(socket: HTTPServerWebSocket, bytes: string|table)
+---A pong WebSocket message
+---@field send_pong fun(socket: HTTPServerWebSocket, bytes: string|table)
+---The ID of the connection, unique within the process
+---@field id fun(socket: HTTPServerWebSocket): string
+---Joins a room to receive its broadcasts. Connections leave all of their rooms when they close
+---@field join fun(socket: HTTPServerWebSocket, room: string): boolean
+---Leaves a room, returning whether the connection was in it
+---@field leave fun(socket: HTTPServerWebSocket, room: string): boolean
+---@field rooms fun(socket: HTTPServerWebSocket): string[]
+
+---@class HTTPWebSocketSendOptions
+---Sends the message as bytes instead of text
+---@field binary? boolean
+
+---@class HTTPWebSocketBroadcastOptions: HTTPWebSocketSendOptions
+---ID of a connection to leave out, usually the one sending the message
+---@field except? string
+
 ----------------------------------------------------------------------------------------
 ----------------------------------------------------------------------------------------
 ----------------------------------------------------------------------------------------
 
+---@class HTTPServerTLSConfiguration
+---@field cert string Path to the PEM encoded certificate chain
+---@field key string Path to the PEM encoded private key
+---@field client_ca? string Path to the PEM encoded CA bundle for verifying client certificates (mTLS)
+---@field client_auth_optional? boolean Allows clients without a certificate when `client_ca` is set
+
+---@class HTTPUnixSocket
+---@field unix string Path of the socket file
+---@field permissions? string Octal file mode of the socket, such as `"660"`
+
 ---@class HTTPServer
 ---@field shutdown fun(HTTPServer) Shuts down the server
+---Whether the server is shutting down. Only available while running
+---@field is_draining fun(HTTPServer): boolean
+---Serves a health check at this path, `true` for `/health`. It answers 503 with `draining` during shutdown
+---@field health boolean|string|nil
+---Seconds in-flight requests get after shutting down before being cut off. Waits for them by default
+---@field shutdown_timeout number?
+---Seconds the server keeps accepting requests after a shutdown starts, while the health check reports `draining`
+---@field shutdown_delay number?
+---Set through `server:on_start`
+---@field start_hooks fun(server: HTTPServer)[]?
+---Set through `server:on_shutdown`
+---@field shutdown_hooks fun(server: HTTPServer)[]?
+---Reloads the TLS certificates from disk without restarting. Only available while running with `tls`
+---@field reload_tls fun(HTTPServer)
+---Serves HTTPS when set
+---@field tls HTTPServerTLSConfiguration?
+---Addresses to listen on instead of `hostname` and `port`: `"host:port"`, `"unix:/path"` or a Unix socket with permissions
+---@field listen string|HTTPUnixSocket|(string|HTTPUnixSocket)[]|nil
+---Every address that was bound once the server is listening, with the ports picked for port 0
+---@field addresses string[]?
+---Secret of at least 32 bytes for signed and private cookies. When a list is given the first one
+---is used for new cookies and the rest are still accepted, which allows rotating the secret
+---@field cookie_key string|string[]|nil
+---Answers preflight requests and adds the CORS headers for every route
+---@field cors HTTPCorsConfiguration?
+---Limits the requests across every route together, on top of any route limits
+---@field rate_limit HTTPRateLimitConfiguration?
+---Keeps server-side sessions for `request:session()`
+---@field session HTTPSessionConfiguration?
+---Rejects POST, PUT, PATCH and DELETE requests that do not send back the token of their cookie with 403
+---@field csrf boolean|HTTPCsrfConfiguration|nil
+---Seconds every handler gets before the request is answered with 504 Gateway Timeout, unless the route sets its own
+---@field timeout number?
+---Largest request body in bytes, for routes without their own `body_limit` and for the server middleware
+---@field body_limit number?
+---Shows the error, request and route on a debug page when a handler fails. Keep it off in production
+---@field development boolean?
+---Set through `server:on_error`
+---@field error_handler fun(err: string, request: HTTPServerRequest, response: HTTPServerResponse): any
 ---@diagnostic disable-next-line: missing-fields
 local HTTPServer = {
   version = "0.0.0",
@@ -157,6 +442,8 @@ local HTTPServer = {
   port = 8080,
   --- Contains all of the route details
   routes = {},
+  --- Contains the server wide middleware hooks in the order they were registered
+  middleware = {},
 }
 function HTTPServer:new()
   local server = {
@@ -167,6 +454,8 @@ function HTTPServer:new()
     port = 8080,
     --- Contains all of the route details
     routes = {},
+    --- Contains the server wide middleware hooks in the order they were registered
+    middleware = {},
   }
 
   setmetatable(server, self)
@@ -182,12 +471,14 @@ end
 
 local function add_to_routes(server, method, path, callback, config)
   local index = (path == "/") and 1 or #server.routes + 1
-  table.insert(server.routes, index, {
+  local route = {
     path = path,
     method = method,
     func = callback,
     config = config or {},
-  })
+  }
+  table.insert(server.routes, index, route)
+  return route
 end
 
 ---@param path string
@@ -240,8 +531,43 @@ function HTTPServer:trace(path, callback, config)
 end
 
 ---@param path string
----@param serve_path string
+---@param callback callback
+---@param config HTTPRouteConfiguration?
+function HTTPServer:head(path, callback, config)
+  add_to_routes(self, "head", path, callback, config)
+end
+
+---@param path string
+---@param callback callback
+---@param config HTTPRouteConfiguration?
+function HTTPServer:connect(path, callback, config)
+  add_to_routes(self, "connect", path, callback, config)
+end
+
+---Answers every HTTP method, `request:method()` tells which one was used
+---@param path string
+---@param callback callback
+---@param config HTTPRouteConfiguration?
+function HTTPServer:any(path, callback, config)
+  add_to_routes(self, "any", path, callback, config)
+end
+
+---Answers the given HTTP methods, which can include custom ones, with a single handler.
+---`request:method()` tells which one was used
+---@param methods string|string[]
+---@param path string
+---@param callback callback
 ---@param config HTTPRouteConfiguration?
+function HTTPServer:route(methods, path, callback, config)
+  if type(methods) == "string" then
+    methods = { methods }
+  end
+  add_to_routes(self, "route", path, callback, config).methods = methods
+end
+
+---@param path string
+---@param serve_path string
+---@param config HTTPStaticDirConfiguration?
 function HTTPServer:static_dir(path, serve_path, config)
   table.insert(self.routes, {
     path = path,
@@ -266,7 +592,7 @@ function HTTPServer:static_file(path, serve_path, config)
 end
 
 ---@param path string
----@param wscallback wscallback
+---@param wscallback fun(socket: HTTPServerWebSocket, request: HTTPServerRequest): any
 ---@param config HTTPRouteConfiguration?
 function HTTPServer:websocket(path, wscallback, config)
   add_to_routes(self, "web_socket", path, wscallback, config)
@@ -277,6 +603,109 @@ function HTTPServer:fallback(callback)
   add_to_routes(self, "fallback", "", callback, {})
 end
 
+---Registers the routes added in the callback under a shared prefix. The group takes the same
+---methods as the server, and its configuration, middleware and fallback only apply to its routes.
+---Routes can still override the configuration of their group
+---@param prefix string
+---@param config HTTPRouteConfiguration|fun(group: HTTPServer)
+---@param callback? fun(group: HTTPServer)
+function HTTPServer:group(prefix, config, callback)
+  if type(config) == "function" then
+    callback, config = config, nil
+  end
+  assert(type(callback) == "function", "Group callback must be a function, got " .. type(callback))
+
+  local group = HTTPServer:new()
+  callback(group)
+
+  table.insert(self.routes, {
+    path = prefix,
+    method = "group",
+    func = function() end,
+    group = group,
+    config = config or {},
+  })
+end
+
+local function add_to_middleware(server, kind, callback)
+  table.insert(server.middleware, {
+    kind = kind,
+    func = callback,
+  })
+end
+
+---Wraps every request, including static files, websockets and the fallback. The rest of the
+---pipeline runs when `next` is called, so anything after it sees the outgoing response.
+---Not calling `next` responds with the returned value instead
+---@param callback middleware_callback
+function HTTPServer:use(callback)
+  add_to_middleware(self, "use", callback)
+end
+
+---Runs before every request. Returning a value or redirecting responds right away
+---@param callback callback
+function HTTPServer:before(callback)
+  add_to_middleware(self, "before", callback)
+end
+
+---Runs after every request with the outgoing response, whose status and headers can still be changed
+---@param callback fun(request: HTTPServerRequest, response: HTTPServerResponse)
+function HTTPServer:after(callback)
+  add_to_middleware(self, "after", callback)
+end
+
+---Renders the response for requests whose handler or middleware raised an error. The response
+---starts out with a 500 status code, and the returned value becomes its body
+---@param callback fun(err: string, request: HTTPServerRequest, response: HTTPServerResponse): any
+function HTTPServer:on_error(callback)
+  self.error_handler = callback
+end
+
+---Runs once the server is listening, alongside it so that the callback can make requests to it
+---@param callback fun(server: HTTPServer)
+function HTTPServer:on_start(callback)
+  self.start_hooks = self.start_hooks or {}
+  table.insert(self.start_hooks, callback)
+end
+
+---Runs when the server starts shutting down, before the in-flight requests are drained. Useful
+---for closing WebSockets and flushing work
+---@param callback fun(server: HTTPServer)
+function HTTPServer:on_shutdown(callback)
+  self.shutdown_hooks = self.shutdown_hooks or {}
+  table.insert(self.shutdown_hooks, callback)
+end
+
+---Describes the routes of the server as an OpenAPI 3.1 document, including their path parameters
+---and validation schemas. Returns a table, or a string for the `json`, `yaml` and `html` formats
+---@param info HTTPOpenAPIInfo?
+---@param format? "json"|"yaml"|"html"
+---@return table|string
+function HTTPServer:openapi(info, format)
+  ---@diagnostic disable-next-line: undefined-global
+  return astra_internal__openapi(self, info, format)
+end
+
+---Serves the OpenAPI document of the server along with a page listing its routes. Both are
+---left out of the document themselves
+---@param info HTTPOpenAPIInfo?
+---@param options HTTPOpenAPIDocsOptions?
+function HTTPServer:openapi_docs(info, options)
+  options = options or {}
+
+  self:get(options.path or "/openapi.json", function(_, response)
+    response:set_header("Content-Type", "application/json")
+    return self:openapi(info, "json")
+  end, { openapi = false })
+
+  if options.docs ~= false then
+    self:get(options.docs or "/docs", function(_, response)
+      response:set_header("Content-Type", "text/html; charset=utf-8")
+      return self:openapi(info, "html")
+    end, { openapi = false })
+  end
+end
+
 ---Runs the server
 function HTTPServer:run()
   ---@diagnostic disable-next-line: undefined-global
@@ -323,6 +752,46 @@ function http.middleware.chain(chain)
   end
 end
 
+---The WebSockets of all servers, grouped into rooms they join with `socket:join(room)`
+http.ws = {}
+
+---Sends a message to every connection in the room. Strings and tables are sent as text, tables
+---as JSON. Connections that have closed are skipped
+---@param room string
+---@param message any
+---@param options HTTPWebSocketBroadcastOptions?
+---@return integer sent How many connections the message was sent to
+function http.ws.broadcast(room, message, options)
+  ---@diagnostic disable-next-line: undefined-global
+  return astra_internal__ws_broadcast(room, message, options)
+end
+
+---Sends a message to a single connection by its ID
+---@param id string
+---@param message any
+---@param options HTTPWebSocketSendOptions?
+---@return boolean sent `false` if the connection is gone
+function http.ws.send(id, message, options)
+  ---@diagnostic disable-next-line: undefined-global
+  return astra_internal__ws_send(id, message, options)
+end
+
+---How many connections are in the room
+---@param room string
+---@return integer
+function http.ws.count(room)
+  ---@diagnostic disable-next-line: undefined-global
+  return astra_internal__ws_count(room)
+end
+
+---The IDs of the connections in the room
+---@param room string
+---@return string[]
+function http.ws.members(room)
+  ---@diagnostic disable-next-line: undefined-global
+  return astra_internal__ws_members(room)
+end
+
 ---Opens a new async HTTP Request. The request is running as a task in parallel
 ---@param details string | HTTPClientRequestTableType
 ---@return HTTPClientRequest
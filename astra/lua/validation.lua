@@ -135,6 +135,222 @@ function check.literal(self, v)
   end
 end
 
+local function join(path, key)
+  if path == "" then
+    return tostring(key)
+  end
+  return path .. "." .. tostring(key)
+end
+
+-- unlike validate, keeps going after the first failure to gather every failing path
+---@param path string
+---@param out { path: string, message: string }[]
+local function collect(self, v, path, out)
+  if self.kind == "struct" and type(v) == "table" then
+    for k, f in pairs(self.m) do
+      collect(f, v[k], join(path, k), out)
+    end
+    for k in pairs(v) do
+      if not self.m[k] then
+        out[#out + 1] = { path = join(path, k), message = "unexpected key" }
+      end
+    end
+  elseif self.kind == "array" and type(v) == "table" then
+    for i, item in ipairs(v) do
+      collect(self.t, item, path .. "[" .. tostring(i) .. "]", out)
+    end
+  elseif self.kind == "optional" then
+    if v ~= nil then
+      collect(self.t, v, path, out)
+    end
+  else
+    local ok, err = self:validate(v)
+    if not ok then
+      out[#out + 1] = { path = path, message = err }
+    end
+  end
+end
+
+ValidatorMt.__index.errors = function(self, v)
+  local out = {}
+  collect(self, v, "", out)
+  table.sort(out, function(a, b)
+    return a.path < b.path
+  end)
+  return out
+end
+
+-- the characters of the Lua character classes, as written within a regex set
+local PATTERN_CLASSES = {
+  a = "A-Za-z",
+  c = "\\x00-\\x1f\\x7f",
+  d = "0-9",
+  g = "!-~",
+  l = "a-z",
+  p = "!-/:-@\\[-`{-~",
+  s = " \\t\\n\\v\\f\\r",
+  u = "A-Z",
+  w = "A-Za-z0-9",
+  x = "A-Fa-f0-9",
+}
+
+-- translates a Lua pattern to the ECMA-262 regex JSON Schema expects, failing on the parts
+-- that have no equivalent
+local function pattern_to_regex(pattern)
+  local function fail(reason)
+    error(string.format("the Lua pattern %q cannot be described as a regex: %s", pattern, reason), 0)
+  end
+  local function escape(c, in_set)
+    local special = in_set and "[%]%[\\%^%-]" or "[%^%$\\%.%*%+%?%(%)%[%]{}|/]"
+    return c:match(special) and "\\" .. c or c
+  end
+  local function class(c, in_set)
+    local chars = PATTERN_CLASSES[c:lower()]
+    if not chars then
+      if c:match("%w") then
+        fail("%" .. c .. " has no equivalent")
+      end
+      return escape(c, in_set)
+    elseif c == c:lower() then
+      return in_set and chars or "[" .. chars .. "]"
+    elseif in_set then
+      fail("complemented classes within sets have no equivalent")
+    end
+    return "[^" .. chars .. "]"
+  end
+
+  local out = {}
+  local i, n = 1, #pattern
+  if pattern:sub(1, 1) == "^" then
+    out[1] = "^"
+    i = 2
+  end
+  while i <= n do
+    local c = pattern:sub(i, i)
+    local item, single = nil, true
+    if c == "%" then
+      if i == n then
+        fail("it ends with %")
+      end
+      item = class(pattern:sub(i + 1, i + 1), false)
+      i = i + 2
+    elseif c == "[" then
+      local set = { "[" }
+      local j = i + 1
+      if pattern:sub(j, j) == "^" then
+        set[2] = "^"
+        j = j + 1
+      end
+      local first = true
+      while true do
+        local d = pattern:sub(j, j)
+        if d == "" then
+          fail("a set is not closed")
+        elseif d == "]" and not first then
+          break
+        end
+        first = false
+        if d == "%" then
+          if j == n then
+            fail("it ends with %")
+          end
+          set[#set + 1] = class(pattern:sub(j + 1, j + 1), true)
+          j = j + 2
+        elseif pattern:sub(j + 1, j + 1) == "-" and j + 2 <= n and pattern:sub(j + 2, j + 2) ~= "]" then
+          set[#set + 1] = escape(d, true) .. "-" .. escape(pattern:sub(j + 2, j + 2), true)
+          j = j + 3
+        else
+          set[#set + 1] = escape(d, true)
+          j = j + 1
+        end
+      end
+      set[#set + 1] = "]"
+      item = table.concat(set)
+      i = j + 1
+    elseif c == "." then
+      item = "[\\s\\S]"
+      i = i + 1
+    elseif c == "(" or c == ")" then
+      if pattern:sub(i, i + 1) == "()" then
+        fail("position captures have no equivalent")
+      end
+      item, single = c, false
+      i = i + 1
+    elseif c == "$" and i == n then
+      item, single = c, false
+      i = i + 1
+    else
+      item = escape(c, false)
+      i = i + 1
+    end
+
+    -- quantifiers only apply to single character items, and are taken literally anywhere else
+    local q = pattern:sub(i, i)
+    if single and (q == "*" or q == "+" or q == "?" or q == "-") then
+      item = item .. (q == "-" and "*?" or q)
+      i = i + 1
+    end
+    out[#out + 1] = item
+  end
+
+  return table.concat(out)
+end
+
+-- the JSON Schema of a validator, as used by OpenAPI documents
+local function to_json_schema(self)
+  local schema
+  if self.kind == "string" then
+    schema = { type = "string", pattern = self.p and pattern_to_regex(self.p) }
+  elseif self.kind == "number" then
+    schema = { type = self.i and "integer" or "number" }
+    if self.r then
+      if self.r.min then
+        schema[self.r.minExclusive and "exclusiveMinimum" or "minimum"] = self.r.min
+      end
+      if self.r.max then
+        schema[self.r.maxExclusive and "exclusiveMaximum" or "maximum"] = self.r.max
+      end
+    end
+  elseif self.kind == "boolean" then
+    schema = { type = "boolean" }
+  elseif self.kind == "nil" then
+    schema = { type = "null" }
+  elseif self.kind == "struct" then
+    local properties, required = {}, {}
+    for k, f in pairs(self.m) do
+      properties[tostring(k)] = to_json_schema(f)
+      if f.kind ~= "optional" then
+        required[#required + 1] = tostring(k)
+      end
+    end
+    table.sort(required)
+    schema = { type = "object", properties = properties, additionalProperties = false }
+    if #required > 0 then
+      schema.required = required
+    end
+  elseif self.kind == "array" then
+    schema = { type = "array", items = to_json_schema(self.t) }
+  elseif self.kind == "optional" then
+    schema = to_json_schema(self.t)
+  elseif self.kind == "union" then
+    local members = {}
+    for _, t in ipairs(self.s) do
+      members[#members + 1] = to_json_schema(t)
+    end
+    schema = { anyOf = members }
+  elseif self.kind == "literal" then
+    schema = { const = self.v }
+  else
+    schema = {}
+  end
+  if self.default ~= nil then
+    schema.default = self.default
+  end
+  return schema
+end
+
+ValidatorMt.__index.json_schema = to_json_schema
+
 ---@param opts? { default?: string }
 ---@return string
 function string_type(opts)
@@ -243,6 +459,21 @@ function validate(v, value)
   return v:validate(value)
 end
 
+---Every failing path of the value rather than only the first one
+---@param v any
+---@param value any
+---@return { path: string, message: string }[]
+function errors(v, value)
+  return v:errors(value)
+end
+
+---The JSON Schema the value has to match, as used by OpenAPI documents
+---@param v any
+---@return table
+function json_schema(v)
+  return v:json_schema()
+end
+
 ---@generic T
 ---@param schema T
 ---@return T
@@ -282,6 +513,14 @@ function build(schema)
       validate = function(self, value)
         return self.schema:validate(value)
       end,
+      ---@diagnostic disable-next-line: undefined-field
+      errors = function(self, value)
+        return self.schema:errors(value)
+      end,
+      ---@diagnostic disable-next-line: undefined-field
+      json_schema = function(self)
+        return self.schema:json_schema()
+      end,
     },
   })
 end
@@ -313,6 +552,8 @@ return {
     range = range,
     pattern = pattern,
     validate = validate,
+    errors = errors,
+    json_schema = json_schema,
     build = build,
   },
   regex = regex,
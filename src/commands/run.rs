@@ -1,22 +1,98 @@
-use crate::{LUA, RUNTIME_FLAGS, components::database::DATABASE_POOLS};
-use std::path::PathBuf;
+use crate::{
+    RUNTIME_FLAGS,
+    components::{
+        database::DATABASE_POOLS,
+        http::server::{servers_stopped, stop_servers},
+        import::RequiredFiles,
+    },
+};
+use std::{collections::HashMap, path::PathBuf, time::Duration};
 use tracing::error;
 
+/// How often `--watch` checks the script and its modules for changes.
+const WATCH_INTERVAL: Duration = Duration::from_millis(500);
+/// How long the tasks of a run get to stop before the script is run again.
+const RELOAD_TIMEOUT: Duration = Duration::from_secs(1);
+
 /// Runs a Lua script.
 pub async fn run_command(
+    lua: mlua::Lua,
     file_path: Option<String>,
     code: Option<String>,
     stdlib_path: Option<String>,
     extra_args: Option<Vec<String>>,
 ) {
-    #[allow(clippy::expect_used)]
-    let lua = LUA.get().expect("Could not get access to the global VM");
+    let is_headless = code.is_some();
+    let (user_file, actual_path_str) = read_script(file_path, code);
+
+    run_command_prerequisite(&lua, &actual_path_str, stdlib_path, extra_args, is_headless).await;
+    spawn_termination_task();
+
+    execute_script(&lua, user_file, &actual_path_str).await;
+
+    // Wait for all Tokio tasks to finish.
+    let metrics = tokio::runtime::Handle::current().metrics();
+    loop {
+        let alive_tasks = metrics.num_alive_tasks();
+        if alive_tasks == 1 {
+            break;
+        }
+    }
+}
+
+/// Runs a Lua script, and runs it again in a new VM whenever it or a module it requires
+/// changes. The sockets of its servers stay open in between, so no request is refused.
+pub async fn watch_command(
+    file_path: Option<String>,
+    stdlib_path: Option<String>,
+    safe: bool,
+    extra_args: Option<Vec<String>>,
+) -> std::io::Result<()> {
+    crate::components::http::server::keep_sockets();
+    spawn_termination_task();
+
+    loop {
+        let (user_file, actual_path_str) = read_script(file_path.clone(), None);
+        let required = RequiredFiles::default();
+        if let Ok(mut files) = required.0.lock() {
+            files.insert(PathBuf::from(&actual_path_str));
+        }
+
+        // every run has its own runtime, which takes all of the tasks of the run with it
+        let runtime = tokio::runtime::Builder::new_multi_thread()
+            .enable_all()
+            .build()?;
+        let lua = crate::create_lua_vm(safe)?;
+        lua.set_app_data(required.clone());
+
+        runtime.spawn({
+            let stdlib_path = stdlib_path.clone();
+            let extra_args = extra_args.clone();
+            async move {
+                run_command_prerequisite(&lua, &actual_path_str, stdlib_path, extra_args, false)
+                    .await;
+                execute_script(&lua, user_file, &actual_path_str).await;
+            }
+        });
+
+        let changed = wait_for_change(&required).await;
+        tracing::info!("{} changed, running the script again", changed.display());
 
+        // the servers finish the requests they are handling and leave their sockets to the next run
+        stop_servers();
+        let _ = tokio::time::timeout(RELOAD_TIMEOUT, servers_stopped()).await;
+        // the pools are tied to the runtime that is about to be shut down
+        let _ = runtime.spawn(close_database_pools()).await;
+        let _ = tokio::task::spawn_blocking(move || runtime.shutdown_timeout(RELOAD_TIMEOUT)).await;
+        // servers that outlive the timeout still hold on to sockets the next run takes over
+        servers_stopped().await;
+    }
+}
+
+fn read_script(file_path: Option<String>, code: Option<String>) -> (String, String) {
     let mut actual_path: String = "init.lua".to_string();
 
-    // Load and execute the Lua script.
-    #[allow(clippy::expect_used)]
-    let (user_file, actual_path_str) = if let Some(code) = code.clone() {
+    if let Some(code) = code {
         actual_path = file_path.unwrap_or("<commandline>".to_string());
         (code, actual_path.clone())
     } else {
@@ -26,18 +102,10 @@ pub async fn run_command(
             check_for_default_file(&mut actual_path, ".".to_string())
         };
         (file, actual_path.clone())
-    };
-
-    run_command_prerequisite(
-        lua,
-        &actual_path_str,
-        stdlib_path,
-        extra_args,
-        code.is_some(),
-    )
-    .await;
-    spawn_termination_task();
+    }
+}
 
+async fn execute_script(lua: &mlua::Lua, user_file: String, actual_path_str: &str) {
     // Remove the Shebang lines
     let user_file = user_file
         .lines()
@@ -55,14 +123,36 @@ pub async fn run_command(
     if let Err(e) = content_to_run.exec_async().await {
         eprintln!("{}", e)
     }
+}
+
+/// Resolves with the first of the required files that changes after the call, including the
+/// ones required later on.
+async fn wait_for_change(required: &RequiredFiles) -> PathBuf {
+    let modified = |path: &PathBuf| {
+        std::fs::metadata(path)
+            .and_then(|metadata| metadata.modified())
+            .ok()
+    };
+    let mut known = HashMap::new();
 
-    // Wait for all Tokio tasks to finish.
-    let metrics = tokio::runtime::Handle::current().metrics();
     loop {
-        let alive_tasks = metrics.num_alive_tasks();
-        if alive_tasks == 1 {
-            break;
+        let files = required
+            .0
+            .lock()
+            .map(|files| files.clone())
+            .unwrap_or_default();
+        for path in files {
+            let current = modified(&path);
+            match known.get(&path) {
+                Some(previous) if *previous != current => return path,
+                Some(_) => {}
+                None => {
+                    known.insert(path, current);
+                }
+            }
         }
+
+        tokio::time::sleep(WATCH_INTERVAL).await;
     }
 }
 
@@ -79,9 +169,12 @@ async fn run_command_prerequisite(
 
     let stdlib_path = stdlib_path.unwrap_or("astra".to_string());
 
-    if let Err(e) = RUNTIME_FLAGS.set(crate::RuntimeFlags {
-        stdlib_path: PathBuf::from(stdlib_path.clone()),
-    }) {
+    // already set by the previous run when watching
+    if RUNTIME_FLAGS.get().is_none()
+        && let Err(e) = RUNTIME_FLAGS.set(crate::RuntimeFlags {
+            stdlib_path: PathBuf::from(stdlib_path.clone()),
+        })
+    {
         error!("Could not set the global STDLIB_PATH: {e:?}");
     }
 
@@ -157,13 +250,7 @@ fn spawn_termination_task() {
             }
         }
 
-        let database_pools = DATABASE_POOLS.lock().await.clone();
-        for (_id, db_type) in database_pools {
-            match db_type {
-                crate::components::database::DatabaseType::Postgres(pool) => pool.close().await,
-                crate::components::database::DatabaseType::Sqlite(pool) => pool.close().await,
-            }
-        }
+        close_database_pools().await;
 
         std::process::exit(
             #[cfg(unix)]
@@ -173,3 +260,13 @@ fn spawn_termination_task() {
         );
     });
 }
+
+async fn close_database_pools() {
+    let database_pools = std::mem::take(&mut *DATABASE_POOLS.lock().await);
+    for (_id, db_type) in database_pools {
+        match db_type {
+            crate::components::database::DatabaseType::Postgres(pool) => pool.close().await,
+            crate::components::database::DatabaseType::Sqlite(pool) => pool.close().await,
+        }
+    }
+}
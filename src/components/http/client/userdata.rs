@@ -1,6 +1,6 @@
 use crate::components::AstraBuffer;
 use futures::StreamExt;
-use mlua::{ExternalError, UserData};
+use mlua::{ExternalError, LuaSerdeExt, UserData};
 use reqwest_websocket::Upgrade;
 use std::collections::HashMap;
 
@@ -131,18 +131,28 @@ impl UserData for super::HTTPClientRequest {
         );
         methods.add_async_method(
             "execute_websocket",
-            |lua, this, callback: mlua::Function| async move {
+            |lua, this, (callback, options): (mlua::Function, mlua::Value)| async move {
+                let options = lua
+                    .from_value::<Option<super::WebSocketOptions>>(options)?
+                    .unwrap_or_default();
+                let ping_interval = super::WebSocketOptions::duration(options.ping_interval)?;
+                let idle_timeout = super::WebSocketOptions::duration(options.idle_timeout)?;
+
                 tokio::spawn(async move {
                     let request = this.request_builder().await?;
-                    let request = request.upgrade();
+                    let request = options.apply(request.upgrade());
                     if let Ok(response) = request.send().await
                         && let Ok(response) = response.into_websocket().await
                     {
-                        if let Err(e) = callback
-                            .call_async::<()>(lua.create_userdata(super::AstraWebSocket(response)))
-                            .await
-                        {
-                            tracing::error!("Error running a task: {e}")
+                        let socket = super::AstraWebSocket::new(response, idle_timeout);
+                        let keep_alive = socket.keep_alive(ping_interval);
+                        tokio::select! {
+                            result = callback.call_async::<()>(lua.create_userdata(socket)) => {
+                                if let Err(e) = result {
+                                    tracing::error!("Error running a task: {e}")
+                                }
+                            }
+                            _ = keep_alive => {}
                         }
                     } else {
                         tracing::error!("Websocket request did not execute successfully");
@@ -1,59 +1,135 @@
-use bytes::Bytes;
-use futures::{SinkExt, TryStreamExt};
+use crate::components::{http::websocket, value_to_bytes};
+use futures::{
+    SinkExt, StreamExt,
+    stream::{SplitSink, SplitStream},
+};
 use mlua::{ExternalError, UserData};
-use reqwest_websocket::{CloseCode, Message, WebSocket};
+use reqwest_websocket::{CloseCode, Message, Upgraded, WebSocket};
+use std::{sync::Arc, time::Duration};
 
-pub struct AstraWebSocket(pub WebSocket);
+/// Options of `execute_websocket`, matching those of the server side WebSocket routes.
+#[derive(Debug, Clone, Default, serde::Deserialize)]
+pub struct WebSocketOptions {
+    /// Subprotocols offered to the server, in order of preference
+    pub protocols: Option<Vec<String>>,
+    pub max_message_size: Option<usize>,
+    pub max_frame_size: Option<usize>,
+    /// Seconds between the pings that keep the connection alive
+    pub ping_interval: Option<f64>,
+    /// Seconds the connection can go without receiving anything before `recv` closes it
+    pub idle_timeout: Option<f64>,
+}
+impl WebSocketOptions {
+    /// Applies the protocols and size limits to the upgrade request.
+    pub fn apply(
+        &self,
+        request: Upgraded<reqwest::RequestBuilder>,
+    ) -> Upgraded<reqwest::RequestBuilder> {
+        // the config type is taken from reqwest-websocket, so that the version of tungstenite
+        // it is built with never has to be named here
+        fn default_config<C: Default>(
+            _: fn(Upgraded<reqwest::RequestBuilder>, C) -> Upgraded<reqwest::RequestBuilder>,
+        ) -> C {
+            C::default()
+        }
+
+        let mut config = default_config(Upgraded::web_socket_config);
+        if let Some(max_message_size) = self.max_message_size {
+            config = config.max_message_size(Some(max_message_size));
+        }
+        if let Some(max_frame_size) = self.max_frame_size {
+            config = config.max_frame_size(Some(max_frame_size));
+        }
+
+        request
+            .protocols(self.protocols.clone().unwrap_or_default())
+            .web_socket_config(config)
+    }
+
+    pub fn duration(seconds: Option<f64>) -> mlua::Result<Option<Duration>> {
+        seconds
+            .map(crate::components::http::server::parse_timeout)
+            .transpose()
+    }
+}
+
+type Sink = Arc<tokio::sync::Mutex<SplitSink<WebSocket, Message>>>;
+
+pub struct AstraWebSocket {
+    sink: Sink,
+    stream: tokio::sync::Mutex<SplitStream<WebSocket>>,
+    /// The subprotocol agreed on during the handshake
+    protocol: Option<String>,
+    idle_timeout: Option<Duration>,
+}
 impl AstraWebSocket {
-    fn value_to_bytes(value: &mlua::Value) -> Result<Bytes, mlua::Error> {
-        if let Some(table) = value.as_table() {
-            Ok(Bytes::from_iter(
-                table.sequence_values::<u8>().filter_map(|x| x.ok()),
-            ))
-        } else if value.is_string() {
-            Ok(Bytes::from(value.to_string()?))
-        } else {
-            Err(mlua::Error::runtime("type cannot be accepted as bytes"))
+    /// Splits the socket so that the pings can be sent while the callback waits for messages.
+    pub fn new(socket: WebSocket, idle_timeout: Option<Duration>) -> Self {
+        let protocol = socket.protocol().map(str::to_string);
+        let (sink, stream) = socket.split();
+
+        Self {
+            sink: Arc::new(tokio::sync::Mutex::new(sink)),
+            stream: tokio::sync::Mutex::new(stream),
+            protocol,
+            idle_timeout,
         }
     }
+
+    /// Pings the server at the interval for as long as it is polled, never finishing on its own.
+    pub fn keep_alive(
+        &self,
+        interval: Option<Duration>,
+    ) -> impl Future<Output = ()> + Send + 'static {
+        websocket::keep_alive(self.sink.clone(), interval)
+    }
+
+    async fn send(&self, message: Message) -> mlua::Result<()> {
+        self.sink
+            .lock()
+            .await
+            .send(message)
+            .await
+            .map_err(|e| e.into_lua_err())
+    }
+
+    async fn next_message(&self) -> mlua::Result<Option<Message>> {
+        websocket::next_message(&self.stream, &self.sink, self.idle_timeout).await
+    }
 }
 impl UserData for AstraWebSocket {
     fn add_methods<M: mlua::UserDataMethods<Self>>(methods: &mut M) {
-        methods.add_async_method_mut("recv", |lua, mut this, ()| async move {
-            match this.0.try_next().await {
-                Ok(msg) => match msg {
-                    Some(msg) => {
-                        let recv = lua.create_table()?;
-                        match msg {
-                            Message::Text(utf8_bytes) => {
-                                recv.set("type", "text")?;
-                                recv.set("value", utf8_bytes.to_string())?;
-                            }
-                            Message::Binary(bytes) => {
-                                recv.set("type", "bytes")?;
-                                recv.set("value", bytes.to_vec())?;
-                            }
-                            Message::Close { code, reason } => {
-                                recv.set("type", "close")?;
-                                let close_frame = lua.create_table()?;
-                                close_frame.set("code", code.to_string())?;
-                                close_frame.set("reason", reason)?;
-                                recv.set("value", close_frame)?;
-                            }
-                            _ => {}
-                        };
-
-                        Ok(recv)
-                    }
-                    None => Err(mlua::Error::runtime("failed to receive a frame")),
-                },
-                Err(e) => Err(e.into_lua_err()),
-            }
+        methods.add_async_method("recv", |lua, this, ()| async move {
+            let Some(msg) = this.next_message().await? else {
+                return Ok(None);
+            };
+
+            let recv = lua.create_table()?;
+            match msg {
+                Message::Text(utf8_bytes) => {
+                    recv.set("type", "text")?;
+                    recv.set("value", utf8_bytes.to_string())?;
+                }
+                Message::Binary(bytes) => {
+                    recv.set("type", "bytes")?;
+                    recv.set("value", bytes.to_vec())?;
+                }
+                Message::Close { code, reason } => {
+                    recv.set("type", "close")?;
+                    let close_frame = lua.create_table()?;
+                    close_frame.set("code", code.to_string())?;
+                    close_frame.set("reason", reason)?;
+                    recv.set("value", close_frame)?;
+                }
+                Message::Ping(_) | Message::Pong(_) => {}
+            };
+
+            Ok(Some(recv))
         });
 
-        methods.add_async_method_mut(
+        methods.add_async_method(
             "send",
-            |_, mut this, (message_type, message): (String, mlua::Value)| async move {
+            |_, this, (message_type, message): (String, mlua::Value)| async move {
                 let msg = match message_type.to_lowercase().as_str() {
                     "text" => Ok(Message::Text(
                         if let Some(table_message) = message.as_table() {
@@ -65,7 +141,7 @@ impl UserData for AstraWebSocket {
                             message.to_string()?
                         },
                     )),
-                    "bytes" => Ok(Message::Binary(Self::value_to_bytes(&message)?)),
+                    "bytes" => Ok(Message::Binary(value_to_bytes(&message)?)),
                     "close" => match message {
                         mlua::Value::Integer(close_code) => Ok(Message::Close {
                             code: CloseCode::from(u16::try_from(close_code).unwrap_or(1006)),
@@ -84,35 +160,22 @@ impl UserData for AstraWebSocket {
                 };
 
                 match msg {
-                    Ok(msg) => match this.0.send(msg).await {
-                        Ok(_) => Ok(()),
-                        Err(e) => Err(e.into_lua_err()),
-                    },
+                    Ok(msg) => this.send(msg).await,
                     Err(e) => Err(e.into_lua_err()),
                 }
             },
         );
 
-        methods.add_async_method_mut("send_text", |_, mut this, message: String| async move {
-            match this.0.send(Message::Text(message)).await {
-                Ok(_) => Ok(()),
-                Err(e) => Err(e.into_lua_err()),
-            }
+        methods.add_async_method("send_text", |_, this, message: String| async move {
+            this.send(Message::Text(message)).await
         });
 
-        methods.add_async_method_mut("send_bytes", |_, mut this, bytes: mlua::Value| async move {
-            match this
-                .0
-                .send(Message::Binary(Self::value_to_bytes(&bytes)?))
-                .await
-            {
-                Ok(_) => Ok(()),
-                Err(e) => Err(e.into_lua_err()),
-            }
+        methods.add_async_method("send_bytes", |_, this, bytes: mlua::Value| async move {
+            this.send(Message::Binary(value_to_bytes(&bytes)?)).await
         });
-        methods.add_async_method_mut(
+        methods.add_async_method(
             "send_close",
-            |_, mut this, close_frame: Option<mlua::Value>| async move {
+            |_, this, close_frame: Option<mlua::Value>| async move {
                 let close_frame: Message = match close_frame {
                     Some(frame) => match frame {
                         mlua::Value::Integer(close_code) => Message::Close {
@@ -134,11 +197,10 @@ impl UserData for AstraWebSocket {
                     },
                 };
 
-                match this.0.send(close_frame).await {
-                    Ok(_) => Ok(()),
-                    Err(e) => Err(e.into_lua_err()),
-                }
+                this.send(close_frame).await
             },
         );
+
+        methods.add_method("protocol", |_, this, ()| Ok(this.protocol.clone()));
     }
 }
@@ -1,10 +1,217 @@
+use super::{
+    cookie::CookieKeys,
+    cors::CorsConfiguration,
+    csrf::Csrf,
+    lifecycle::Lifecycle,
+    middleware::Middleware,
+    rate_limit::{RateLimitConfiguration, RateLimiter},
+    session::Sessions,
+};
+use mlua::LuaSerdeExt;
 use mlua::{FromLua, UserData};
 
+/// Server wide settings that every route needs access to while handling requests.
+#[derive(Debug, Clone, Default)]
+pub struct ServerConfiguration {
+    pub cookie_keys: CookieKeys,
+    pub middleware: std::sync::Arc<Vec<Middleware>>,
+    pub cors: Option<tower_http::cors::CorsLayer>,
+    pub rate_limit: Option<std::sync::Arc<RateLimiter>>,
+    pub timeout: Option<std::time::Duration>,
+    /// Largest body in bytes of routes without their own limit, and of the middleware at this level
+    pub body_limit: Option<usize>,
+    /// Store the sessions of `request:session()` are kept in
+    pub sessions: Option<std::sync::Arc<Sessions>>,
+    /// Checks the token of requests with unsafe methods
+    pub csrf: Option<std::sync::Arc<Csrf>>,
+    /// Health endpoint and shutdown state of the running server
+    pub lifecycle: std::sync::Arc<Lifecycle>,
+    /// Renders the response for requests whose handler failed
+    pub error_handler: Option<mlua::Function>,
+    /// Shows the error, request and route on a debug page when a handler fails
+    pub development: bool,
+}
+impl ServerConfiguration {
+    pub fn from_table(lua: &mlua::Lua, server: &mlua::Table) -> mlua::Result<Self> {
+        let cors = match server.get::<mlua::Value>("cors")? {
+            mlua::Value::Nil => None,
+            cors => Some(lua.from_value::<CorsConfiguration>(cors)?.layer()?),
+        };
+
+        let cookie_keys = CookieKeys::from_lua(server.get("cookie_key")?)?;
+        let rate_limit = match server.get::<mlua::Value>("rate_limit")? {
+            mlua::Value::Nil => None,
+            rate_limit => Some(RateLimiter::new(
+                RateLimitConfiguration::from_lua(lua, rate_limit)?,
+                cookie_keys.clone(),
+            )?),
+        };
+
+        let sessions = match server.get::<mlua::Value>("session")? {
+            mlua::Value::Nil => None,
+            sessions => Some(Sessions::from_lua(lua, sessions)?),
+        };
+
+        Ok(Self {
+            cookie_keys,
+            middleware: std::sync::Arc::new(Middleware::from_table(server)?),
+            cors,
+            rate_limit,
+            timeout: server
+                .get::<Option<f64>>("timeout")?
+                .map(parse_timeout)
+                .transpose()?,
+            body_limit: server.get("body_limit")?,
+            sessions,
+            csrf: Csrf::from_lua(lua, server.get("csrf")?)?,
+            lifecycle: std::sync::Arc::new(Lifecycle::from_lua(server.get("health")?)?),
+            error_handler: server.get("error_handler")?,
+            development: server.get::<Option<bool>>("development")?.unwrap_or(false),
+        })
+    }
+}
+
 #[derive(Debug, Clone, Default, PartialEq, serde::Serialize, serde::Deserialize, FromLua)]
 pub struct RouteConfiguration {
     pub body_limit: Option<usize>,
     pub compression: Option<bool>,
     pub headers: Option<std::collections::HashMap<String, String>>,
+    pub cors: Option<CorsConfiguration>,
+    pub rate_limit: Option<RateLimitConfiguration>,
+    /// Seconds the handler gets before the request is answered with 504
+    pub timeout: Option<f64>,
+    /// Validation schema the path parameters are checked against, 400 if they do not match
+    #[serde(skip)]
+    pub params: Option<mlua::Table>,
+    /// Validation schema for the JSON body, 422 listing the failing paths if it does not match
+    #[serde(skip)]
+    pub body_schema: Option<mlua::Table>,
+    /// Validation schema for the query string, 422 listing the failing paths if it does not match
+    #[serde(skip)]
+    pub query_schema: Option<mlua::Table>,
+    /// Serves the `index.html` of a static directory for paths that do not exist
+    pub spa: Option<bool>,
+    /// Serves the `.br`, `.gz` and `.zst` variants of static files to clients that accept them
+    pub precompressed: Option<bool>,
+    /// `Cache-Control` header of the files found in a static directory
+    pub cache_control: Option<String>,
+    /// Regex for the paths of content hashed assets, which are cached for good
+    pub immutable: Option<String>,
+    /// Lists the contents of static directories without an `index.html`
+    pub listing: Option<bool>,
+    /// File within the static directory served with 404 for paths that do not exist
+    pub not_found: Option<String>,
+    /// Set to `false` to skip the CSRF check of the server for this route
+    pub csrf: Option<bool>,
+    /// Runs before a WebSocket is accepted, returning a value turns the client away
+    #[serde(skip)]
+    pub upgrade: Option<mlua::Function>,
+    /// Subprotocols a WebSocket accepts, picked from those the client offers
+    pub protocols: Option<Vec<String>>,
+    /// Largest WebSocket message in bytes
+    pub max_message_size: Option<usize>,
+    /// Largest WebSocket frame in bytes
+    pub max_frame_size: Option<usize>,
+    /// Seconds between the pings that keep a WebSocket alive
+    pub ping_interval: Option<f64>,
+    /// Seconds a WebSocket can go without receiving anything before `recv` closes it
+    pub idle_timeout: Option<f64>,
+}
+/// Turns a timeout in seconds into a duration, rejecting values that cannot be waited on.
+pub fn parse_timeout(seconds: f64) -> mlua::Result<std::time::Duration> {
+    match std::time::Duration::try_from_secs_f64(seconds) {
+        Ok(timeout) if !timeout.is_zero() => Ok(timeout),
+        _ => Err(mlua::Error::runtime(format!(
+            "The timeout must be a positive amount of seconds, got {seconds}"
+        ))),
+    }
+}
+
+impl RouteConfiguration {
+    /// Fills in whatever this route does not set from the configuration of its group.
+    pub fn inherit(self, parent: &Self) -> Self {
+        let headers = match (&parent.headers, self.headers) {
+            (Some(parent), Some(headers)) => {
+                let mut merged = parent.clone();
+                merged.extend(headers);
+                Some(merged)
+            }
+            (parent, headers) => headers.or_else(|| parent.clone()),
+        };
+
+        Self {
+            body_limit: self.body_limit.or(parent.body_limit),
+            compression: self.compression.or(parent.compression),
+            headers,
+            cors: self.cors.or_else(|| parent.cors.clone()),
+            rate_limit: self.rate_limit.or_else(|| parent.rate_limit.clone()),
+            timeout: self.timeout.or(parent.timeout),
+            params: self.params.or_else(|| parent.params.clone()),
+            body_schema: self.body_schema.or_else(|| parent.body_schema.clone()),
+            query_schema: self.query_schema.or_else(|| parent.query_schema.clone()),
+            spa: self.spa.or(parent.spa),
+            precompressed: self.precompressed.or(parent.precompressed),
+            cache_control: self.cache_control.or_else(|| parent.cache_control.clone()),
+            immutable: self.immutable.or_else(|| parent.immutable.clone()),
+            listing: self.listing.or(parent.listing),
+            not_found: self.not_found.or_else(|| parent.not_found.clone()),
+            csrf: self.csrf.or(parent.csrf),
+            upgrade: self.upgrade.or_else(|| parent.upgrade.clone()),
+            protocols: self.protocols.or_else(|| parent.protocols.clone()),
+            max_message_size: self.max_message_size.or(parent.max_message_size),
+            max_frame_size: self.max_frame_size.or(parent.max_frame_size),
+            ping_interval: self.ping_interval.or(parent.ping_interval),
+            idle_timeout: self.idle_timeout.or(parent.idle_timeout),
+        }
+    }
+
+    pub fn from_lua(lua: &mlua::Lua, value: mlua::Value) -> mlua::Result<Self> {
+        // schemas and functions cannot be deserialized, those are taken from the table as is
+        let table = match &value {
+            mlua::Value::Table(table) => Some(table.clone()),
+            _ => None,
+        };
+        let schema = |key: &str| match &table {
+            Some(table) => table.get::<Option<mlua::Table>>(key),
+            None => Ok(None),
+        };
+        let rate_limit = match &table {
+            Some(table) => match table.get::<mlua::Value>("rate_limit")? {
+                mlua::Value::Nil => None,
+                rate_limit => Some(RateLimitConfiguration::from_lua(lua, rate_limit)?),
+            },
+            None => None,
+        };
+        let params = schema("params")?;
+        let body_schema = schema("body_schema")?;
+        let query_schema = schema("query_schema")?;
+        let upgrade = match &table {
+            Some(table) => table.get::<Option<mlua::Function>>("upgrade")?,
+            None => None,
+        };
+
+        let mut configuration: Self = lua.from_value_with(
+            value,
+            mlua::DeserializeOptions::new().deny_unsupported_types(false),
+        )?;
+        configuration.rate_limit = rate_limit;
+        configuration.params = params;
+        configuration.body_schema = body_schema;
+        configuration.query_schema = query_schema;
+        configuration.upgrade = upgrade;
+        for timeout in [
+            configuration.timeout,
+            configuration.ping_interval,
+            configuration.idle_timeout,
+        ]
+        .into_iter()
+        .flatten()
+        {
+            parse_timeout(timeout)?;
+        }
+
+        Ok(configuration)
+    }
 }
 impl UserData for RouteConfiguration {
     fn add_methods<M: mlua::UserDataMethods<Self>>(methods: &mut M) {
@@ -1,5 +1,86 @@
-use axum_extra::extract::cookie::Cookie;
+use axum::http::HeaderMap;
+use axum_extra::extract::{
+    PrivateCookieJar, SignedCookieJar,
+    cookie::{Cookie, Key},
+};
 use mlua::{FromLua, UserData};
+use sha2::Digest;
+
+/// Secrets shorter than this are rejected since they would make the derived keys guessable.
+const MIN_SECRET_LENGTH: usize = 32;
+
+/// Keys used for signing and encrypting cookies. The first one signs and encrypts new cookies,
+/// the rest are only tried when reading so that the secret can be rotated without logging
+/// everyone out.
+#[derive(Debug, Clone, Default)]
+pub struct CookieKeys(std::sync::Arc<Vec<Key>>);
+impl CookieKeys {
+    /// Reads the `cookie_key` field of the server, which is either a single secret or a list of them.
+    pub fn from_lua(value: mlua::Value) -> mlua::Result<Self> {
+        let secrets = match value {
+            mlua::Value::Nil => Vec::new(),
+            mlua::Value::String(secret) => vec![secret.as_bytes().to_vec()],
+            mlua::Value::Table(secrets) => secrets
+                .sequence_values::<mlua::String>()
+                .map(|secret| secret.map(|secret| secret.as_bytes().to_vec()))
+                .collect::<mlua::Result<Vec<_>>>()?,
+            _ => {
+                return Err(mlua::Error::runtime(
+                    "The cookie key must be a string or a list of strings",
+                ));
+            }
+        };
+
+        let keys = secrets
+            .into_iter()
+            .map(|secret| {
+                if secret.len() < MIN_SECRET_LENGTH {
+                    Err(mlua::Error::runtime(format!(
+                        "The cookie key must be at least {MIN_SECRET_LENGTH} bytes long"
+                    )))
+                } else {
+                    // stretches the secret to the 64 bytes needed for signing and encryption
+                    Ok(Key::from(sha2::Sha512::digest(&secret).as_slice()))
+                }
+            })
+            .collect::<mlua::Result<Vec<_>>>()?;
+
+        Ok(Self(std::sync::Arc::new(keys)))
+    }
+
+    /// The key new cookies are signed and encrypted with.
+    pub fn primary(&self) -> mlua::Result<Key> {
+        self.0.first().cloned().ok_or_else(|| {
+            mlua::Error::runtime("No cookie key is set, set `cookie_key` on the server first")
+        })
+    }
+
+    pub fn get_signed(
+        &self,
+        headers: &HeaderMap,
+        name: &str,
+    ) -> mlua::Result<Option<Cookie<'static>>> {
+        self.primary()?;
+
+        Ok(self
+            .0
+            .iter()
+            .find_map(|key| SignedCookieJar::from_headers(headers, key.clone()).get(name)))
+    }
+
+    pub fn get_private(
+        &self,
+        headers: &HeaderMap,
+        name: &str,
+    ) -> mlua::Result<Option<Cookie<'static>>> {
+        self.primary()?;
+
+        Ok(self
+            .0
+            .iter()
+            .find_map(|key| PrivateCookieJar::from_headers(headers, key.clone()).get(name)))
+    }
+}
 
 #[derive(Debug, Clone, FromLua)]
 pub struct AstraHTTPCookie<'a>(pub Cookie<'a>);
@@ -0,0 +1,96 @@
+use axum::http::{HeaderName, HeaderValue, Method};
+use tower_http::cors::{AllowHeaders, AllowMethods, AllowOrigin, CorsLayer};
+
+#[derive(Debug, Clone, Default, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct CorsConfiguration {
+    /// Origins allowed to make requests, `"*"` allows any. Defaults to any
+    pub origins: Option<Vec<String>>,
+    /// Allowed request methods, `"*"` allows any. Defaults to any
+    pub methods: Option<Vec<String>>,
+    /// Allowed request headers, `"*"` allows any. Defaults to any
+    pub headers: Option<Vec<String>>,
+    /// Allows cookies and authorization headers to be sent along
+    pub credentials: Option<bool>,
+    /// How many seconds browsers may cache the preflight response for
+    pub max_age: Option<u64>,
+}
+impl CorsConfiguration {
+    /// Builds the layer that answers preflight requests and adds the CORS headers to responses.
+    pub fn layer(&self) -> mlua::Result<CorsLayer> {
+        let credentials = self.credentials.unwrap_or(false);
+        let is_any = |values: &Option<Vec<String>>| {
+            values
+                .as_ref()
+                .is_none_or(|values| values.iter().any(|value| value == "*"))
+        };
+
+        let origins = if is_any(&self.origins) {
+            if credentials {
+                return Err(mlua::Error::runtime(
+                    "CORS cannot allow any origin together with credentials, list the origins instead",
+                ));
+            }
+
+            AllowOrigin::any()
+        } else {
+            AllowOrigin::list(parse_all(
+                self.origins.iter().flatten(),
+                "origin",
+                |origin| HeaderValue::from_str(origin).ok(),
+            )?)
+        };
+
+        // browsers reject wildcards on credentialed requests, echoing the request is equivalent
+        let methods = if is_any(&self.methods) {
+            if credentials {
+                AllowMethods::mirror_request()
+            } else {
+                AllowMethods::any()
+            }
+        } else {
+            AllowMethods::list(parse_all(
+                self.methods.iter().flatten(),
+                "method",
+                |method| Method::from_bytes(method.to_uppercase().as_bytes()).ok(),
+            )?)
+        };
+
+        let headers = if is_any(&self.headers) {
+            if credentials {
+                AllowHeaders::mirror_request()
+            } else {
+                AllowHeaders::any()
+            }
+        } else {
+            AllowHeaders::list(parse_all(
+                self.headers.iter().flatten(),
+                "header",
+                |header| HeaderName::from_bytes(header.as_bytes()).ok(),
+            )?)
+        };
+
+        let mut layer = CorsLayer::new()
+            .allow_origin(origins)
+            .allow_methods(methods)
+            .allow_headers(headers)
+            .allow_credentials(credentials);
+        if let Some(max_age) = self.max_age {
+            layer = layer.max_age(std::time::Duration::from_secs(max_age));
+        }
+
+        Ok(layer)
+    }
+}
+
+fn parse_all<'a, T>(
+    values: impl Iterator<Item = &'a String>,
+    kind: &str,
+    parse: impl Fn(&str) -> Option<T>,
+) -> mlua::Result<Vec<T>> {
+    values
+        .map(|value| {
+            parse(value)
+                .ok_or_else(|| mlua::Error::runtime(format!("Invalid CORS {kind}: {value}")))
+        })
+        .collect()
+}
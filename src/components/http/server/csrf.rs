@@ -0,0 +1,332 @@
+use super::{
+    configs::{RouteConfiguration, ServerConfiguration},
+    requests::RequestLua,
+};
+use axum::{
+    body::Body,
+    extract::{FromRequest, MatchedPath, State},
+    http::{HeaderName, HeaderValue, Method, Request, StatusCode, header},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use axum_extra::extract::{CookieJar, cookie::Cookie};
+use mlua::LuaSerdeExt;
+use std::{collections::HashMap, sync::Arc};
+
+tokio::task_local! {
+    /// Token of the request being handled, read by the `csrf_token()` template function
+    static CSRF_TOKEN: String;
+}
+
+/// The token of the request currently being handled, if CSRF protection is enabled.
+pub fn current_token() -> Option<String> {
+    CSRF_TOKEN.try_with(|token| token.clone()).ok()
+}
+
+/// The token issued to the client of a request, kept in the request extensions.
+#[derive(Debug, Clone)]
+pub struct CsrfToken(pub String);
+
+#[derive(Debug, Clone, Default, serde::Deserialize)]
+pub struct CsrfConfiguration {
+    /// Name of the cookie holding the token, defaults to `csrf_token`
+    pub cookie: Option<String>,
+    /// Header the token can be sent in, defaults to `X-CSRF-Token`
+    pub header: Option<String>,
+    /// Form or JSON field the token can be sent in, defaults to `csrf_token`
+    pub field: Option<String>,
+    /// Only sends the token cookie over HTTPS
+    pub secure: Option<bool>,
+}
+
+/// What the check needs to know of a route, which it looks up on its own since it runs before
+/// the middleware and the route.
+#[derive(Debug, Clone)]
+struct CheckedRoute {
+    /// `None` for routes that answer any method
+    methods: Option<Vec<Method>>,
+    enabled: bool,
+    body_limit: Option<usize>,
+}
+impl CheckedRoute {
+    fn new(methods: Option<Vec<Method>>, config: &RouteConfiguration) -> Self {
+        Self {
+            methods,
+            enabled: config.csrf != Some(false),
+            body_limit: config.body_limit,
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct CheckedRoutes {
+    /// By the path axum matched
+    paths: HashMap<String, Vec<CheckedRoute>>,
+    /// Fallbacks by the prefix of their group, as requests that reach them match no path
+    fallbacks: Vec<(String, CheckedRoute)>,
+}
+
+/// Double submit protection: the token in the cookie has to be sent back along with every
+/// request that changes something, which other sites cannot do as they cannot read it.
+#[derive(Debug)]
+pub struct Csrf {
+    cookie: String,
+    header: HeaderName,
+    field: String,
+    secure: bool,
+    routes: std::sync::RwLock<CheckedRoutes>,
+}
+impl Csrf {
+    pub fn from_lua(lua: &mlua::Lua, value: mlua::Value) -> mlua::Result<Option<Arc<Self>>> {
+        let configuration = match value {
+            mlua::Value::Nil | mlua::Value::Boolean(false) => return Ok(None),
+            mlua::Value::Boolean(true) => CsrfConfiguration::default(),
+            value => lua.from_value::<CsrfConfiguration>(value)?,
+        };
+
+        let header = configuration.header.as_deref().unwrap_or("X-CSRF-Token");
+        Ok(Some(Arc::new(Self {
+            cookie: configuration
+                .cookie
+                .unwrap_or_else(|| "csrf_token".to_string()),
+            header: HeaderName::try_from(header).map_err(|e| {
+                mlua::Error::runtime(format!("Invalid CSRF header name {header}: {e}"))
+            })?,
+            field: configuration
+                .field
+                .unwrap_or_else(|| "csrf_token".to_string()),
+            secure: configuration.secure.unwrap_or(false),
+            routes: Default::default(),
+        })))
+    }
+
+    /// Keeps the configuration of a route for the requests axum matches to its path.
+    pub fn add_route(
+        &self,
+        path: String,
+        methods: Option<Vec<Method>>,
+        config: &RouteConfiguration,
+    ) {
+        if let Ok(mut routes) = self.routes.write() {
+            routes
+                .paths
+                .entry(path)
+                .or_default()
+                .push(CheckedRoute::new(methods, config));
+        }
+    }
+
+    /// Keeps the configuration of the fallback of a group, or of the server for an empty prefix.
+    pub fn add_fallback(&self, prefix: String, config: &RouteConfiguration) {
+        if let Ok(mut routes) = self.routes.write() {
+            routes
+                .fallbacks
+                .push((prefix, CheckedRoute::new(None, config)));
+        }
+    }
+
+    /// The route a request is headed to, or the innermost fallback that would answer it.
+    fn route(&self, request: &Request<Body>) -> Option<CheckedRoute> {
+        let routes = self.routes.read().ok()?;
+        if let Some(path) = request.extensions().get::<MatchedPath>() {
+            let candidates = routes.paths.get(path.as_str())?;
+            return candidates
+                .iter()
+                .find(|route| {
+                    route
+                        .methods
+                        .as_ref()
+                        .is_some_and(|methods| methods.contains(request.method()))
+                })
+                .or_else(|| candidates.iter().find(|route| route.methods.is_none()))
+                .cloned();
+        }
+
+        let path = request.uri().path();
+        routes
+            .fallbacks
+            .iter()
+            .filter(|(prefix, _)| {
+                path.strip_prefix(prefix.as_str())
+                    .is_some_and(|rest| rest.is_empty() || rest.starts_with('/'))
+            })
+            .max_by_key(|(prefix, _)| prefix.len())
+            .map(|(_, route)| route.clone())
+    }
+
+    /// Rejects requests with unsafe methods and WebSocket upgrades that do not send the token
+    /// of their cookie back, through the header, a form field or a JSON field. Upgrades have no
+    /// body and send the field in the query string instead.
+    async fn verify(&self, request: &RequestLua) -> Result<(), Response> {
+        let is_upgrade = request
+            .parts
+            .headers
+            .get(header::UPGRADE)
+            .is_some_and(|upgrade| upgrade.as_bytes().eq_ignore_ascii_case(b"websocket"));
+        if !is_upgrade
+            && matches!(
+                request.parts.method,
+                Method::GET | Method::HEAD | Method::OPTIONS | Method::TRACE
+            )
+        {
+            return Ok(());
+        }
+
+        let Some(CsrfToken(expected)) = request.parts.extensions.get::<CsrfToken>() else {
+            return Ok(());
+        };
+        // a token that was only just issued has never been seen by the client
+        let has_cookie = CookieJar::from_headers(&request.parts.headers)
+            .get(&self.cookie)
+            .is_some_and(|cookie| cookie.value() == expected);
+
+        let submitted = match request.parts.headers.get(&self.header) {
+            Some(token) => token.to_str().ok().map(str::to_string),
+            None if is_upgrade => self.submitted_query(request),
+            None => self.submitted_field(request).await?,
+        };
+
+        match submitted {
+            Some(submitted) if has_cookie && tokens_match(&submitted, expected) => Ok(()),
+            _ => Err((StatusCode::FORBIDDEN, "Invalid CSRF token").into_response()),
+        }
+    }
+
+    fn submitted_query(&self, request: &RequestLua) -> Option<String> {
+        axum::extract::Query::<Vec<(String, String)>>::try_from_uri(&request.parts.uri)
+            .ok()?
+            .0
+            .into_iter()
+            .find_map(|(key, value)| (key == self.field).then_some(value))
+    }
+
+    async fn submitted_field(&self, request: &RequestLua) -> Result<Option<String>, Response> {
+        let content_type = request
+            .parts
+            .headers
+            .get(header::CONTENT_TYPE)
+            .and_then(|content_type| content_type.to_str().ok())
+            .unwrap_or_default();
+        let mime = content_type.to_ascii_lowercase();
+        let is_form = mime.starts_with("application/x-www-form-urlencoded");
+        let is_json = mime.starts_with("application/json");
+        let is_multipart = mime.starts_with("multipart/form-data");
+        if !is_form && !is_json && !is_multipart {
+            return Ok(None);
+        }
+
+        // the body stays buffered, so the handler can still read it afterwards
+        let bytes = match request.bytes().await {
+            Ok(bytes) => bytes,
+            Err(_) => {
+                return Err(match request.body.lock().await.rejection() {
+                    Some(status) => status.into_response(),
+                    None => StatusCode::BAD_REQUEST.into_response(),
+                });
+            }
+        };
+
+        if is_json {
+            return Ok(serde_json::from_slice::<serde_json::Value>(&bytes)
+                .ok()
+                .and_then(|body| body.get(&self.field)?.as_str().map(str::to_string)));
+        }
+
+        if is_form {
+            let form = Request::from_parts(request.parts.clone(), Body::from(bytes));
+            return Ok(axum::Form::<Vec<(String, String)>>::from_request(form, &())
+                .await
+                .ok()
+                .and_then(|form| {
+                    form.0
+                        .into_iter()
+                        .find_map(|(key, value)| (key == self.field).then_some(value))
+                }));
+        }
+
+        let Ok(boundary) = multer::parse_boundary(content_type) else {
+            return Ok(None);
+        };
+        let mut multipart = multer::Multipart::new(
+            futures::stream::once(async move { Ok::<_, std::convert::Infallible>(bytes) }),
+            boundary,
+        );
+        while let Ok(Some(field)) = multipart.next_field().await {
+            if field.name() == Some(self.field.as_str()) {
+                return Ok(field.text().await.ok());
+            }
+        }
+
+        Ok(None)
+    }
+}
+
+/// Compares the tokens in constant time, so that the timing gives nothing away.
+fn tokens_match(submitted: &str, expected: &str) -> bool {
+    submitted.len() == expected.len()
+        && submitted
+            .bytes()
+            .zip(expected.bytes())
+            .fold(0, |difference, (a, b)| difference | (a ^ b))
+            == 0
+}
+
+fn is_valid_token(token: &str) -> bool {
+    token.len() == 64 && token.chars().all(|c| c.is_ascii_hexdigit())
+}
+
+/// Issues a token to every client that does not have one yet and makes it available to the
+/// handlers and templates of the request. Checks the token before the middleware runs, so that
+/// no hook can answer a forged request.
+pub async fn layer(
+    State(server): State<ServerConfiguration>,
+    mut request: Request<Body>,
+    next: Next,
+) -> Response {
+    let Some(csrf) = server.csrf.clone() else {
+        return next.run(request).await;
+    };
+    let existing = CookieJar::from_headers(request.headers())
+        .get(&csrf.cookie)
+        .map(|cookie| cookie.value().to_string())
+        .filter(|token| is_valid_token(token));
+    let token = existing.clone().unwrap_or_else(|| {
+        format!(
+            "{}{}",
+            uuid::Uuid::new_v4().simple(),
+            uuid::Uuid::new_v4().simple()
+        )
+    });
+    request.extensions_mut().insert(CsrfToken(token.clone()));
+
+    let route = csrf.route(&request);
+    if route.as_ref().is_none_or(|route| route.enabled) {
+        let body_limit = route
+            .and_then(|route| route.body_limit)
+            .or(server.body_limit);
+        let checked = RequestLua::new(request, body_limit, server.cookie_keys.clone()).await;
+        if let Err(response) = csrf.verify(&checked).await {
+            return response;
+        }
+
+        // the body read to find the token is handed on along with whatever is left of it
+        let body = checked.body.lock().await.take_body();
+        request = Request::from_parts(checked.parts, body);
+    }
+
+    let mut response = CSRF_TOKEN.scope(token.clone(), next.run(request)).await;
+
+    if existing.is_none() {
+        // readable from scripts, which send it back in the header
+        let cookie = Cookie::build((csrf.cookie.clone(), token))
+            .path("/")
+            .secure(csrf.secure)
+            .same_site(axum_extra::extract::cookie::SameSite::Lax)
+            .build();
+        if let Ok(cookie) = HeaderValue::from_str(&cookie.to_string()) {
+            response.headers_mut().append(header::SET_COOKIE, cookie);
+        }
+    }
+
+    response
+}
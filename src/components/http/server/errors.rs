@@ -0,0 +1,116 @@
+use super::{configs::ServerConfiguration, requests::RequestLua, responses::ResponseLua, routes};
+use axum::{
+    http::StatusCode,
+    response::{Html, IntoResponse, Response},
+};
+use axum_extra::extract::CookieJar;
+
+/// Answers a request whose handler failed, through the `on_error` handler when there is one
+/// and with a debug page in development mode.
+pub async fn error_response(
+    lua: &mlua::Lua,
+    server: &ServerConfiguration,
+    error: mlua::Error,
+    request: &mlua::AnyUserData,
+    route: Option<&str>,
+) -> Response {
+    if let Some(handler) = &server.error_handler {
+        match call_error_handler(lua, server, handler, &error, request).await {
+            Ok(response) => return response,
+            Err(e) => tracing::error!("Error executing the error handler: {e}"),
+        }
+    }
+
+    if server.development {
+        return debug_page(&error, request, route);
+    }
+
+    StatusCode::INTERNAL_SERVER_ERROR.into_response()
+}
+
+async fn call_error_handler(
+    lua: &mlua::Lua,
+    server: &ServerConfiguration,
+    handler: &mlua::Function,
+    error: &mlua::Error,
+    request: &mlua::AnyUserData,
+) -> mlua::Result<Response> {
+    let response = lua.create_userdata(ResponseLua {
+        status_code: StatusCode::INTERNAL_SERVER_ERROR,
+        ..Default::default()
+    })?;
+
+    let result = handler
+        .call_async::<mlua::Value>((error.to_string(), request, &response))
+        .await?;
+    let response_details = response.borrow::<ResponseLua>()?.clone();
+
+    Ok(routes::build_response(
+        lua,
+        result,
+        &response_details,
+        request,
+        server,
+        CookieJar::new(),
+    )
+    .await?
+    .into_response())
+}
+
+pub fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&#39;")
+}
+
+/// Renders the error along with the request and route it happened in. Only meant for
+/// development since it exposes the internals of the server.
+fn debug_page(error: &mlua::Error, request: &mlua::AnyUserData, route: Option<&str>) -> Response {
+    let mut request_details = String::new();
+    if let Ok(request) = request.borrow::<RequestLua>() {
+        request_details.push_str(&format!(
+            "<p><code>{} {}</code></p><table>",
+            escape(request.parts.method.as_str()),
+            escape(&request.parts.uri.to_string())
+        ));
+        for (key, value) in request.parts.headers.iter() {
+            request_details.push_str(&format!(
+                "<tr><th>{}</th><td>{}</td></tr>",
+                escape(key.as_str()),
+                escape(&String::from_utf8_lossy(value.as_bytes()))
+            ));
+        }
+        request_details.push_str("</table>");
+    }
+
+    let route = route
+        .map(|route| format!("<h2>Route</h2><p><code>{}</code></p>", escape(route)))
+        .unwrap_or_default();
+
+    let page = format!(
+        r#"<!DOCTYPE html>
+<html>
+<head>
+<meta charset="utf-8">
+<title>Internal Server Error</title>
+<style>
+body {{ font-family: sans-serif; margin: 2rem; color: #222; }}
+pre {{ background: #f5f5f5; padding: 1rem; overflow-x: auto; }}
+th {{ text-align: left; padding-right: 1rem; }}
+</style>
+</head>
+<body>
+<h1>Internal Server Error</h1>
+<pre>{}</pre>
+{route}
+<h2>Request</h2>
+{request_details}
+</body>
+</html>"#,
+        escape(&error.to_string())
+    );
+
+    (StatusCode::INTERNAL_SERVER_ERROR, Html(page)).into_response()
+}
@@ -0,0 +1,186 @@
+use axum::extract::ws::{Message, WebSocket};
+use futures::{SinkExt, stream::SplitSink};
+use std::{
+    collections::{HashMap, HashSet},
+    sync::{Arc, LazyLock, Mutex},
+};
+
+/// The sending half of a WebSocket, shared by its handler and the broadcasts reaching it.
+pub type Sink = Arc<tokio::sync::Mutex<SplitSink<WebSocket, Message>>>;
+
+struct Connection {
+    sink: Sink,
+    rooms: HashSet<String>,
+}
+
+/// Every open WebSocket of the process along with the rooms it has joined.
+#[derive(Default)]
+struct Hub {
+    connections: HashMap<String, Connection>,
+    rooms: HashMap<String, HashSet<String>>,
+}
+impl Hub {
+    fn leave(&mut self, id: &str, room: &str) -> bool {
+        let Some(members) = self.rooms.get_mut(room) else {
+            return false;
+        };
+        let left = members.remove(id);
+        if members.is_empty() {
+            self.rooms.remove(room);
+        }
+        if let Some(connection) = self.connections.get_mut(id) {
+            connection.rooms.remove(room);
+        }
+
+        left
+    }
+
+    fn leave_all(&mut self, id: &str) {
+        let rooms = self
+            .connections
+            .get(id)
+            .map(|connection| connection.rooms.iter().cloned().collect::<Vec<_>>())
+            .unwrap_or_default();
+        for room in rooms {
+            self.leave(id, &room);
+        }
+    }
+}
+
+static HUB: LazyLock<Mutex<Hub>> = LazyLock::new(|| Mutex::new(Hub::default()));
+
+/// Keeps a WebSocket in the hub, taking it out of all of its rooms once dropped at the end of
+/// the connection.
+pub struct Presence(String);
+impl Drop for Presence {
+    fn drop(&mut self) {
+        if let Ok(mut hub) = HUB.lock() {
+            hub.leave_all(&self.0);
+            hub.connections.remove(&self.0);
+        }
+    }
+}
+
+/// Adds a WebSocket to the hub under a new ID.
+pub fn connect(sink: Sink) -> (String, Presence) {
+    let id = uuid::Uuid::new_v4().simple().to_string();
+    if let Ok(mut hub) = HUB.lock() {
+        hub.connections.insert(
+            id.clone(),
+            Connection {
+                sink,
+                rooms: HashSet::new(),
+            },
+        );
+    }
+
+    (id.clone(), Presence(id))
+}
+
+/// Returns `false` for connections that are already gone.
+pub fn join(id: &str, room: &str) -> bool {
+    let Ok(mut hub) = HUB.lock() else {
+        return false;
+    };
+    let Some(connection) = hub.connections.get_mut(id) else {
+        return false;
+    };
+    connection.rooms.insert(room.to_string());
+    hub.rooms
+        .entry(room.to_string())
+        .or_default()
+        .insert(id.to_string());
+
+    true
+}
+
+/// Returns whether the connection was in the room.
+pub fn leave(id: &str, room: &str) -> bool {
+    HUB.lock().is_ok_and(|mut hub| hub.leave(id, room))
+}
+
+pub fn rooms(id: &str) -> Vec<String> {
+    let mut rooms = HUB
+        .lock()
+        .ok()
+        .and_then(|hub| {
+            hub.connections
+                .get(id)
+                .map(|connection| connection.rooms.iter().cloned().collect::<Vec<_>>())
+        })
+        .unwrap_or_default();
+    rooms.sort();
+    rooms
+}
+
+pub fn members(room: &str) -> Vec<String> {
+    let mut members = HUB
+        .lock()
+        .ok()
+        .and_then(|hub| {
+            hub.rooms
+                .get(room)
+                .map(|members| members.iter().cloned().collect::<Vec<_>>())
+        })
+        .unwrap_or_default();
+    members.sort();
+    members
+}
+
+pub fn count(room: &str) -> usize {
+    HUB.lock()
+        .ok()
+        .and_then(|hub| hub.rooms.get(room).map(HashSet::len))
+        .unwrap_or(0)
+}
+
+/// Sends the message to every member of the room but the excluded one, returning how many
+/// received it. Members whose socket has closed are taken out of the room.
+pub async fn broadcast(room: &str, message: Message, except: Option<&str>) -> usize {
+    let sinks = match HUB.lock() {
+        Ok(hub) => hub
+            .rooms
+            .get(room)
+            .into_iter()
+            .flatten()
+            .filter(|id| Some(id.as_str()) != except)
+            .filter_map(|id| {
+                hub.connections
+                    .get(id)
+                    .map(|connection| (id.clone(), connection.sink.clone()))
+            })
+            .collect::<Vec<_>>(),
+        Err(_) => return 0,
+    };
+
+    // the lock is not held while sending, so a slow client does not hold up the rest of the hub
+    let results = futures::future::join_all(sinks.into_iter().map(|(id, sink)| {
+        let message = message.clone();
+        async move { (id, sink.lock().await.send(message).await.is_ok()) }
+    }))
+    .await;
+
+    let mut sent = 0;
+    for (id, ok) in results {
+        if ok {
+            sent += 1;
+        } else if let Ok(mut hub) = HUB.lock() {
+            hub.leave_all(&id);
+        }
+    }
+
+    sent
+}
+
+/// Sends the message to a single connection, returning `false` if it is gone.
+pub async fn send(id: &str, message: Message) -> bool {
+    let Some(sink) = HUB.lock().ok().and_then(|hub| {
+        hub.connections
+            .get(id)
+            .map(|connection| connection.sink.clone())
+    }) else {
+        return false;
+    };
+
+    sink.lock().await.send(message).await.is_ok()
+}
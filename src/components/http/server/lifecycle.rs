@@ -0,0 +1,166 @@
+use axum::{
+    Json,
+    body::Body,
+    extract::State,
+    http::{Method, Request, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use std::sync::{
+    Arc,
+    atomic::{AtomicBool, Ordering},
+};
+
+/// Where a running server is in its life, shared between the shutdown sequence and the
+/// requests still being handled.
+#[derive(Debug)]
+pub struct Lifecycle {
+    draining: AtomicBool,
+    /// Flipped once the server stops accepting connections, which ends WebSockets and streams
+    closing: tokio::sync::watch::Sender<bool>,
+    /// Flipped once the shutdown timeout has passed and in-flight work has to stop
+    cut: tokio::sync::watch::Sender<bool>,
+    /// Path of the health endpoint, if the server has one
+    health: Option<String>,
+}
+impl Default for Lifecycle {
+    fn default() -> Self {
+        Self::new(None)
+    }
+}
+impl Lifecycle {
+    pub fn new(health: Option<String>) -> Self {
+        Self {
+            draining: AtomicBool::new(false),
+            closing: tokio::sync::watch::Sender::new(false),
+            cut: tokio::sync::watch::Sender::new(false),
+            health,
+        }
+    }
+
+    /// Reads the `health` field of a server, `true` serving the endpoint at `/health`.
+    pub fn from_lua(value: mlua::Value) -> mlua::Result<Self> {
+        let health = match value {
+            mlua::Value::Nil | mlua::Value::Boolean(false) => None,
+            mlua::Value::Boolean(true) => Some("/health".to_string()),
+            mlua::Value::String(path) => {
+                let path = path.to_str()?.to_string();
+                if !path.starts_with('/') {
+                    return Err(mlua::Error::runtime(format!(
+                        "The health endpoint has to start with a slash, got {path}"
+                    )));
+                }
+                Some(path)
+            }
+            value => {
+                return Err(mlua::Error::runtime(format!(
+                    "Expected a boolean or a path for the health endpoint, got {}",
+                    value.type_name()
+                )));
+            }
+        };
+
+        Ok(Self::new(health))
+    }
+
+    pub fn is_draining(&self) -> bool {
+        self.draining.load(Ordering::Relaxed)
+    }
+
+    pub fn start_draining(&self) {
+        self.draining.store(true, Ordering::Relaxed);
+    }
+
+    /// Ends the WebSockets and streams that are still open, which would keep the server from
+    /// ever stopping otherwise.
+    pub fn close_connections(&self) {
+        self.closing.send_replace(true);
+    }
+
+    /// Resolves once the server closes its WebSockets and streams.
+    pub async fn closing(&self) {
+        let mut closing = self.closing.subscribe();
+        let _ = closing.wait_for(|closing| *closing).await;
+    }
+
+    /// Stops every request that is still running.
+    pub fn cut_off(&self) {
+        self.cut.send_replace(true);
+    }
+
+    /// Resolves once the server cuts off the work still running.
+    pub async fn cut(&self) {
+        let mut cut = self.cut.subscribe();
+        let _ = cut.wait_for(|cut| *cut).await;
+    }
+}
+
+/// Number of servers still running, which `astra run --watch` waits for before it runs the
+/// script again.
+static RUNNING: std::sync::atomic::AtomicUsize = std::sync::atomic::AtomicUsize::new(0);
+static STOPPED: tokio::sync::Notify = tokio::sync::Notify::const_new();
+static STOP_ALL: tokio::sync::Notify = tokio::sync::Notify::const_new();
+
+/// Counts a server as running until it is dropped, including when its task is.
+pub struct Running;
+impl Running {
+    pub fn start() -> Self {
+        RUNNING.fetch_add(1, Ordering::AcqRel);
+        Self
+    }
+}
+impl Drop for Running {
+    fn drop(&mut self) {
+        RUNNING.fetch_sub(1, Ordering::AcqRel);
+        STOPPED.notify_waiters();
+    }
+}
+
+/// Shuts every running server down, as if it received a signal.
+pub fn stop_servers() {
+    STOP_ALL.notify_waiters();
+}
+
+/// Resolves once `stop_servers` is called.
+pub async fn stop_requested() {
+    STOP_ALL.notified().await;
+}
+
+/// Resolves once no server is running anymore.
+pub async fn servers_stopped() {
+    loop {
+        let stopped = STOPPED.notified();
+        if RUNNING.load(Ordering::Acquire) == 0 {
+            return;
+        }
+        stopped.await;
+    }
+}
+
+/// Answers the health endpoint, which reports `draining` with 503 once the server is shutting
+/// down, and cuts off requests that outlive the shutdown timeout.
+pub async fn layer(
+    State(lifecycle): State<Arc<Lifecycle>>,
+    request: Request<Body>,
+    next: Next,
+) -> Response {
+    if let Some(health) = &lifecycle.health
+        && request.uri().path() == health
+        && matches!(*request.method(), Method::GET | Method::HEAD)
+    {
+        return if lifecycle.is_draining() {
+            (
+                StatusCode::SERVICE_UNAVAILABLE,
+                Json(serde_json::json!({ "status": "draining" })),
+            )
+                .into_response()
+        } else {
+            Json(serde_json::json!({ "status": "ok" })).into_response()
+        };
+    }
+
+    tokio::select! {
+        response = next.run(request) => response,
+        _ = lifecycle.cut() => StatusCode::SERVICE_UNAVAILABLE.into_response(),
+    }
+}
@@ -0,0 +1,409 @@
+use super::tls::{HANDSHAKE_TIMEOUT, TlsState};
+use std::{net::SocketAddr, path::PathBuf};
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    net::TcpListener,
+};
+
+/// The client at the other end of a connection.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Peer {
+    Tcp(SocketAddr),
+    /// Connections over a Unix socket come from the same machine but carry no IP address
+    Unix,
+}
+impl Peer {
+    pub fn ip(&self) -> Option<std::net::IpAddr> {
+        match self {
+            Self::Tcp(address) => Some(address.ip()),
+            Self::Unix => None,
+        }
+    }
+}
+impl std::fmt::Display for Peer {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Tcp(address) => address.fmt(f),
+            Self::Unix => f.write_str("unix"),
+        }
+    }
+}
+
+impl axum::extract::connect_info::Connected<axum::serve::IncomingStream<'_, Listeners>> for Peer {
+    fn connect_info(stream: axum::serve::IncomingStream<'_, Listeners>) -> Self {
+        *stream.remote_addr()
+    }
+}
+
+/// A connection accepted by any of the listeners of a server.
+pub trait Connection: AsyncRead + AsyncWrite + Unpin + Send {}
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> Connection for T {}
+
+/// A connection along with the address of its peer.
+type Accepted = (Box<dyn Connection>, Peer);
+
+/// An address the server listens on, given as `host:port`, `unix:/path/to/socket` or
+/// `{ unix = "/path/to/socket", permissions = "660" }`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ListenAddress {
+    Tcp(String),
+    Unix {
+        path: PathBuf,
+        /// Octal file mode of the socket, such as `660`
+        permissions: Option<u32>,
+    },
+}
+impl ListenAddress {
+    pub fn from_lua(lua: &mlua::Lua, value: mlua::Value) -> mlua::Result<Self> {
+        #[derive(serde::Deserialize)]
+        struct UnixAddress {
+            unix: String,
+            permissions: Option<String>,
+        }
+
+        match value {
+            mlua::Value::String(address) => {
+                let address = address.to_str()?.to_string();
+                Ok(match address.strip_prefix("unix:") {
+                    Some(path) => Self::Unix {
+                        path: PathBuf::from(path),
+                        permissions: None,
+                    },
+                    None => Self::Tcp(address),
+                })
+            }
+            mlua::Value::Table(_) => {
+                use mlua::LuaSerdeExt;
+                let address: UnixAddress = lua.from_value(value)?;
+                let permissions = address
+                    .permissions
+                    .map(|permissions| {
+                        u32::from_str_radix(permissions.trim_start_matches("0o"), 8).map_err(|e| {
+                            mlua::Error::runtime(format!(
+                                "Invalid permissions {permissions} for the Unix socket {}, expected an octal mode such as \"660\": {e}",
+                                address.unix
+                            ))
+                        })
+                    })
+                    .transpose()?;
+
+                Ok(Self::Unix {
+                    path: PathBuf::from(address.unix),
+                    permissions,
+                })
+            }
+            value => Err(mlua::Error::runtime(format!(
+                "Expected an address to listen on, got {}",
+                value.type_name()
+            ))),
+        }
+    }
+
+    /// Reads the `listen` field of a server, falling back to its hostname and port.
+    pub fn from_server(
+        lua: &mlua::Lua,
+        server: &mlua::Table,
+        hostname: &str,
+        port: u16,
+    ) -> mlua::Result<Vec<Self>> {
+        match server.get::<mlua::Value>("listen")? {
+            mlua::Value::Nil => Ok(vec![Self::Tcp(format!("{hostname}:{port}"))]),
+            mlua::Value::Table(addresses) if !addresses.contains_key("unix")? => {
+                let addresses = addresses
+                    .sequence_values::<mlua::Value>()
+                    .map(|address| Self::from_lua(lua, address?))
+                    .collect::<mlua::Result<Vec<_>>>()?;
+                if addresses.is_empty() {
+                    return Err(mlua::Error::runtime(
+                        "The server needs at least one address to listen on",
+                    ));
+                }
+
+                Ok(addresses)
+            }
+            address => Ok(vec![Self::from_lua(lua, address)?]),
+        }
+    }
+}
+
+/// Sockets of the servers of a script that `astra run --watch` reruns, picked up again by the
+/// servers of the next run so that no connection is refused in between.
+static KEPT_SOCKETS: std::sync::Mutex<Option<Vec<(ListenAddress, KeptSocket)>>> =
+    std::sync::Mutex::new(None);
+
+/// Keeps the sockets of the servers open once they stop, for the next run of the script.
+pub fn keep_sockets() {
+    if let Ok(mut kept) = KEPT_SOCKETS.lock() {
+        kept.get_or_insert_with(Vec::new);
+    }
+}
+
+/// A socket outside of any runtime, as the runtime of every run is shut down with it.
+enum KeptSocket {
+    Tcp(std::net::TcpListener),
+    #[cfg(unix)]
+    Unix(std::os::unix::net::UnixListener),
+}
+
+enum Bound {
+    Tcp(TcpListener),
+    #[cfg(unix)]
+    Unix(tokio::net::UnixListener),
+}
+impl Bound {
+    async fn bind(address: &ListenAddress) -> std::io::Result<Self> {
+        match address {
+            ListenAddress::Tcp(address) => bind_tcp(address).await.map(Self::Tcp),
+            #[cfg(unix)]
+            ListenAddress::Unix { path, permissions } => {
+                bind_unix(path, *permissions).map(Self::Unix)
+            }
+            #[cfg(not(unix))]
+            ListenAddress::Unix { .. } => Err(std::io::Error::new(
+                std::io::ErrorKind::Unsupported,
+                "Unix sockets are not supported on this platform",
+            )),
+        }
+    }
+
+    /// The socket kept open for the address by the previous run of the script, if any.
+    fn take_kept(address: &ListenAddress) -> Option<std::io::Result<Self>> {
+        let mut kept = KEPT_SOCKETS.lock().ok()?;
+        let kept = kept.as_mut()?;
+        let index = kept.iter().position(|(kept, _)| kept == address)?;
+
+        Some(match kept.swap_remove(index).1 {
+            KeptSocket::Tcp(listener) => TcpListener::from_std(listener).map(Self::Tcp),
+            #[cfg(unix)]
+            KeptSocket::Unix(listener) => {
+                tokio::net::UnixListener::from_std(listener).map(Self::Unix)
+            }
+        })
+    }
+
+    /// A duplicate of the socket, which stays open once this one is closed.
+    fn keep(&self) -> std::io::Result<KeptSocket> {
+        match self {
+            Self::Tcp(listener) => Ok(KeptSocket::Tcp(
+                socket2::SockRef::from(listener).try_clone()?.into(),
+            )),
+            #[cfg(unix)]
+            Self::Unix(listener) => Ok(KeptSocket::Unix(
+                socket2::SockRef::from(listener).try_clone()?.into(),
+            )),
+        }
+    }
+
+    async fn accept(&self) -> std::io::Result<Accepted> {
+        match self {
+            Self::Tcp(listener) => {
+                let (stream, address) = listener.accept().await?;
+                Ok((Box::new(stream), Peer::Tcp(address)))
+            }
+            #[cfg(unix)]
+            Self::Unix(listener) => {
+                let (stream, _) = listener.accept().await?;
+                Ok((Box::new(stream), Peer::Unix))
+            }
+        }
+    }
+}
+
+/// Binds a TCP address, keeping IPv6 sockets to IPv6 so that `0.0.0.0` and `[::]` can be
+/// listened on side by side.
+async fn bind_tcp(address: &str) -> std::io::Result<TcpListener> {
+    let mut last_error = None;
+
+    for address in tokio::net::lookup_host(address).await? {
+        let bind = || -> std::io::Result<TcpListener> {
+            let socket = socket2::Socket::new(
+                socket2::Domain::for_address(address),
+                socket2::Type::STREAM,
+                Some(socket2::Protocol::TCP),
+            )?;
+            if address.is_ipv6() {
+                socket.set_only_v6(true)?;
+            }
+            #[cfg(unix)]
+            socket.set_reuse_address(true)?;
+            socket.set_nonblocking(true)?;
+            socket.bind(&address.into())?;
+            socket.listen(1024)?;
+
+            TcpListener::from_std(socket.into())
+        };
+
+        match bind() {
+            Ok(listener) => return Ok(listener),
+            Err(e) => last_error = Some(e),
+        }
+    }
+
+    Err(last_error.unwrap_or_else(|| {
+        std::io::Error::new(
+            std::io::ErrorKind::AddrNotAvailable,
+            "the address did not resolve to anything",
+        )
+    }))
+}
+
+#[cfg(unix)]
+fn bind_unix(
+    path: &std::path::Path,
+    permissions: Option<u32>,
+) -> std::io::Result<tokio::net::UnixListener> {
+    use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+
+    // a socket left behind by a previous run is replaced, one that is still in use is not
+    if let Ok(metadata) = std::fs::symlink_metadata(path)
+        && metadata.file_type().is_socket()
+        && std::os::unix::net::UnixStream::connect(path).is_err()
+    {
+        std::fs::remove_file(path)?;
+    }
+
+    let listener = tokio::net::UnixListener::bind(path)?;
+    if let Some(permissions) = permissions {
+        std::fs::set_permissions(path, std::fs::Permissions::from_mode(permissions))?;
+    }
+
+    Ok(listener)
+}
+
+/// Every listener of a server behind one, completing the TLS handshakes in the background
+/// so that only established connections are handed to axum.
+pub struct Listeners {
+    bound: Vec<(ListenAddress, Bound)>,
+    tls: Option<TlsState>,
+    handshakes: (
+        tokio::sync::mpsc::Sender<Accepted>,
+        tokio::sync::mpsc::Receiver<Accepted>,
+    ),
+    local_addr: Option<SocketAddr>,
+    /// The addresses that were actually bound, with the ports picked for port 0
+    pub addresses: Vec<String>,
+    unix_paths: Vec<PathBuf>,
+}
+impl Listeners {
+    pub async fn bind(addresses: &[ListenAddress], tls: Option<TlsState>) -> mlua::Result<Self> {
+        let mut bound = Vec::new();
+        let mut bound_addresses = Vec::new();
+        let mut unix_paths = Vec::new();
+        let mut local_addr = None;
+
+        for address in addresses {
+            let listener = match Bound::take_kept(address) {
+                Some(listener) => listener,
+                None => Bound::bind(address).await,
+            }
+            .map_err(|e| {
+                mlua::Error::runtime(match address {
+                    ListenAddress::Tcp(address) => format!("Could not listen on {address}: {e}"),
+                    ListenAddress::Unix { path, .. } => format!(
+                        "Could not listen on the Unix socket {}: {e}",
+                        path.display()
+                    ),
+                })
+            })?;
+
+            match (&listener, address) {
+                (Bound::Tcp(listener), _) => {
+                    let address = listener.local_addr()?;
+                    local_addr.get_or_insert(address);
+                    bound_addresses.push(address.to_string());
+                }
+                (_, ListenAddress::Unix { path, .. }) => {
+                    bound_addresses.push(format!("unix:{}", path.display()));
+                    unix_paths.push(path.clone());
+                }
+                _ => {}
+            }
+            bound.push((address.clone(), listener));
+        }
+
+        Ok(Self {
+            bound,
+            tls,
+            handshakes: tokio::sync::mpsc::channel(64),
+            local_addr,
+            addresses: bound_addresses,
+            unix_paths,
+        })
+    }
+
+    /// Port of the first TCP listener, which is the one picked by the OS for port 0.
+    pub fn port(&self) -> Option<u16> {
+        self.local_addr.map(|address| address.port())
+    }
+}
+
+/// Waits for a connection on any of the listeners.
+async fn accept_any(bound: &[(ListenAddress, Bound)]) -> std::io::Result<Accepted> {
+    let accepts = bound
+        .iter()
+        .map(|(_, listener)| Box::pin(listener.accept()));
+    futures::future::select_all(accepts).await.0
+}
+
+impl axum::serve::Listener for Listeners {
+    type Io = Box<dyn Connection>;
+    type Addr = Peer;
+
+    async fn accept(&mut self) -> (Self::Io, Self::Addr) {
+        loop {
+            let (stream, address) = tokio::select! {
+                Some(connection) = self.handshakes.1.recv() => return connection,
+                accepted = accept_any(&self.bound) => match accepted {
+                    Ok(accepted) => accepted,
+                    Err(e) => {
+                        tracing::error!("Could not accept a connection: {e}");
+                        tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+                        continue;
+                    }
+                },
+            };
+
+            let Some(tls) = &self.tls else {
+                return (stream, address);
+            };
+            let Some(acceptor) = tls.acceptor() else {
+                tracing::error!("The TLS configuration is unavailable");
+                continue;
+            };
+            let sender = self.handshakes.0.clone();
+
+            tokio::spawn(async move {
+                match tokio::time::timeout(HANDSHAKE_TIMEOUT, acceptor.accept(stream)).await {
+                    Ok(Ok(stream)) => {
+                        let _ = sender.send((Box::new(stream), address)).await;
+                    }
+                    Ok(Err(e)) => tracing::debug!("TLS handshake with {address} failed: {e}"),
+                    Err(_) => tracing::debug!("TLS handshake with {address} timed out"),
+                }
+            });
+        }
+    }
+
+    fn local_addr(&self) -> std::io::Result<Self::Addr> {
+        Ok(self.local_addr.map_or(Peer::Unix, Peer::Tcp))
+    }
+}
+impl Drop for Listeners {
+    fn drop(&mut self) {
+        if let Ok(mut kept) = KEPT_SOCKETS.lock()
+            && let Some(kept) = kept.as_mut()
+        {
+            for (address, listener) in &self.bound {
+                match listener.keep() {
+                    Ok(socket) => kept.push((address.clone(), socket)),
+                    Err(e) => tracing::error!("Could not keep the socket of {address:?} open: {e}"),
+                }
+            }
+            return;
+        }
+
+        for path in &self.unix_paths {
+            let _ = std::fs::remove_file(path);
+        }
+    }
+}
@@ -0,0 +1,276 @@
+use crate::components::http::server::{
+    configs::ServerConfiguration, errors, requests::RequestLua, responses::ResponseLua, routes,
+};
+use axum::{
+    body::Body,
+    http::{Request, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use axum_extra::extract::CookieJar;
+use std::sync::{
+    Arc,
+    atomic::{AtomicBool, Ordering},
+};
+
+/// Headers that can be sent several times, which the hooks add to instead of replacing.
+const REPEATED_HEADERS: [axum::http::HeaderName; 5] = [
+    axum::http::header::SET_COOKIE,
+    axum::http::header::VARY,
+    axum::http::header::LINK,
+    axum::http::header::VIA,
+    axum::http::header::WWW_AUTHENTICATE,
+];
+
+#[derive(Debug, Clone)]
+pub enum Middleware {
+    /// Wraps the rest of the pipeline, which runs when the hook calls `next`
+    Use(mlua::Function),
+    /// Runs before the handler, returning a value short-circuits the request
+    Before(mlua::Function),
+    /// Runs after the handler and can change the outgoing response
+    After(mlua::Function),
+}
+impl Middleware {
+    /// Reads the hooks registered through `server:use`, `server:before` and `server:after`.
+    pub fn from_table(server: &mlua::Table) -> mlua::Result<Vec<Self>> {
+        let Some(entries) = server.get::<Option<mlua::Table>>("middleware")? else {
+            return Ok(Vec::new());
+        };
+
+        entries
+            .sequence_values::<mlua::Table>()
+            .map(|entry| {
+                let entry = entry?;
+                let function = entry.get::<mlua::Function>("func")?;
+
+                match entry.get::<String>("kind")?.as_str() {
+                    "use" => Ok(Self::Use(function)),
+                    "before" => Ok(Self::Before(function)),
+                    "after" => Ok(Self::After(function)),
+                    kind => Err(mlua::Error::runtime(format!(
+                        "Unknown middleware kind: {kind}"
+                    ))),
+                }
+            })
+            .collect()
+    }
+}
+
+/// Runs the server wide middleware around every request, including static files,
+/// websockets and the fallback.
+pub async fn layer(
+    lua: mlua::Lua,
+    server: ServerConfiguration,
+    request: Request<Body>,
+    next: Next,
+) -> Response {
+    let request = RequestLua::new(request, server.body_limit, server.cookie_keys.clone()).await;
+    let body = request.body.clone();
+
+    let request = match lua.create_userdata(request) {
+        Ok(request) => request,
+        Err(e) => {
+            tracing::error!("Error executing the middleware: {e}");
+            return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+        }
+    };
+
+    async fn layer_inner(
+        lua: mlua::Lua,
+        server: ServerConfiguration,
+        request: mlua::AnyUserData,
+        next: Next,
+    ) -> mlua::Result<Response> {
+        let pipeline = Pipeline {
+            response: lua.create_userdata(ResponseLua::default())?,
+            lua,
+            server: server.clone(),
+            request,
+            next: Arc::new(tokio::sync::Mutex::new(Some(next))),
+            outgoing: Arc::new(tokio::sync::Mutex::new(None)),
+        };
+        pipeline.clone().run(0).await?;
+
+        let Some(mut response) = pipeline.outgoing.lock().await.take() else {
+            return Err(mlua::Error::runtime(
+                "The middleware finished without producing a response",
+            ));
+        };
+
+        let response_details = pipeline.response.borrow::<ResponseLua>()?;
+        *response.status_mut() = response_details.status_code;
+        // holds every header of the response by now, minus the ones the hooks removed
+        *response.headers_mut() = response_details.headers.clone();
+
+        let (cookie_jar, signed_cookie_jar, private_cookie_jar) = routes::apply_cookie_operations(
+            &response_details.cookie_operations,
+            CookieJar::new(),
+            &server,
+        )?;
+
+        Ok((cookie_jar, signed_cookie_jar, private_cookie_jar, response).into_response())
+    }
+
+    match layer_inner(lua.clone(), server.clone(), request.clone(), next).await {
+        Ok(response) => response,
+        Err(e) => {
+            if let Some(status) = body.lock().await.rejection() {
+                return status.into_response();
+            }
+
+            tracing::error!("Error executing the middleware: {e}");
+
+            errors::error_response(&lua, &server, e, &request, None).await
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Pipeline {
+    lua: mlua::Lua,
+    server: ServerConfiguration,
+    request: mlua::AnyUserData,
+    response: mlua::AnyUserData,
+    next: Arc<tokio::sync::Mutex<Option<Next>>>,
+    outgoing: Arc<tokio::sync::Mutex<Option<Response>>>,
+}
+impl Pipeline {
+    fn run(self, index: usize) -> futures::future::BoxFuture<'static, mlua::Result<()>> {
+        Box::pin(async move {
+            let Some(middleware) = self.server.middleware.get(index).cloned() else {
+                return self.run_handler().await;
+            };
+
+            match middleware {
+                Middleware::Use(function) => {
+                    let called = Arc::new(AtomicBool::new(false));
+                    let next = {
+                        let pipeline = self.clone();
+                        let called = called.clone();
+
+                        self.lua.create_async_function(move |_, ()| {
+                            let pipeline = pipeline.clone();
+                            let already_called = called.swap(true, Ordering::SeqCst);
+
+                            async move {
+                                if already_called {
+                                    return Err(mlua::Error::runtime(
+                                        "next can only be called once per request",
+                                    ));
+                                }
+
+                                pipeline.run(index + 1).await
+                            }
+                        })?
+                    };
+
+                    let result = function
+                        .call_async::<mlua::Value>((&self.request, &self.response, next))
+                        .await?;
+
+                    if !called.load(Ordering::SeqCst) {
+                        self.respond(result).await?;
+                    }
+
+                    Ok(())
+                }
+                Middleware::Before(function) => {
+                    let result = function
+                        .call_async::<mlua::Value>((&self.request, &self.response))
+                        .await?;
+
+                    let redirected = self.response.borrow::<ResponseLua>()?.redirect.is_some();
+                    if !result.is_nil() || redirected {
+                        self.respond(result).await
+                    } else {
+                        self.run(index + 1).await
+                    }
+                }
+                Middleware::After(function) => {
+                    self.clone().run(index + 1).await?;
+
+                    function
+                        .call_async::<()>((&self.request, &self.response))
+                        .await
+                }
+            }
+        })
+    }
+
+    /// Hands the request over to the router, passing along whatever is left of the body.
+    async fn run_handler(&self) -> mlua::Result<()> {
+        let (parts, body) = {
+            let request = self.request.borrow::<RequestLua>()?;
+            (request.parts.clone(), request.body.clone())
+        };
+        let body = body.lock().await.take_body();
+
+        let Some(next) = self.next.lock().await.take() else {
+            return Err(mlua::Error::runtime("The handler has already been run"));
+        };
+
+        let response = next.run(Request::from_parts(parts, body)).await;
+        self.receive(response).await
+    }
+
+    /// Short-circuits the request with the value returned from a hook.
+    async fn respond(&self, result: mlua::Value) -> mlua::Result<()> {
+        let response_details = {
+            let mut response_details = self.response.borrow_mut::<ResponseLua>()?;
+            let current = response_details.clone();
+
+            // already part of the response, only later changes have to be applied
+            response_details.cookie_operations.clear();
+            response_details.redirect = None;
+            response_details.stream = None;
+            response_details.file = None;
+
+            current
+        };
+        let response = routes::build_response(
+            &self.lua,
+            result,
+            &response_details,
+            &self.request,
+            &self.server,
+            CookieJar::new(),
+        )
+        .await?
+        .into_response();
+
+        self.receive(response).await
+    }
+
+    /// Stores the outgoing response and exposes its status and headers to the hooks that
+    /// still have to run. The headers they have set so far replace those of the response,
+    /// except for the ones that can be sent several times, which are added to them.
+    async fn receive(&self, response: Response) -> mlua::Result<()> {
+        {
+            let mut response_details = self.response.borrow_mut::<ResponseLua>()?;
+            let mut headers = response.headers().clone();
+            for key in response_details.headers.keys() {
+                if REPEATED_HEADERS.contains(key) {
+                    for value in response_details.headers.get_all(key) {
+                        if !headers
+                            .get_all(key)
+                            .iter()
+                            .any(|existing| existing == value)
+                        {
+                            headers.append(key, value.clone());
+                        }
+                    }
+                } else if let Some(value) = response_details.headers.get(key) {
+                    headers.insert(key, value.clone());
+                }
+            }
+
+            response_details.status_code = response.status();
+            response_details.headers = headers;
+        }
+
+        *self.outgoing.lock().await = Some(response);
+
+        Ok(())
+    }
+}
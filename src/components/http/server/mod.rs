@@ -1,11 +1,39 @@
 mod configs;
 mod cookie;
+mod cors;
+mod csrf;
+mod errors;
+mod hub;
+mod lifecycle;
+mod listener;
+mod middleware;
+mod multipart;
+mod openapi;
+mod params;
+mod rate_limit;
 mod requests;
 mod responses;
 mod routes;
+mod session;
+mod static_files;
+mod stream;
+mod tls;
 mod websocket;
 
+pub use configs::parse_timeout;
+pub use csrf::current_token as csrf_token;
+pub use lifecycle::{servers_stopped, stop_servers};
+pub use listener::keep_sockets;
+
+use mlua::LuaSerdeExt;
+
+/// How long the requests cut off at the shutdown timeout get to send their 503 response.
+const CUT_OFF_GRACE: std::time::Duration = std::time::Duration::from_secs(1);
+
 pub fn register_to_lua(lua: &mlua::Lua) -> mlua::Result<()> {
+    openapi::register_to_lua(lua)?;
+    websocket::AstraWebSocket::register_to_lua(lua)?;
+
     // Register function for running the server
     lua.globals().set(
         "astra_internal__start_server",
@@ -20,12 +48,52 @@ pub fn register_to_lua(lua: &mlua::Lua) -> mlua::Result<()> {
                 port = new_port;
             }
 
-            let listener_address: String = format!("{hostname}:{port}");
+            let tls = match server.get::<mlua::Value>("tls")? {
+                mlua::Value::Nil => None,
+                tls => Some(tls::TlsState::new(lua.from_value(tls)?)?),
+            };
+
+            let configuration = configs::ServerConfiguration::from_table(&lua, &server)?;
+
+            let addresses = listener::ListenAddress::from_server(&lua, &server, &hostname, port)?;
+            let listeners = listener::Listeners::bind(&addresses, tls.clone()).await?;
+            let _running = lifecycle::Running::start();
+            // port 0 leaves the choice to the OS, the script gets to know which one it was
+            if let Some(port) = listeners.port() {
+                server.set("port", port)?;
+            }
+            server.set("addresses", listeners.addresses.clone())?;
+
+            if let Some(tls) = tls.clone() {
+                server.set(
+                    "reload_tls",
+                    lua.create_function(move |_, _: mlua::MultiValue| tls.reload())?,
+                )?;
+            }
+
+            let lifecycle = configuration.lifecycle.clone();
+            let shutdown_timeout = server
+                .get::<Option<f64>>("shutdown_timeout")?
+                .map(configs::parse_timeout)
+                .transpose()?;
+            let shutdown_delay = server
+                .get::<Option<f64>>("shutdown_delay")?
+                .map(configs::parse_timeout)
+                .transpose()?;
+            let start_hooks = server
+                .get::<Option<Vec<mlua::Function>>>("start_hooks")?
+                .unwrap_or_default();
+            let shutdown_hooks = server
+                .get::<Option<Vec<mlua::Function>>>("shutdown_hooks")?
+                .unwrap_or_default();
 
-            #[allow(clippy::expect_used)]
-            let listener = tokio::net::TcpListener::bind(listener_address.clone())
-                .await
-                .expect("Could not create a TCP listener");
+            server.set(
+                "is_draining",
+                lua.create_function({
+                    let lifecycle = lifecycle.clone();
+                    move |_, _: mlua::MultiValue| Ok(lifecycle.is_draining())
+                })?,
+            )?;
 
             let (shutdown_tx, mut shutdown_rx) = tokio::sync::mpsc::channel(1);
             let shutdown_tx = std::sync::Arc::new(tokio::sync::Mutex::new(shutdown_tx));
@@ -43,13 +111,10 @@ pub fn register_to_lua(lua: &mlua::Lua) -> mlua::Result<()> {
                 })?,
             )?;
 
-            #[allow(clippy::expect_used)]
-            axum::serve(
-                listener,
-                crate::components::http::server::routes::load_routes(server)
-                    .into_make_service_with_connect_info::<std::net::SocketAddr>(),
-            )
-            .with_graceful_shutdown(async move {
+            let (stopping_tx, stopping_rx) = tokio::sync::oneshot::channel::<()>();
+            let shutdown_server = server.clone();
+            let shutdown_lifecycle = lifecycle.clone();
+            let shutdown_signal = async move {
                 let sigint = tokio::signal::ctrl_c();
                 #[cfg(unix)]
                 if let Ok(mut sigterm) =
@@ -62,6 +127,7 @@ pub fn register_to_lua(lua: &mlua::Lua) -> mlua::Result<()> {
                         _ = sigquit.recv() => {}
                         _ = sigint => {}
                         _ = shutdown_rx.recv() => {}
+                        _ = lifecycle::stop_requested() => {}
                     }
                 }
 
@@ -70,13 +136,68 @@ pub fn register_to_lua(lua: &mlua::Lua) -> mlua::Result<()> {
                     tokio::select! {
                         _ = sigint => {}
                         _ = shutdown_rx.recv() => {}
+                        _ = lifecycle::stop_requested() => {}
+                    }
+                }
+
+                // the health endpoint reports the shutdown while the hooks clean up
+                shutdown_lifecycle.start_draining();
+                for hook in shutdown_hooks {
+                    if let Err(e) = hook.call_async::<()>(shutdown_server.clone()).await {
+                        tracing::error!("Error running a shutdown hook: {e}");
                     }
                 }
+                // keeps accepting requests while load balancers notice the server is draining
+                if let Some(delay) = shutdown_delay {
+                    tokio::time::sleep(delay).await;
+                }
+                // WebSockets and streams stay open for as long as the client wants otherwise
+                shutdown_lifecycle.close_connections();
+                let _ = stopping_tx.send(());
+            };
+
+            if !start_hooks.is_empty() {
+                let server = server.clone();
+                // run alongside the server, so that the hooks can make requests to it
+                tokio::spawn(async move {
+                    for hook in start_hooks {
+                        if let Err(e) = hook.call_async::<()>(server.clone()).await {
+                            tracing::error!("Error running a start hook: {e}");
+                        }
+                    }
+                });
+            }
+
+            let app = crate::components::http::server::routes::load_routes(&lua, server, configuration)?
+                .into_make_service_with_connect_info::<listener::Peer>();
 
-                // let _ = shutdown_rx.recv().await;
-            })
-            .await
-            .expect("Could not start the HTTP server");
+            let serve = axum::serve(listeners, app)
+                .with_graceful_shutdown(shutdown_signal)
+                .into_future();
+            let served = match shutdown_timeout {
+                Some(shutdown_timeout) => {
+                    tokio::pin!(serve);
+                    tokio::select! {
+                        served = &mut serve => served,
+                        _ = async {
+                            if stopping_rx.await.is_ok() {
+                                tokio::time::sleep(shutdown_timeout).await;
+                            } else {
+                                std::future::pending::<()>().await;
+                            }
+                        } => {
+                            tracing::warn!("Cutting off the requests still running after the shutdown timeout");
+                            lifecycle.cut_off();
+                            // the cut off requests still get to send their response
+                            tokio::time::timeout(CUT_OFF_GRACE, &mut serve)
+                                .await
+                                .unwrap_or(Ok(()))
+                        }
+                    }
+                }
+                None => serve.await,
+            };
+            served.map_err(|e| mlua::Error::runtime(format!("The HTTP server stopped: {e}")))?;
 
             Ok(())
         })?,
@@ -0,0 +1,258 @@
+use super::requests::RequestBody;
+use mlua::{ExternalError, UserData};
+use std::{collections::HashMap, sync::Arc};
+use tokio::{io::AsyncWriteExt, sync::Mutex};
+
+/// Limits of a streamed multipart upload, anything over them ends the request with 413 or 415.
+#[derive(Debug, Clone, Default, serde::Deserialize)]
+pub struct MultipartOptions {
+    /// Bytes all of the fields can take up together
+    pub max_size: Option<u64>,
+    /// Bytes a single field can take up
+    pub max_field_size: Option<u64>,
+    /// Bytes specific fields can take up, by name
+    pub field_sizes: Option<HashMap<String, u64>>,
+    /// Content types files can have, such as `image/png` or `image/*`
+    pub content_types: Option<Vec<String>>,
+}
+impl MultipartOptions {
+    fn constraints(&self) -> multer::Constraints {
+        let mut size_limit = multer::SizeLimit::new();
+        if let Some(max_size) = self.max_size {
+            size_limit = size_limit.whole_stream(max_size);
+        }
+        if let Some(max_field_size) = self.max_field_size {
+            size_limit = size_limit.per_field(max_field_size);
+        }
+        for (name, max_size) in self.field_sizes.iter().flatten() {
+            size_limit = size_limit.for_field(name.clone(), *max_size);
+        }
+
+        multer::Constraints::new().size_limit(size_limit)
+    }
+
+    fn allows(&self, content_type: &str) -> bool {
+        let Some(content_types) = &self.content_types else {
+            return true;
+        };
+
+        let essence = content_type
+            .split(';')
+            .next()
+            .unwrap_or_default()
+            .trim()
+            .to_ascii_lowercase();
+        content_types.iter().any(|allowed| {
+            let allowed = allowed.to_ascii_lowercase();
+            match allowed.strip_suffix("/*") {
+                Some(kind) => kind == "*" || essence.split('/').next() == Some(kind),
+                None => allowed == essence,
+            }
+        })
+    }
+}
+
+/// The field that is currently being read. Only one of them can be read at a time, moving on
+/// to the next one drops whatever is left of it.
+#[derive(Default)]
+struct CurrentField {
+    index: usize,
+    field: Option<multer::Field<'static>>,
+}
+
+/// Marks the body as rejected when the upload goes over its limits, so that the request is
+/// answered with 413 even if the handler does not catch the error.
+async fn read_error(body: &Mutex<RequestBody>, error: multer::Error) -> mlua::Error {
+    if matches!(
+        error,
+        multer::Error::FieldSizeExceeded { .. } | multer::Error::StreamSizeExceeded { .. }
+    ) {
+        *body.lock().await = RequestBody::TooLarge;
+    }
+
+    error.into_lua_err()
+}
+
+pub struct AstraMultipartStream {
+    multipart: Arc<Mutex<multer::Multipart<'static>>>,
+    current: Arc<Mutex<CurrentField>>,
+    body: Arc<Mutex<RequestBody>>,
+    options: MultipartOptions,
+}
+impl AstraMultipartStream {
+    pub fn new(
+        body: Arc<Mutex<RequestBody>>,
+        body_limit: usize,
+        boundary: String,
+        options: MultipartOptions,
+    ) -> mlua::Result<Self> {
+        let stream = futures::stream::unfold(body.clone(), move |body| async move {
+            let chunk = body.lock().await.next_chunk(body_limit).await;
+            match chunk {
+                Ok(Some(chunk)) => Some((Ok(chunk), body)),
+                Ok(None) => None,
+                Err(e) => Some((Err(e), body)),
+            }
+        });
+
+        Ok(Self {
+            multipart: Arc::new(Mutex::new(multer::Multipart::with_constraints(
+                stream,
+                boundary,
+                options.constraints(),
+            ))),
+            current: Arc::new(Mutex::new(CurrentField::default())),
+            body,
+            options,
+        })
+    }
+}
+impl UserData for AstraMultipartStream {
+    fn add_methods<M: mlua::UserDataMethods<Self>>(methods: &mut M) {
+        methods.add_async_method("next_field", |_, this, ()| async move {
+            let mut current = this.current.lock().await;
+            current.field = None;
+
+            let field = match this.multipart.lock().await.next_field().await {
+                Ok(Some(field)) => field,
+                Ok(None) => return Ok(None),
+                Err(e) => return Err(read_error(&this.body, e).await),
+            };
+
+            let name = field.name().unwrap_or_default().to_string();
+            let file_name = field.file_name().map(|file_name| file_name.to_string());
+            let content_type = field
+                .content_type()
+                .map(|content_type| content_type.to_string());
+
+            // fields that are not files are always let through
+            if let Some(content_type) = content_type
+                .as_deref()
+                .or(file_name.as_ref().map(|_| "application/octet-stream"))
+                && !this.options.allows(content_type)
+            {
+                *this.body.lock().await = RequestBody::UnsupportedMediaType;
+                return Err(mlua::Error::runtime(format!(
+                    "The field {name} has the content type {content_type}, which is not allowed"
+                )));
+            }
+
+            let headers = field
+                .headers()
+                .iter()
+                .map(|(key, value)| {
+                    (
+                        key.as_str().to_string(),
+                        value.to_str().unwrap_or_default().to_string(),
+                    )
+                })
+                .collect();
+
+            current.index += 1;
+            current.field = Some(field);
+
+            Ok(Some(AstraMultipartStreamField {
+                name,
+                file_name,
+                content_type,
+                headers,
+                index: current.index,
+                current: this.current.clone(),
+                body: this.body.clone(),
+            }))
+        });
+    }
+}
+
+pub struct AstraMultipartStreamField {
+    name: String,
+    file_name: Option<String>,
+    content_type: Option<String>,
+    headers: HashMap<String, String>,
+    index: usize,
+    current: Arc<Mutex<CurrentField>>,
+    body: Arc<Mutex<RequestBody>>,
+}
+impl AstraMultipartStreamField {
+    /// Reads the next chunk of the field, as long as the stream has not moved past it.
+    async fn chunk(&self) -> mlua::Result<Option<bytes::Bytes>> {
+        let mut current = self.current.lock().await;
+        if current.index != self.index {
+            return Err(mlua::Error::runtime(format!(
+                "The field {} can no longer be read, the upload has moved on to the next field",
+                self.name
+            )));
+        }
+
+        let Some(field) = current.field.as_mut() else {
+            return Ok(None);
+        };
+        match field.chunk().await {
+            Ok(Some(chunk)) => Ok(Some(chunk)),
+            Ok(None) => {
+                current.field = None;
+                Ok(None)
+            }
+            Err(e) => {
+                current.field = None;
+                Err(read_error(&self.body, e).await)
+            }
+        }
+    }
+
+    async fn bytes(&self) -> mlua::Result<bytes::Bytes> {
+        let mut buffer = bytes::BytesMut::new();
+        while let Some(chunk) = self.chunk().await? {
+            buffer.extend_from_slice(&chunk);
+        }
+
+        Ok(buffer.freeze())
+    }
+
+    /// Writes the rest of the field to disk, removing the file again if the upload fails.
+    async fn save_file(&self, path: &str) -> mlua::Result<usize> {
+        let mut file = tokio::fs::File::create(path).await?;
+        let mut written = 0;
+
+        let result = async {
+            while let Some(chunk) = self.chunk().await? {
+                file.write_all(&chunk).await?;
+                written += chunk.len();
+            }
+            file.flush().await?;
+
+            Ok::<_, mlua::Error>(written)
+        }
+        .await;
+
+        if result.is_err() {
+            drop(file);
+            let _ = tokio::fs::remove_file(path).await;
+        }
+
+        result
+    }
+}
+impl UserData for AstraMultipartStreamField {
+    fn add_methods<M: mlua::UserDataMethods<Self>>(methods: &mut M) {
+        methods.add_method("name", |_, this, ()| Ok(this.name.clone()));
+        methods.add_method("file_name", |_, this, ()| Ok(this.file_name.clone()));
+        methods.add_method("content_type", |_, this, ()| Ok(this.content_type.clone()));
+        methods.add_method("headers", |_, this, ()| Ok(this.headers.clone()));
+        methods.add_async_method("chunk", |lua, this, ()| async move {
+            match this.chunk().await? {
+                Some(chunk) => Ok(Some(lua.create_string(chunk)?)),
+                None => Ok(None),
+            }
+        });
+        methods.add_async_method("text", |_, this, ()| async move {
+            String::from_utf8(this.bytes().await?.to_vec()).map_err(|e| e.into_lua_err())
+        });
+        methods.add_async_method("bytes", |_, this, ()| async move {
+            Ok(this.bytes().await?.to_vec())
+        });
+        methods.add_async_method("save_file", |_, this, path: String| async move {
+            this.save_file(&path).await
+        });
+    }
+}
@@ -0,0 +1,470 @@
+use super::{
+    configs::RouteConfiguration,
+    errors::escape,
+    params::{self, ParamType},
+};
+use mlua::LuaSerdeExt;
+use serde_json::{Map, Value, json};
+use std::collections::HashMap;
+
+/// The methods OpenAPI has a place for, in the order the docs page lists them.
+const METHODS: [&str; 8] = [
+    "get", "post", "put", "patch", "delete", "head", "options", "trace",
+];
+
+/// Builds the OpenAPI 3.1 document of the routes of a server. `info` becomes the info object
+/// of the document, apart from its `servers` which are moved to the top level.
+pub fn document(
+    lua: &mlua::Lua,
+    server: &mlua::Table,
+    info: Option<mlua::Table>,
+) -> mlua::Result<Value> {
+    let mut info = match info {
+        Some(info) => lua.from_value::<Map<String, Value>>(mlua::Value::Table(info))?,
+        None => Map::new(),
+    };
+    let servers = info.remove("servers");
+    info.entry("title").or_insert_with(|| json!("Astra"));
+    if !info.contains_key("version") {
+        let version = server.get::<Option<String>>("version")?;
+        info.insert(
+            "version".to_string(),
+            json!(version.unwrap_or_else(|| "0.0.0".to_string())),
+        );
+    }
+
+    let mut paths = Map::new();
+    collect(
+        lua,
+        server,
+        "",
+        &RouteConfiguration::default(),
+        &HashMap::new(),
+        None,
+        &mut paths,
+    )?;
+
+    let mut document = json!({ "openapi": "3.1.0", "info": info, "paths": paths });
+    if let Some(servers) = servers {
+        document["servers"] = servers;
+    }
+
+    Ok(document)
+}
+
+/// Adds the operations of the routes of a server or group, recursing into nested groups the
+/// same way the router does. Groups pass their configuration and tags on to their routes.
+fn collect(
+    lua: &mlua::Lua,
+    server: &mlua::Table,
+    prefix: &str,
+    inherited: &RouteConfiguration,
+    inherited_params: &HashMap<String, ParamType>,
+    inherited_tags: Option<&Value>,
+    paths: &mut Map<String, Value>,
+) -> mlua::Result<()> {
+    let Some(routes) = server.get::<Option<mlua::Table>>("routes")? else {
+        return Ok(());
+    };
+
+    for entry in routes.sequence_values::<mlua::Table>() {
+        let entry = entry?;
+        let config = entry.get::<mlua::Value>("config")?;
+        let metadata = match &config {
+            mlua::Value::Table(config) => match config.get::<mlua::Value>("openapi")? {
+                // left out of the document, along with every route of a group
+                mlua::Value::Boolean(false) => continue,
+                mlua::Value::Table(metadata) => Some(metadata),
+                _ => None,
+            },
+            _ => None,
+        };
+
+        let (path, types) = params::parse_path(&entry.get::<String>("path")?)?;
+        let mut param_types = inherited_params.clone();
+        param_types.extend(types);
+        let config = RouteConfiguration::from_lua(lua, config)?.inherit(inherited);
+        let tags = match &metadata {
+            Some(metadata) => metadata
+                .get::<Option<Vec<String>>>("tags")?
+                .map(|tags| json!(tags)),
+            None => None,
+        }
+        .or_else(|| inherited_tags.cloned());
+        let path = join(prefix, &path);
+
+        let methods = match entry.get::<String>("method")?.as_str() {
+            "group" => {
+                let group = entry.get::<mlua::Table>("group")?;
+                collect(
+                    lua,
+                    &group,
+                    &path,
+                    &config,
+                    &param_types,
+                    tags.as_ref(),
+                    paths,
+                )?;
+                continue;
+            }
+            "route" => entry
+                .get::<Vec<String>>("methods")?
+                .into_iter()
+                .map(|method| method.to_lowercase())
+                .collect(),
+            method => vec![method.to_string()],
+        };
+
+        for method in methods {
+            // websockets, static files, fallbacks and custom verbs have no operation to describe
+            if !METHODS.contains(&method.as_str()) {
+                continue;
+            }
+
+            let operation = operation(
+                lua,
+                &path,
+                &config,
+                &param_types,
+                metadata.as_ref(),
+                tags.clone(),
+            )?;
+            // catch-all parameters are plain parameters to OpenAPI
+            let path = path.replace("{*", "{");
+            if let Value::Object(item) = paths.entry(path).or_insert_with(|| json!({})) {
+                item.insert(method, operation);
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Joins the prefix of a group with the path of one of its routes, where `/` is the group itself.
+pub fn join(prefix: &str, path: &str) -> String {
+    let prefix = prefix.trim_end_matches('/');
+    match path {
+        "" | "/" if !prefix.is_empty() => prefix.to_string(),
+        _ => format!("{prefix}{path}"),
+    }
+}
+
+fn path_params(path: &str) -> Vec<String> {
+    path.split('{')
+        .skip(1)
+        .filter_map(|part| part.split_once('}'))
+        .map(|(name, _)| name.trim_start_matches('*').to_string())
+        .filter(|name| !name.is_empty())
+        .collect()
+}
+
+fn param_schema(param_type: ParamType) -> Value {
+    match param_type {
+        ParamType::Int => json!({ "type": "integer" }),
+        ParamType::Number => json!({ "type": "number" }),
+        ParamType::String => json!({ "type": "string" }),
+        ParamType::Uuid => json!({ "type": "string", "format": "uuid" }),
+        ParamType::Bool => json!({ "type": "boolean" }),
+    }
+}
+
+/// JSON Schema of a `validation` schema. Tables without a `json_schema` method are taken as a
+/// JSON Schema already.
+fn json_schema(lua: &mlua::Lua, schema: &mlua::Table) -> mlua::Result<Value> {
+    match schema.get::<Option<mlua::Function>>("json_schema")? {
+        Some(json_schema) => lua.from_value(json_schema.call::<mlua::Value>(schema)?),
+        None => lua.from_value(mlua::Value::Table(schema.clone())),
+    }
+}
+
+fn operation(
+    lua: &mlua::Lua,
+    path: &str,
+    config: &RouteConfiguration,
+    param_types: &HashMap<String, ParamType>,
+    metadata: Option<&mlua::Table>,
+    tags: Option<Value>,
+) -> mlua::Result<Value> {
+    let mut operation = Map::new();
+
+    if let Some(metadata) = metadata {
+        for (key, field) in [
+            ("summary", "summary"),
+            ("description", "description"),
+            ("operation_id", "operationId"),
+            ("deprecated", "deprecated"),
+        ] {
+            let value = metadata.get::<mlua::Value>(key)?;
+            if !value.is_nil() {
+                operation.insert(field.to_string(), lua.from_value(value)?);
+            }
+        }
+    }
+    if let Some(tags) = tags {
+        operation.insert("tags".to_string(), tags);
+    }
+
+    let mut parameters = Vec::new();
+    let params_schema = config
+        .params
+        .as_ref()
+        .map(|schema| json_schema(lua, schema))
+        .transpose()?;
+    for name in path_params(path) {
+        let schema = params_schema
+            .as_ref()
+            .and_then(|schema| schema.get("properties")?.get(&name).cloned())
+            .or_else(|| param_types.get(&name).copied().map(param_schema))
+            .unwrap_or_else(|| json!({ "type": "string" }));
+        parameters.push(json!({ "name": name, "in": "path", "required": true, "schema": schema }));
+    }
+    if let Some(schema) = &config.query_schema {
+        let schema = json_schema(lua, schema)?;
+        let required = schema
+            .get("required")
+            .and_then(Value::as_array)
+            .cloned()
+            .unwrap_or_default();
+        if let Some(Value::Object(properties)) = schema.get("properties") {
+            for (name, schema) in properties {
+                parameters.push(json!({
+                    "name": name,
+                    "in": "query",
+                    "required": required.contains(&json!(name)),
+                    "schema": schema,
+                }));
+            }
+        }
+    }
+    if !parameters.is_empty() {
+        operation.insert("parameters".to_string(), json!(parameters));
+    }
+
+    if let Some(schema) = &config.body_schema {
+        operation.insert(
+            "requestBody".to_string(),
+            json!({
+                "required": true,
+                "content": { "application/json": { "schema": json_schema(lua, schema)? } },
+            }),
+        );
+    }
+
+    let mut responses = Map::new();
+    if let Some(metadata) = metadata
+        && let Some(declared) = metadata.get::<Option<mlua::Table>>("responses")?
+    {
+        for pair in declared.pairs::<mlua::Value, mlua::Value>() {
+            let (status, response) = pair?;
+            let status = match status {
+                mlua::Value::Integer(status) => status.to_string(),
+                mlua::Value::Number(status) => (status as i64).to_string(),
+                mlua::Value::String(status) => status.to_str()?.to_string(),
+                status => {
+                    return Err(mlua::Error::runtime(format!(
+                        "Expected a status code for the response of {path}, got {}",
+                        status.type_name()
+                    )));
+                }
+            };
+            responses.insert(status, response_object(lua, path, response)?);
+        }
+    }
+    if responses.is_empty() {
+        responses.insert("200".to_string(), json!({ "description": "OK" }));
+    }
+    if config.body_schema.is_some() || config.query_schema.is_some() {
+        responses
+            .entry("422")
+            .or_insert_with(|| json!({ "description": "The request did not match its schema" }));
+    }
+    operation.insert("responses".to_string(), Value::Object(responses));
+
+    Ok(Value::Object(operation))
+}
+
+/// A response is either its description, or a table with a `description`, the `schema` of
+/// its body and the `content_type` of the body, which defaults to JSON.
+fn response_object(lua: &mlua::Lua, path: &str, response: mlua::Value) -> mlua::Result<Value> {
+    match response {
+        mlua::Value::String(description) => {
+            Ok(json!({ "description": description.to_str()?.to_string() }))
+        }
+        mlua::Value::Table(response) => {
+            let mut object = Map::new();
+            object.insert(
+                "description".to_string(),
+                json!(
+                    response
+                        .get::<Option<String>>("description")?
+                        .unwrap_or_default()
+                ),
+            );
+            if let Some(schema) = response.get::<Option<mlua::Table>>("schema")? {
+                let content_type = response
+                    .get::<Option<String>>("content_type")?
+                    .unwrap_or_else(|| "application/json".to_string());
+                let mut content = Map::new();
+                content.insert(
+                    content_type,
+                    json!({ "schema": json_schema(lua, &schema)? }),
+                );
+                object.insert("content".to_string(), Value::Object(content));
+            }
+
+            Ok(Value::Object(object))
+        }
+        response => Err(mlua::Error::runtime(format!(
+            "Expected a description or a table for a response of {path}, got {}",
+            response.type_name()
+        ))),
+    }
+}
+
+/// Renders the document as a page listing every operation, without any scripts so that it
+/// works offline.
+pub fn docs_page(document: &Value) -> String {
+    fn text(value: Option<&Value>) -> &str {
+        value.and_then(Value::as_str).unwrap_or_default()
+    }
+    let pretty = |value: &Value| escape(&serde_json::to_string_pretty(value).unwrap_or_default());
+    let info = document.get("info");
+
+    let mut operations = String::new();
+    if let Some(Value::Object(paths)) = document.get("paths") {
+        for (path, item) in paths {
+            for method in METHODS {
+                let Some(operation) = item.get(method) else {
+                    continue;
+                };
+
+                operations.push_str(&format!(
+                    "<section><h2><span class=\"method {method}\">{}</span> <code>{}</code></h2>",
+                    method.to_uppercase(),
+                    escape(path)
+                ));
+                if let Some(summary) = operation.get("summary").and_then(Value::as_str) {
+                    operations.push_str(&format!("<p><strong>{}</strong></p>", escape(summary)));
+                }
+                if let Some(description) = operation.get("description").and_then(Value::as_str) {
+                    operations.push_str(&format!("<p>{}</p>", escape(description)));
+                }
+
+                if let Some(Value::Array(parameters)) = operation.get("parameters") {
+                    operations.push_str(
+                        "<h3>Parameters</h3><table><tr><th>Name</th><th>In</th><th>Required</th><th>Schema</th></tr>",
+                    );
+                    for parameter in parameters {
+                        operations.push_str(&format!(
+                            "<tr><td><code>{}</code></td><td>{}</td><td>{}</td><td><code>{}</code></td></tr>",
+                            escape(text(parameter.get("name"))),
+                            escape(text(parameter.get("in"))),
+                            parameter
+                                .get("required")
+                                .and_then(Value::as_bool)
+                                .unwrap_or(false),
+                            escape(&parameter.get("schema").map(Value::to_string).unwrap_or_default())
+                        ));
+                    }
+                    operations.push_str("</table>");
+                }
+
+                if let Some(Value::Object(content)) = operation
+                    .get("requestBody")
+                    .and_then(|body| body.get("content"))
+                {
+                    for (content_type, media) in content {
+                        operations.push_str(&format!(
+                            "<h3>Request body <code>{}</code></h3><pre>{}</pre>",
+                            escape(content_type),
+                            pretty(media.get("schema").unwrap_or(&Value::Null))
+                        ));
+                    }
+                }
+
+                if let Some(Value::Object(responses)) = operation.get("responses") {
+                    operations.push_str("<h3>Responses</h3><dl>");
+                    for (status, response) in responses {
+                        operations.push_str(&format!(
+                            "<dt><code>{}</code></dt><dd>{}",
+                            escape(status),
+                            escape(text(response.get("description")))
+                        ));
+                        if let Some(Value::Object(content)) = response.get("content") {
+                            for (content_type, media) in content {
+                                operations.push_str(&format!(
+                                    "<p><code>{}</code></p><pre>{}</pre>",
+                                    escape(content_type),
+                                    pretty(media.get("schema").unwrap_or(&Value::Null))
+                                ));
+                            }
+                        }
+                        operations.push_str("</dd>");
+                    }
+                    operations.push_str("</dl>");
+                }
+
+                operations.push_str("</section>");
+            }
+        }
+    }
+
+    let description = info
+        .and_then(|info| info.get("description"))
+        .and_then(Value::as_str)
+        .map(|description| format!("<p>{}</p>", escape(description)))
+        .unwrap_or_default();
+
+    format!(
+        r#"<!DOCTYPE html>
+<html>
+<head>
+<meta charset="utf-8">
+<title>{title}</title>
+<style>
+body {{ font-family: sans-serif; margin: 2rem; color: #222; max-width: 60rem; }}
+section {{ border-top: 1px solid #ddd; padding: 0.5rem 0; }}
+pre {{ background: #f5f5f5; padding: 1rem; overflow-x: auto; }}
+th, td {{ text-align: left; padding-right: 1rem; }}
+.method {{ display: inline-block; min-width: 5rem; padding: 0.1rem 0.4rem; border-radius: 4px; color: #fff; background: #555; font-size: 0.9em; text-align: center; }}
+.get {{ background: #2f6fb3; }}
+.post {{ background: #2e8b57; }}
+.put, .patch {{ background: #b7791f; }}
+.delete {{ background: #c0392b; }}
+</style>
+</head>
+<body>
+<h1>{title} <small>{version}</small></h1>
+{description}
+{operations}
+</body>
+</html>"#,
+        title = escape(text(info.and_then(|info| info.get("title")))),
+        version = escape(text(info.and_then(|info| info.get("version")))),
+    )
+}
+
+pub fn register_to_lua(lua: &mlua::Lua) -> mlua::Result<()> {
+    lua.globals().set(
+        "astra_internal__openapi",
+        lua.create_function(
+            |lua, (server, info, format): (mlua::Table, Option<mlua::Table>, Option<String>)| {
+                let document = document(lua, &server, info)?;
+
+                match format.as_deref() {
+                    None => lua.to_value(&document),
+                    Some("json") => serde_json::to_string_pretty(&document)
+                        .map_err(mlua::Error::external)
+                        .and_then(|json| lua.to_value(&json)),
+                    Some("yaml") => serde_yaml::to_string(&document)
+                        .map_err(mlua::Error::external)
+                        .and_then(|yaml| lua.to_value(&yaml)),
+                    Some("html") => lua.to_value(&docs_page(&document)),
+                    Some(format) => Err(mlua::Error::runtime(format!(
+                        "Unknown format {format} for the OpenAPI document, expected json, yaml or html"
+                    ))),
+                }
+            },
+        )?,
+    )
+}
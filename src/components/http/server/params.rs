@@ -0,0 +1,122 @@
+use std::collections::HashMap;
+
+/// The types a path parameter can be declared with, as in `/users/{id:int}`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ParamType {
+    Int,
+    Number,
+    String,
+    Uuid,
+    Bool,
+}
+impl ParamType {
+    fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "int" | "integer" => Some(Self::Int),
+            "number" | "float" => Some(Self::Number),
+            "string" | "str" => Some(Self::String),
+            "uuid" => Some(Self::Uuid),
+            "bool" | "boolean" => Some(Self::Bool),
+            _ => None,
+        }
+    }
+}
+
+pub type ParamTypes = std::sync::Arc<HashMap<String, ParamType>>;
+
+/// Lua versions before 5.3, LuaJIT and Luau keep integers as doubles, which only hold them
+/// exactly up to 2^53.
+const NATIVE_INTEGERS: bool = cfg!(any(feature = "lua53", feature = "lua54", feature = "lua55"));
+
+fn is_exact_integer(integer: i64) -> bool {
+    NATIVE_INTEGERS || integer.unsigned_abs() <= 1 << 53
+}
+
+/// Strips the types off of the parameters of a path so that axum can route it, returning
+/// the declared types alongside.
+pub fn parse_path(path: &str) -> mlua::Result<(String, HashMap<String, ParamType>)> {
+    let mut stripped = String::with_capacity(path.len());
+    let mut types = HashMap::new();
+    let mut rest = path;
+
+    while let Some(start) = rest.find('{') {
+        stripped.push_str(&rest[..=start]);
+        rest = &rest[start + 1..];
+
+        // `{{` is an escaped brace rather than a parameter
+        if let Some(after) = rest.strip_prefix('{') {
+            stripped.push('{');
+            rest = after;
+            continue;
+        }
+
+        let Some(end) = rest.find('}') else {
+            break;
+        };
+        let parameter = &rest[..end];
+        rest = &rest[end..];
+
+        match parameter.split_once(':') {
+            Some((name, kind)) => {
+                let param_type = ParamType::from_name(kind.trim()).ok_or_else(|| {
+                    mlua::Error::runtime(format!(
+                        "Unknown type `{kind}` for the parameter `{name}` in {path}"
+                    ))
+                })?;
+
+                stripped.push_str(name);
+                types.insert(name.trim_start_matches('*').to_string(), param_type);
+            }
+            None => stripped.push_str(parameter),
+        }
+    }
+    stripped.push_str(rest);
+
+    Ok((stripped, types))
+}
+
+/// Converts a raw parameter to its declared type, `None` meaning it does not match.
+/// Parameters without a type only become numbers when no digits would be lost doing so.
+pub fn param_value(
+    lua: &mlua::Lua,
+    value: &str,
+    param_type: Option<ParamType>,
+) -> mlua::Result<Option<mlua::Value>> {
+    Ok(match param_type {
+        Some(ParamType::Int) => value
+            .parse::<i64>()
+            .ok()
+            .filter(|integer| is_exact_integer(*integer))
+            .map(mlua::Value::Integer),
+        Some(ParamType::Number) => value
+            .parse::<f64>()
+            .ok()
+            .filter(|number| number.is_finite())
+            .map(mlua::Value::Number),
+        Some(ParamType::Bool) => match value {
+            "true" => Some(mlua::Value::Boolean(true)),
+            "false" => Some(mlua::Value::Boolean(false)),
+            _ => None,
+        },
+        Some(ParamType::Uuid) => match uuid::Uuid::try_parse(value) {
+            Ok(_) => Some(mlua::Value::String(lua.create_string(value)?)),
+            Err(_) => None,
+        },
+        Some(ParamType::String) => Some(mlua::Value::String(lua.create_string(value)?)),
+        None => {
+            if let Ok(integer) = value.parse::<i64>()
+                && integer.to_string() == value
+                && is_exact_integer(integer)
+            {
+                Some(mlua::Value::Integer(integer))
+            } else if let Ok(number) = value.parse::<f64>()
+                && number.is_finite()
+                && number.to_string() == value
+            {
+                Some(mlua::Value::Number(number))
+            } else {
+                Some(mlua::Value::String(lua.create_string(value)?))
+            }
+        }
+    })
+}
@@ -0,0 +1,193 @@
+use super::{cookie::CookieKeys, listener::Peer, requests::RequestLua};
+use axum::{
+    body::Body,
+    extract::{ConnectInfo, State},
+    http::{Request, StatusCode, header::RETRY_AFTER},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use mlua::LuaSerdeExt;
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex, PoisonError},
+    time::{Duration, Instant},
+};
+
+/// Every this many new clients, the ones that are back to a full bucket are forgotten.
+const PRUNE_INTERVAL: usize = 10_000;
+
+#[derive(Debug, Clone, Default, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct RateLimitConfiguration {
+    /// How many requests are allowed per window
+    pub requests: u32,
+    /// Length of the window in seconds, defaults to a second
+    pub window: Option<f64>,
+    /// How many requests can be made at once before being throttled, defaults to `requests`
+    pub burst: Option<u32>,
+    /// Counts the requests per value of this header instead of per IP address when present
+    pub header: Option<String>,
+    /// Counts the requests per returned key instead of per IP address
+    #[serde(skip)]
+    pub key: Option<mlua::Function>,
+}
+impl RateLimitConfiguration {
+    /// Deserializes the configuration, keeping hold of the key function serde cannot represent.
+    pub fn from_lua(lua: &mlua::Lua, value: mlua::Value) -> mlua::Result<Self> {
+        let key = match &value {
+            mlua::Value::Table(table) => match table.get::<mlua::Value>("key")? {
+                mlua::Value::Function(key) => Some(key),
+                _ => None,
+            },
+            _ => None,
+        };
+
+        let mut configuration: Self = lua.from_value_with(
+            value,
+            mlua::DeserializeOptions::new().deny_unsupported_types(false),
+        )?;
+        configuration.key = key;
+
+        Ok(configuration)
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+#[derive(Debug, Default)]
+struct Buckets {
+    clients: HashMap<String, Bucket>,
+    /// New clients since the last time the full buckets were forgotten
+    added: usize,
+}
+
+/// A token bucket per client, refilled at `requests / window` tokens per second.
+#[derive(Debug)]
+pub struct RateLimiter {
+    configuration: RateLimitConfiguration,
+    rate: f64,
+    capacity: f64,
+    cookie_keys: CookieKeys,
+    buckets: Mutex<Buckets>,
+}
+impl RateLimiter {
+    pub fn new(
+        configuration: RateLimitConfiguration,
+        cookie_keys: CookieKeys,
+    ) -> mlua::Result<Arc<Self>> {
+        let window = configuration.window.unwrap_or(1.0);
+        if configuration.requests == 0 || !(window.is_finite() && window > 0.0) {
+            return Err(mlua::Error::runtime(
+                "The rate limit needs a positive amount of requests and window",
+            ));
+        }
+
+        Ok(Arc::new(Self {
+            rate: f64::from(configuration.requests) / window,
+            capacity: f64::from(configuration.burst.unwrap_or(configuration.requests).max(1)),
+            configuration,
+            cookie_keys,
+            buckets: Mutex::new(Buckets::default()),
+        }))
+    }
+
+    /// Takes a token from the client's bucket, or returns how long until one is available.
+    fn acquire(&self, key: String) -> Result<(), Duration> {
+        let now = Instant::now();
+        // the buckets stay consistent even if a thread panicked while holding them
+        let mut buckets = self.buckets.lock().unwrap_or_else(PoisonError::into_inner);
+
+        if !buckets.clients.contains_key(&key) {
+            buckets.added += 1;
+            if buckets.added >= PRUNE_INTERVAL {
+                buckets.added = 0;
+                buckets.clients.retain(|_, bucket| {
+                    bucket.tokens + now.duration_since(bucket.updated).as_secs_f64() * self.rate
+                        < self.capacity
+                });
+            }
+        }
+
+        let bucket = buckets.clients.entry(key).or_insert(Bucket {
+            tokens: self.capacity,
+            updated: now,
+        });
+        bucket.tokens = (bucket.tokens
+            + now.duration_since(bucket.updated).as_secs_f64() * self.rate)
+            .min(self.capacity);
+        bucket.updated = now;
+
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64((1.0 - bucket.tokens) / self.rate))
+        }
+    }
+
+    /// Works out who the request should be counted against, `None` exempts it.
+    async fn key(&self, request: Request<Body>) -> mlua::Result<(Option<String>, Request<Body>)> {
+        if let Some(function) = &self.configuration.key {
+            let (parts, body) = request.into_parts();
+            let request_lua = RequestLua::new(
+                Request::from_parts(parts.clone(), Body::empty()),
+                None,
+                self.cookie_keys.clone(),
+            )
+            .await;
+
+            let key = function.call_async::<Option<String>>(request_lua).await?;
+            return Ok((key, Request::from_parts(parts, body)));
+        }
+
+        // clients without the header are still counted by their address
+        if let Some(header) = &self.configuration.header
+            && let Some(key) = request
+                .headers()
+                .get(header)
+                .and_then(|value| value.to_str().ok())
+        {
+            let key = format!("{header}:{key}");
+            return Ok((Some(key), request));
+        }
+
+        // clients of a Unix socket have no address to be counted by, and are exempt
+        let key = request
+            .extensions()
+            .get::<ConnectInfo<Peer>>()
+            .and_then(|connect_info| connect_info.ip())
+            .map(|ip| ip.to_string());
+        Ok((key, request))
+    }
+}
+
+pub async fn layer(
+    State(limiter): State<Arc<RateLimiter>>,
+    request: Request<Body>,
+    next: Next,
+) -> Response {
+    let request = match limiter.key(request).await {
+        Ok((Some(key), request)) => match limiter.acquire(key) {
+            Ok(()) => request,
+            Err(retry_after) => {
+                let retry_after = retry_after.as_secs_f64().ceil().max(1.0) as u64;
+                return (
+                    StatusCode::TOO_MANY_REQUESTS,
+                    [(RETRY_AFTER, retry_after.to_string())],
+                )
+                    .into_response();
+            }
+        },
+        Ok((None, request)) => request,
+        Err(e) => {
+            tracing::error!("Error executing the rate limit key function: {e}");
+
+            return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+        }
+    };
+
+    next.run(request).await
+}
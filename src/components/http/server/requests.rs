@@ -1,4 +1,11 @@
-use super::cookie::AstraHTTPCookie;
+use super::{
+    cookie::{AstraHTTPCookie, CookieKeys},
+    csrf::CsrfToken,
+    listener::Peer,
+    multipart::{AstraMultipartStream, MultipartOptions},
+    params::{ParamTypes, param_value},
+    session::AstraSession,
+};
 use crate::components::AstraBuffer;
 use axum::{
     body::Body,
@@ -6,28 +13,106 @@ use axum::{
     http::{Request, request::Parts},
 };
 use axum_extra::extract::{CookieJar, cookie::Cookie};
+use futures::StreamExt;
 use mlua::{ExternalError, LuaSerdeExt, UserData};
 use std::collections::HashMap;
-use tokio::io::AsyncWriteExt;
+
+/// The request body is only pulled from the connection once the handler asks for it.
+#[derive(Debug)]
+pub enum RequestBody {
+    Unread(Body),
+    Buffered(bytes::Bytes),
+    Streaming {
+        stream: axum::body::BodyDataStream,
+        read: usize,
+    },
+    Consumed,
+    TooLarge,
+    /// A multipart upload contained a file of a type that is not allowed
+    UnsupportedMediaType,
+}
+impl RequestBody {
+    /// Pulls the next chunk of a streaming body while keeping track of the body limit.
+    pub async fn next_chunk(&mut self, limit: usize) -> mlua::Result<Option<bytes::Bytes>> {
+        let Self::Streaming { stream, read } = self else {
+            return Ok(None);
+        };
+
+        match stream.next().await {
+            Some(Ok(chunk)) => {
+                *read += chunk.len();
+                if *read > limit {
+                    *self = Self::TooLarge;
+                    Err(Self::too_large(limit))
+                } else {
+                    Ok(Some(chunk))
+                }
+            }
+            Some(Err(e)) => {
+                *self = Self::Consumed;
+                Err(mlua::Error::runtime(format!(
+                    "Could not read the request body: {e}"
+                )))
+            }
+            None => {
+                *self = Self::Consumed;
+                Ok(None)
+            }
+        }
+    }
+
+    /// The status the request is answered with if reading the body was refused.
+    pub fn rejection(&self) -> Option<axum::http::StatusCode> {
+        match self {
+            Self::TooLarge => Some(axum::http::StatusCode::PAYLOAD_TOO_LARGE),
+            Self::UnsupportedMediaType => Some(axum::http::StatusCode::UNSUPPORTED_MEDIA_TYPE),
+            _ => None,
+        }
+    }
+
+    /// Hands whatever has not been read yet over to the next handler.
+    pub fn take_body(&mut self) -> Body {
+        match std::mem::replace(self, Self::Consumed) {
+            Self::Unread(body) => body,
+            Self::Buffered(bytes) => {
+                *self = Self::Buffered(bytes.clone());
+                Body::from(bytes)
+            }
+            Self::Streaming { stream, .. } => Body::from_stream(stream),
+            Self::Consumed => Body::empty(),
+            rejected @ (Self::TooLarge | Self::UnsupportedMediaType) => {
+                *self = rejected;
+                Body::empty()
+            }
+        }
+    }
+
+    fn too_large(limit: usize) -> mlua::Error {
+        mlua::Error::runtime(format!(
+            "The request body exceeds the limit of {limit} bytes"
+        ))
+    }
+}
 
 #[derive(Debug)]
 pub struct RequestLua {
     pub parts: Parts,
-    pub bytes: Option<bytes::Bytes>,
+    pub body: std::sync::Arc<tokio::sync::Mutex<RequestBody>>,
+    pub body_limit: usize,
     pub cookie_jar: CookieJar,
+    pub cookie_keys: CookieKeys,
+    /// When the handler will be cut off, if it has a timeout
+    pub deadline: Option<tokio::time::Instant>,
+    /// Types declared for the path parameters of the route
+    pub param_types: ParamTypes,
 }
 impl RequestLua {
-    pub async fn new(request: Request<Body>) -> Self {
+    pub async fn new(
+        request: Request<Body>,
+        body_limit: Option<usize>,
+        cookie_keys: CookieKeys,
+    ) -> Self {
         let (mut parts, body) = request.into_parts();
-        let bytes = match axum::body::to_bytes(body, usize::MAX).await {
-            Ok(bytes) => Some(bytes),
-
-            Err(e) => {
-                eprintln!("Error extracting body from request: {e:#?}");
-
-                None
-            }
-        };
 
         let cookie_jar = match CookieJar::from_request_parts(&mut parts, &()).await {
             Ok(cookie) => cookie,
@@ -39,8 +124,109 @@ impl RequestLua {
 
         Self {
             parts,
-            bytes,
+            body: std::sync::Arc::new(tokio::sync::Mutex::new(RequestBody::Unread(body))),
+            body_limit: body_limit.unwrap_or(usize::MAX),
             cookie_jar,
+            cookie_keys,
+            deadline: None,
+            param_types: ParamTypes::default(),
+        }
+    }
+
+    /// Converts the path parameters to their declared types, `None` if one does not match.
+    pub async fn typed_params(&self, lua: &mlua::Lua) -> mlua::Result<Option<mlua::Table>> {
+        let raw_path_params = RawPathParams::from_request_parts(&mut self.parts.clone(), &())
+            .await
+            .map_err(|e| e.into_lua_err())?;
+
+        let params_table = lua.create_table()?;
+        for (key, value) in &raw_path_params {
+            match param_value(lua, value, self.param_types.get(key).copied())? {
+                Some(value) => params_table.set(key, value)?,
+                None => return Ok(None),
+            }
+        }
+
+        Ok(Some(params_table))
+    }
+
+    /// Buffers the whole body in memory, reading it from the connection on the first call.
+    pub async fn bytes(&self) -> mlua::Result<bytes::Bytes> {
+        let mut state = self.body.lock().await;
+
+        match std::mem::replace(&mut *state, RequestBody::Consumed) {
+            RequestBody::Unread(body) => {
+                *state = RequestBody::Streaming {
+                    stream: body.into_data_stream(),
+                    read: 0,
+                };
+            }
+            RequestBody::Buffered(bytes) => {
+                *state = RequestBody::Buffered(bytes.clone());
+                return Ok(bytes);
+            }
+            RequestBody::Streaming { stream, read } => {
+                *state = RequestBody::Streaming { stream, read };
+                return Err(mlua::Error::runtime(
+                    "The request body is already being streamed",
+                ));
+            }
+            RequestBody::Consumed => {
+                return Err(mlua::Error::runtime(
+                    "The request body has already been consumed",
+                ));
+            }
+            RequestBody::TooLarge => {
+                *state = RequestBody::TooLarge;
+                return Err(RequestBody::too_large(self.body_limit));
+            }
+            RequestBody::UnsupportedMediaType => {
+                *state = RequestBody::UnsupportedMediaType;
+                return Err(mlua::Error::runtime("The request body has been rejected"));
+            }
+        }
+
+        let mut buffer = bytes::BytesMut::new();
+        while let Some(chunk) = state.next_chunk(self.body_limit).await? {
+            buffer.extend_from_slice(&chunk);
+        }
+
+        let bytes = buffer.freeze();
+        *state = RequestBody::Buffered(bytes.clone());
+        Ok(bytes)
+    }
+}
+impl RequestLua {
+    /// Switches the body over to streaming, keeping track of the body limit as it is read.
+    pub async fn streaming_body(
+        &self,
+    ) -> mlua::Result<std::sync::Arc<tokio::sync::Mutex<RequestBody>>> {
+        let mut state = self.body.lock().await;
+
+        match std::mem::replace(&mut *state, RequestBody::Consumed) {
+            RequestBody::Unread(body) => {
+                *state = RequestBody::Streaming {
+                    stream: body.into_data_stream(),
+                    read: 0,
+                };
+                Ok(self.body.clone())
+            }
+            // an already buffered body is replayed without touching the original
+            RequestBody::Buffered(bytes) => {
+                *state = RequestBody::Buffered(bytes.clone());
+                Ok(std::sync::Arc::new(tokio::sync::Mutex::new(
+                    RequestBody::Streaming {
+                        stream: Body::from(bytes).into_data_stream(),
+                        read: 0,
+                    },
+                )))
+            }
+            other => {
+                *state = other;
+                Err(mlua::Error::runtime(
+                    "The request body has already been consumed",
+                ))
+            }
         }
     }
 }
@@ -58,88 +244,102 @@ impl UserData for RequestLua {
             }
         });
         methods.add_async_method("params", |lua, this, ()| async move {
-            let raw_path_params = RawPathParams::from_request_parts(&mut this.parts.clone(), &())
-                .await
-                .map_err(|e| e.into_lua_err())?;
-
-            let params_table = lua.create_table()?;
-
-            for (key, value) in &raw_path_params {
-                if let Ok(value) = value.parse::<i32>() {
-                    params_table.set(key, value)?;
-                } else if let Ok(value) = value.parse::<f32>() {
-                    params_table.set(key, value)?;
-                } else {
-                    params_table.set(key, value)?;
-                }
-            }
-
-            Ok(params_table)
+            this.typed_params(&lua).await?.ok_or_else(|| {
+                mlua::Error::runtime("The path parameters do not match their declared types")
+            })
         });
         methods.add_async_method("ip_address", |_, this, ()| async move {
-            let connect_info = ConnectInfo::<std::net::SocketAddr>::from_request_parts(
-                &mut this.parts.clone(),
-                &(),
-            )
-            .await
-            .map_err(|e| e.into_lua_err())?;
+            let connect_info =
+                ConnectInfo::<Peer>::from_request_parts(&mut this.parts.clone(), &())
+                    .await
+                    .map_err(|e| e.into_lua_err())?;
 
-            Ok(AstraSocketAddr(connect_info.ip()))
+            // clients of a Unix socket have no IP address
+            Ok(connect_info.ip().map(AstraSocketAddr))
         });
         methods.add_async_method("form", |lua, this, ()| async move {
-            match &this.bytes {
-                Some(bytes) => {
-                    let request =
-                        Request::from_parts(this.parts.clone(), Body::from(bytes.clone()));
-
-                    match axum::Form::<Vec<Vec<serde_value::Value>>>::from_request(request, &())
-                        .await
-                    {
-                        Ok(form) => {
-                            let key_value = lua.create_table()?;
-
-                            for i in form.0 {
-                                let key = i.first().and_then(|key| {
-                                    key.clone().deserialize_into::<'_, String>().ok()
-                                });
-                                if key.is_none() {
-                                    continue;
-                                }
-
-                                if i.len() >= 2 {
-                                    let value = i.get(1);
-                                    if value.is_none() {
-                                        continue;
-                                    }
-
-                                    key_value.set(key.clone(), lua.to_value(&value)?)?;
-                                } else {
-                                    key_value.raw_push(key)?;
-                                }
+            let request = Request::from_parts(this.parts.clone(), Body::from(this.bytes().await?));
+
+            match axum::Form::<Vec<Vec<serde_value::Value>>>::from_request(request, &()).await {
+                Ok(form) => {
+                    let key_value = lua.create_table()?;
+
+                    for i in form.0 {
+                        let key = i
+                            .first()
+                            .and_then(|key| key.clone().deserialize_into::<'_, String>().ok());
+                        if key.is_none() {
+                            continue;
+                        }
+
+                        if i.len() >= 2 {
+                            let value = i.get(1);
+                            if value.is_none() {
+                                continue;
                             }
 
-                            Ok(key_value)
+                            key_value.set(key.clone(), lua.to_value(&value)?)?;
+                        } else {
+                            key_value.raw_push(key)?;
                         }
-                        Err(e) => Err(e.into_lua_err()),
                     }
-                }
 
-                None => Err(mlua::Error::runtime("No bytes found")),
+                    Ok(key_value)
+                }
+                Err(e) => Err(e.into_lua_err()),
             }
         });
+        methods.add_method("csrf_token", |_, this, ()| {
+            Ok(this
+                .parts
+                .extensions
+                .get::<CsrfToken>()
+                .map(|CsrfToken(token)| token.clone()))
+        });
+        methods.add_async_method("session", |_, this, ()| async move {
+            let session = this
+                .parts
+                .extensions
+                .get::<AstraSession>()
+                .cloned()
+                .ok_or_else(|| {
+                    mlua::Error::runtime("Sessions are not enabled, set `server.session` first")
+                })?;
+            session.load().await?;
+
+            Ok(session)
+        });
+        methods.add_async_method(
+            "multipart_stream",
+            |lua, this, options: Option<mlua::Table>| async move {
+                let options = match options {
+                    Some(options) => lua.from_value(mlua::Value::Table(options))?,
+                    None => MultipartOptions::default(),
+                };
+                let content_type = this
+                    .parts
+                    .headers
+                    .get(axum::http::header::CONTENT_TYPE)
+                    .and_then(|content_type| content_type.to_str().ok())
+                    .unwrap_or_default();
+                let boundary =
+                    multer::parse_boundary(content_type).map_err(|e| e.into_lua_err())?;
+
+                AstraMultipartStream::new(
+                    this.streaming_body().await?,
+                    this.body_limit,
+                    boundary,
+                    options,
+                )
+            },
+        );
         methods.add_async_method("multipart", |_, this, ()| async move {
-            match &this.bytes {
-                Some(bytes) => {
-                    let multipart_request =
-                        Request::from_parts(this.parts.clone(), Body::from(bytes.clone()));
-
-                    match Multipart::from_request(multipart_request, &()).await {
-                        Ok(multipart) => AstraMultipart::new(multipart).await,
-                        Err(e) => Err(e.into_lua_err()),
-                    }
-                }
+            let multipart_request =
+                Request::from_parts(this.parts.clone(), Body::from(this.bytes().await?));
 
-                None => Err(mlua::Error::runtime("No bytes found")),
+            match Multipart::from_request(multipart_request, &()).await {
+                Ok(multipart) => AstraMultipart::new(multipart).await,
+                Err(e) => Err(e.into_lua_err()),
             }
         });
         methods.add_method("headers", |_, this, ()| {
@@ -156,13 +356,46 @@ impl UserData for RequestLua {
                 .get(name.as_str())
                 .map(|cookie| AstraHTTPCookie(cookie.clone())))
         });
+        methods.add_method("get_signed_cookie", |_, this, name: String| {
+            Ok(this
+                .cookie_keys
+                .get_signed(&this.parts.headers, &name)?
+                .map(AstraHTTPCookie))
+        });
+        methods.add_method("get_private_cookie", |_, this, name: String| {
+            Ok(this
+                .cookie_keys
+                .get_private(&this.parts.headers, &name)?
+                .map(AstraHTTPCookie))
+        });
+        methods.add_method("time_remaining", |_, this, ()| {
+            Ok(this.deadline.map(|deadline| {
+                deadline
+                    .saturating_duration_since(tokio::time::Instant::now())
+                    .as_secs_f64()
+            }))
+        });
         methods.add_method("new_cookie", |_, _, (name, value): (String, String)| {
             Ok(AstraHTTPCookie(Cookie::new(name, value)))
         });
         // ! Create new cookie
-        methods.add_method("body", |_, this, ()| match this.bytes.clone() {
-            Some(bytes) => Ok(AstraBuffer::new(bytes)),
-            None => Ok(AstraBuffer::new(bytes::Bytes::new())),
+        methods.add_async_method("body", |_, this, ()| async move {
+            Ok(AstraBuffer::new(this.bytes().await?))
+        });
+        methods.add_async_method("body_stream", |lua, this, ()| async move {
+            let limit = this.body_limit;
+            let body = this.streaming_body().await?;
+
+            lua.create_async_function(move |lua, ()| {
+                let body = body.clone();
+
+                async move {
+                    match body.lock().await.next_chunk(limit).await? {
+                        Some(chunk) => Ok(Some(lua.create_string(chunk)?)),
+                        None => Ok(None),
+                    }
+                }
+            })
         });
     }
 }
@@ -203,6 +436,19 @@ impl UserData for AstraMultipartField {
     }
 }
 
+/// Reduces a file name sent by the client to its last component, so that it cannot point
+/// outside of the directory it is saved to.
+fn upload_file_name(file_name: &str) -> mlua::Result<&str> {
+    match file_name.rsplit(['/', '\\']).next() {
+        Some(name) if !name.is_empty() && name != "." && name != ".." && !name.contains('\0') => {
+            Ok(name)
+        }
+        _ => Err(mlua::Error::runtime(format!(
+            "The file name {file_name:?} cannot be saved, a path to save it to is needed"
+        ))),
+    }
+}
+
 #[derive(Debug)]
 pub struct AstraMultipart {
     fields: Vec<AstraMultipartField>,
@@ -271,30 +517,43 @@ impl UserData for AstraMultipart {
             file_name
         });
 
-        methods.add_async_method_mut(
+        methods.add_async_method(
             "save_file",
-            |_, this, file_path: Option<String>| async move {
-                let mut file_path = if let Some(file_path) = file_path {
-                    Some(tokio::fs::File::create(file_path).await?)
-                } else {
-                    None
+            |_, this, (file_path, field_name): (Option<String>, Option<String>)| async move {
+                // the named field, otherwise the first one that is a file
+                let field = this.fields.iter().find(|field| match &field_name {
+                    Some(name) => &field.name == name,
+                    None => field.file_name.is_some(),
+                });
+                let Some(field) = field else {
+                    return Err(mlua::Error::runtime(match field_name {
+                        Some(name) => format!("The multipart has no field named {name}"),
+                        None => "The multipart has no files".to_string(),
+                    }));
                 };
 
-                for field in &this.fields {
-                    if file_path.is_none()
-                        && let Some(filename) = &field.file_name
-                    {
-                        file_path = Some(tokio::fs::File::create(filename).await?);
-                    }
-                    if let Some(ref mut file) = file_path
-                        && let bytes = &field.data
-                        && let Err(err) = file.write(bytes).await
-                    {
-                        return Err(err.into_lua_err());
+                let is_directory = match &file_path {
+                    Some(path) => tokio::fs::metadata(path).await.is_ok_and(|m| m.is_dir()),
+                    None => true,
+                };
+                // a directory, or no path at all, takes the name the client sent along
+                let file_path = match file_path {
+                    Some(path) if !is_directory => path,
+                    directory => {
+                        let Some(file_name) = &field.file_name else {
+                            return Err(mlua::Error::runtime(
+                                "The field has no file name, a path to save it to is needed",
+                            ));
+                        };
+                        std::path::PathBuf::from(directory.unwrap_or_default())
+                            .join(upload_file_name(file_name)?)
+                            .to_string_lossy()
+                            .to_string()
                     }
-                }
+                };
+                tokio::fs::write(&file_path, &field.data).await?;
 
-                Ok(())
+                Ok(file_path)
             },
         );
     }
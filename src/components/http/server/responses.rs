@@ -1,13 +1,17 @@
-use crate::components::http::server::cookie::AstraHTTPCookie;
+use crate::components::http::server::{
+    cookie::AstraHTTPCookie, static_files::SendFile, stream::ResponseStream,
+};
 use axum::{
     http::{HeaderMap, HeaderName, HeaderValue, StatusCode},
     response::Redirect,
 };
+use mlua::LuaSerdeExt;
 
-// ! Support more cookie types like signed and private
 #[derive(Debug, Clone)]
 pub enum CookieOperation<'a> {
     Add(AstraHTTPCookie<'a>),
+    AddSigned(AstraHTTPCookie<'a>),
+    AddPrivate(AstraHTTPCookie<'a>),
     Remove { key: String },
 }
 
@@ -17,6 +21,8 @@ pub struct ResponseLua<'a> {
     pub headers: HeaderMap,
     pub cookie_operations: Vec<CookieOperation<'a>>,
     pub redirect: Option<Redirect>,
+    pub stream: Option<ResponseStream>,
+    pub file: Option<SendFile>,
 }
 impl Default for ResponseLua<'_> {
     fn default() -> Self {
@@ -25,6 +31,8 @@ impl Default for ResponseLua<'_> {
             headers: HeaderMap::new(),
             cookie_operations: Vec::new(),
             redirect: None,
+            stream: None,
+            file: None,
         }
     }
 }
@@ -42,6 +50,10 @@ impl mlua::UserData for ResponseLua<'_> {
             }
         });
 
+        methods.add_method("get_status_code", |_, this, ()| {
+            Ok(this.status_code.as_u16())
+        });
+
         methods.add_method_mut("redirect_to", |_, this, redirect_path: String| {
             this.redirect = Some(Redirect::to(&redirect_path));
             Ok(())
@@ -55,6 +67,46 @@ impl mlua::UserData for ResponseLua<'_> {
             Ok(())
         });
 
+        methods.add_method_mut("stream", |_, this, callback: mlua::Function| {
+            this.stream = Some(ResponseStream::Body(callback));
+            Ok(())
+        });
+        methods.add_method_mut(
+            "sse",
+            |lua, this, (callback, options): (mlua::Function, Option<mlua::Table>)| {
+                let keep_alive = match options {
+                    Some(options) => match options.get::<mlua::Value>("keep_alive")? {
+                        mlua::Value::Boolean(false) => Some(0),
+                        mlua::Value::Nil | mlua::Value::Boolean(true) => None,
+                        interval => Some(lua.from_value::<u64>(interval)?),
+                    },
+                    None => None,
+                };
+
+                this.stream = Some(ResponseStream::Sse {
+                    function: callback,
+                    keep_alive,
+                });
+                Ok(())
+            },
+        );
+
+        methods.add_method_mut(
+            "send_file",
+            |lua, this, (path, options): (String, Option<mlua::Table>)| {
+                let file = match options {
+                    Some(options) => lua.from_value::<SendFile>(mlua::Value::Table(options))?,
+                    None => SendFile::default(),
+                };
+
+                this.file = Some(SendFile {
+                    path: path.into(),
+                    ..file
+                });
+                Ok(())
+            },
+        );
+
         methods.add_method_mut(
             "set_header",
             |_, this, (header_key, header_value): (String, String)| match HeaderName::from_lowercase(
@@ -105,6 +157,20 @@ impl mlua::UserData for ResponseLua<'_> {
             Ok(())
         });
 
+        methods.add_method_mut("set_signed_cookie", |_, this, cookie: AstraHTTPCookie| {
+            this.cookie_operations
+                .push(CookieOperation::AddSigned(cookie));
+
+            Ok(())
+        });
+
+        methods.add_method_mut("set_private_cookie", |_, this, cookie: AstraHTTPCookie| {
+            this.cookie_operations
+                .push(CookieOperation::AddPrivate(cookie));
+
+            Ok(())
+        });
+
         methods.add_method_mut("remove_cookie", |_, this, key: String| {
             this.cookie_operations.push(CookieOperation::Remove { key });
 
@@ -1,23 +1,24 @@
-use crate::{
-    LUA,
-    components::http::server::{
-        configs::RouteConfiguration,
-        requests::{self, RequestLua},
-        responses::{self, CookieOperation},
-        routes,
-        websocket::AstraWebSocket,
-    },
+use crate::components::http::server::{
+    configs::{RouteConfiguration, ServerConfiguration},
+    csrf, errors, lifecycle, middleware, openapi,
+    params::{self, ParamType, ParamTypes},
+    rate_limit::{self, RateLimiter},
+    requests,
+    responses::{self, CookieOperation},
+    routes, session,
+    static_files::StaticFiles,
+    websocket::AstraWebSocket,
 };
 use axum::{
     Router,
     body::Body,
-    extract::{DefaultBodyLimit, WebSocketUpgrade},
+    extract::{DefaultBodyLimit, FromRequestParts, WebSocketUpgrade},
     http::Request,
     response::IntoResponse,
-    routing::{any, delete, get, options, patch, post, put, trace},
+    routing::{MethodFilter, any, connect, delete, get, head, options, patch, post, put, trace},
 };
-use axum_extra::extract::{CookieJar, cookie::Cookie};
-use mlua::LuaSerdeExt;
+use axum_extra::extract::{CookieJar, PrivateCookieJar, SignedCookieJar, cookie::Cookie};
+use mlua::{LuaSerdeExt, ObjectLike};
 
 #[derive(Debug, Clone, Copy, mlua::FromLua, serde::Serialize, serde::Deserialize, PartialEq)]
 #[serde(rename_all = "snake_case")]
@@ -29,10 +30,15 @@ pub enum Method {
     Options,
     Patch,
     Trace,
+    Head,
+    Connect,
+    Any,
+    Route,
     StaticDir,
     StaticFile,
     WebSocket,
     Fallback,
+    Group,
 }
 #[derive(Debug, Clone, mlua::FromLua)]
 pub struct Route {
@@ -42,40 +48,437 @@ pub struct Route {
     pub static_dir: Option<String>,
     pub static_file: Option<String>,
     pub config: RouteConfiguration,
+    pub group: Option<mlua::Table>,
+    /// The verbs a `route` entry answers to
+    pub methods: Option<Vec<String>>,
+    /// Types declared for the path parameters, including those of the group prefixes
+    pub param_types: ParamTypes,
 }
 
+pub type RouteResponse = (
+    CookieJar,
+    Option<SignedCookieJar>,
+    Option<PrivateCookieJar>,
+    axum::response::Response,
+);
+
 pub async fn route(
     lua: &mlua::Lua,
     details: Route,
+    server: ServerConfiguration,
     request: Request<Body>,
-) -> Result<(CookieJar, axum::response::Response), axum::http::StatusCode> {
-    let request = requests::RequestLua::new(request).await;
-    // find a way to add keys here
+) -> Result<RouteResponse, axum::response::Response> {
+    let timeout = match details.config.timeout {
+        Some(timeout) => std::time::Duration::try_from_secs_f64(timeout).ok(),
+        None => server.timeout,
+    };
+
+    let mut request = requests::RequestLua::new(
+        request,
+        details.config.body_limit,
+        server.cookie_keys.clone(),
+    )
+    .await;
+    request.deadline = timeout.map(|timeout| tokio::time::Instant::now() + timeout);
+    request.param_types = details.param_types.clone();
+    validate_params(lua, &request, &details.config).await?;
+    validate_schemas(lua, &request, &details.config).await?;
     let cookie_jar = request.cookie_jar.clone();
+    let body = request.body.clone();
+    let path = details.path.clone();
+
+    let request = match lua.create_userdata(request) {
+        Ok(request) => request,
+        Err(e) => {
+            tracing::error!("Error executing the route: {e}");
+            return Err(axum::http::StatusCode::INTERNAL_SERVER_ERROR.into_response());
+        }
+    };
 
     async fn route_inner(
         lua: &mlua::Lua,
         details: Route,
+        server: ServerConfiguration,
         cookie_jar: CookieJar,
-        request: RequestLua,
-    ) -> mlua::Result<(CookieJar, axum::response::Response)> {
-        let request = lua.create_userdata(request)?;
+        request: mlua::AnyUserData,
+    ) -> mlua::Result<RouteResponse> {
         let response = lua.create_userdata(responses::ResponseLua::default())?;
-        let mut cookie_jar = cookie_jar.clone();
 
         // if a response userdata can be created
         let result = details
             .function
-            .call_async::<mlua::Value>((request, response.clone()))
+            .call_async::<mlua::Value>((&request, response.clone()))
             .await?;
 
         let response_details = response.borrow::<responses::ResponseLua>()?;
 
-        if let Some(redirect_to) = &response_details.redirect {
-            return Ok((cookie_jar, redirect_to.clone().into_response()));
+        build_response(
+            lua,
+            result,
+            &response_details,
+            &request,
+            &server,
+            cookie_jar,
+        )
+        .await
+    }
+
+    let handler = route_inner(
+        lua,
+        details,
+        server.clone(),
+        cookie_jar.clone(),
+        request.clone(),
+    );
+    let result = match timeout {
+        Some(timeout) => match tokio::time::timeout(timeout, handler).await {
+            Ok(result) => result,
+            Err(_) => return Err(axum::http::StatusCode::GATEWAY_TIMEOUT.into_response()),
+        },
+        None => handler.await,
+    };
+
+    match result {
+        Ok(response) => Ok(response),
+        Err(e) => {
+            if let Some(status) = body.lock().await.rejection() {
+                return Err(status.into_response());
+            }
+
+            tracing::error!("Error executing the route: {e}");
+
+            Err(errors::error_response(lua, &server, e, &request, Some(&path)).await)
+        }
+    }
+}
+
+/// Answers the upgrade request of a WebSocket route. The `upgrade` hook of the route gets to
+/// turn the client away first, and the handler receives the request along with the socket.
+async fn websocket(
+    lua: &mlua::Lua,
+    details: Route,
+    server: ServerConfiguration,
+    request: Request<Body>,
+) -> axum::response::Response {
+    let (mut parts, body) = request.into_parts();
+    let mut upgrade = match WebSocketUpgrade::from_request_parts(&mut parts, &()).await {
+        Ok(upgrade) => upgrade,
+        Err(rejection) => return rejection.into_response(),
+    };
+
+    let mut request = requests::RequestLua::new(
+        Request::from_parts(parts, body),
+        details.config.body_limit,
+        server.cookie_keys.clone(),
+    )
+    .await;
+    request.param_types = details.param_types.clone();
+    if let Err(response) = validate_params(lua, &request, &details.config).await {
+        return response;
+    }
+    if let Err(response) = validate_schemas(lua, &request, &details.config).await {
+        return response;
+    }
+
+    let request = match lua.create_userdata(request) {
+        Ok(request) => request,
+        Err(e) => {
+            tracing::error!("Error executing the route: {e}");
+            return axum::http::StatusCode::INTERNAL_SERVER_ERROR.into_response();
+        }
+    };
+
+    if let Some(hook) = &details.config.upgrade {
+        match reject_upgrade(lua, hook, &request, &server).await {
+            Ok(None) => {}
+            Ok(Some(response)) => return response,
+            Err(e) => {
+                tracing::error!("Error executing the route: {e}");
+                return errors::error_response(lua, &server, e, &request, Some(&details.path))
+                    .await;
+            }
+        }
+    }
+
+    let config = &details.config;
+    if let Some(protocols) = config.protocols.clone() {
+        upgrade = upgrade.protocols(protocols);
+    }
+    if let Some(max_message_size) = config.max_message_size {
+        upgrade = upgrade.max_message_size(max_message_size);
+    }
+    if let Some(max_frame_size) = config.max_frame_size {
+        upgrade = upgrade.max_frame_size(max_frame_size);
+    }
+    // both were checked when the routes were loaded
+    let ping_interval = config
+        .ping_interval
+        .and_then(|seconds| std::time::Duration::try_from_secs_f64(seconds).ok());
+    let idle_timeout = config
+        .idle_timeout
+        .and_then(|seconds| std::time::Duration::try_from_secs_f64(seconds).ok());
+
+    let lifecycle = server.lifecycle.clone();
+    upgrade
+        .on_failed_upgrade(|err| {
+            tracing::error!("failed to upgrade connection: {err}");
+        })
+        .on_upgrade(move |socket| async move {
+            // leaves its rooms once the handler is done with it
+            let (lua_socket, _presence) = AstraWebSocket::connect(socket, idle_timeout);
+            let keep_alive = lua_socket.keep_alive(ping_interval);
+            let going_away = lua_socket.going_away();
+            // the handler is stopped first, as it may be sending something itself
+            let closing = tokio::select! {
+                result = details.function.call_async::<()>((lua_socket, request)) => {
+                    if let Err(e) = result {
+                        tracing::error!("Error executing the route: {e}");
+                    }
+                    false
+                }
+                _ = keep_alive => false,
+                _ = lifecycle.closing() => true,
+            };
+            if closing {
+                going_away.await;
+            }
+        })
+}
+
+/// Runs the `upgrade` hook of a WebSocket route, which rejects the client by returning a value
+/// like a `before` middleware. Rejections keep the status set on the response, 403 if it is not
+/// an error or redirect.
+async fn reject_upgrade(
+    lua: &mlua::Lua,
+    hook: &mlua::Function,
+    request: &mlua::AnyUserData,
+    server: &ServerConfiguration,
+) -> mlua::Result<Option<axum::response::Response>> {
+    let response = lua.create_userdata(responses::ResponseLua::default())?;
+    let result = hook
+        .call_async::<mlua::Value>((request, response.clone()))
+        .await?;
+
+    let mut response_details = response.borrow_mut::<responses::ResponseLua>()?;
+    if result.is_nil() && response_details.redirect.is_none() {
+        return Ok(None);
+    }
+    if response_details.status_code.as_u16() < 300 {
+        response_details.status_code = axum::http::StatusCode::FORBIDDEN;
+    }
+
+    let (cookie_jar, signed_cookie_jar, private_cookie_jar, response) = build_response(
+        lua,
+        result,
+        &response_details,
+        request,
+        server,
+        CookieJar::new(),
+    )
+    .await?;
+
+    Ok(Some(
+        (cookie_jar, signed_cookie_jar, private_cookie_jar, response).into_response(),
+    ))
+}
+
+/// Rejects requests whose path parameters do not match their declared types with 404, or
+/// the `params` schema of the route with 400.
+async fn validate_params(
+    lua: &mlua::Lua,
+    request: &requests::RequestLua,
+    config: &RouteConfiguration,
+) -> Result<(), axum::response::Response> {
+    if request.param_types.is_empty() && config.params.is_none() {
+        return Ok(());
+    }
+
+    let params = match request.typed_params(lua).await {
+        Ok(Some(params)) => params,
+        Ok(None) => return Err(axum::http::StatusCode::NOT_FOUND.into_response()),
+        Err(e) => {
+            tracing::error!("Error reading the path parameters: {e}");
+            return Err(axum::http::StatusCode::INTERNAL_SERVER_ERROR.into_response());
         }
+    };
+
+    let Some(schema) = &config.params else {
+        return Ok(());
+    };
+    match schema.call_method::<(bool, Option<String>)>("validate", params) {
+        Ok((true, _)) => Ok(()),
+        Ok((false, error)) => Err((
+            axum::http::StatusCode::BAD_REQUEST,
+            error.unwrap_or_else(|| "Invalid path parameters".to_string()),
+        )
+            .into_response()),
+        Err(e) => {
+            tracing::error!("Error validating the path parameters: {e}");
+            Err(axum::http::StatusCode::INTERNAL_SERVER_ERROR.into_response())
+        }
+    }
+}
+
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
+struct SchemaError {
+    #[serde(default)]
+    location: String,
+    path: String,
+    message: String,
+}
+
+/// Checks the query string and JSON body against the `query_schema` and `body_schema` of
+/// the route, answering 422 with every failing path before the handler runs.
+async fn validate_schemas(
+    lua: &mlua::Lua,
+    request: &requests::RequestLua,
+    config: &RouteConfiguration,
+) -> Result<(), axum::response::Response> {
+    fn respond(
+        status: axum::http::StatusCode,
+        errors: Vec<SchemaError>,
+    ) -> axum::response::Response {
+        (status, axum::Json(serde_json::json!({ "errors": errors }))).into_response()
+    }
+    fn failure(location: &str, message: impl ToString) -> axum::response::Response {
+        respond(
+            axum::http::StatusCode::BAD_REQUEST,
+            vec![SchemaError {
+                location: location.to_string(),
+                path: String::new(),
+                message: message.to_string(),
+            }],
+        )
+    }
+    fn collect(
+        lua: &mlua::Lua,
+        schema: &mlua::Table,
+        location: &str,
+        value: &serde_json::Value,
+    ) -> mlua::Result<Vec<SchemaError>> {
+        // `null` counts as a missing value, so that optional fields accept it
+        let value = lua.to_value_with(
+            value,
+            mlua::SerializeOptions::new()
+                .serialize_none_to_null(false)
+                .serialize_unit_to_null(false),
+        )?;
+        let errors = schema.call_method::<mlua::Value>("errors", value)?;
+
+        Ok(lua
+            .from_value::<Vec<SchemaError>>(errors)?
+            .into_iter()
+            .map(|error| SchemaError {
+                location: location.to_string(),
+                ..error
+            })
+            .collect())
+    }
+
+    let mut errors = Vec::new();
+
+    if let Some(schema) = &config.query_schema {
+        let queries =
+            match axum::extract::Query::<serde_json::Value>::try_from_uri(&request.parts.uri) {
+                Ok(queries) => queries.0,
+                Err(e) => return Err(failure("query", e.body_text())),
+            };
+
+        match collect(lua, schema, "query", &queries) {
+            Ok(query_errors) => errors.extend(query_errors),
+            Err(e) => {
+                tracing::error!("Error validating the query string: {e}");
+                return Err(axum::http::StatusCode::INTERNAL_SERVER_ERROR.into_response());
+            }
+        }
+    }
+
+    if let Some(schema) = &config.body_schema {
+        let bytes = match request.bytes().await {
+            Ok(bytes) => bytes,
+            Err(e) => {
+                if let Some(status) = request.body.lock().await.rejection() {
+                    return Err(status.into_response());
+                }
+                return Err(failure("body", e));
+            }
+        };
+        let body = match serde_json::from_slice::<serde_json::Value>(&bytes) {
+            Ok(body) => body,
+            Err(e) => return Err(failure("body", format!("Invalid JSON: {e}"))),
+        };
 
-        let mut resulting_response = match result {
+        match collect(lua, schema, "body", &body) {
+            Ok(body_errors) => errors.extend(body_errors),
+            Err(e) => {
+                tracing::error!("Error validating the request body: {e}");
+                return Err(axum::http::StatusCode::INTERNAL_SERVER_ERROR.into_response());
+            }
+        }
+    }
+
+    if errors.is_empty() {
+        Ok(())
+    } else {
+        Err(respond(
+            axum::http::StatusCode::UNPROCESSABLE_ENTITY,
+            errors,
+        ))
+    }
+}
+
+/// Only lets through the methods a route was registered for, used for custom verbs.
+async fn allow_methods(
+    axum::extract::State(methods): axum::extract::State<std::sync::Arc<Vec<axum::http::Method>>>,
+    request: Request<Body>,
+    next: axum::middleware::Next,
+) -> axum::response::Response {
+    if methods.contains(request.method()) {
+        return next.run(request).await;
+    }
+
+    let allow = methods
+        .iter()
+        .map(|method| method.as_str())
+        .collect::<Vec<_>>()
+        .join(", ");
+    (
+        axum::http::StatusCode::METHOD_NOT_ALLOWED,
+        [(axum::http::header::ALLOW, allow)],
+    )
+        .into_response()
+}
+
+/// Turns the value returned from a handler along with the details set on its response
+/// object into the outgoing response.
+pub async fn build_response(
+    lua: &mlua::Lua,
+    result: mlua::Value,
+    response_details: &responses::ResponseLua<'static>,
+    request: &mlua::AnyUserData,
+    server: &ServerConfiguration,
+    cookie_jar: CookieJar,
+) -> mlua::Result<RouteResponse> {
+    if let Some(redirect_to) = &response_details.redirect {
+        return Ok((cookie_jar, None, None, redirect_to.clone().into_response()));
+    }
+
+    let mut resulting_response = if let Some(file) = response_details.file.clone() {
+        let parts = request.borrow::<requests::RequestLua>()?.parts.clone();
+        let mut response = file.into_response(&parts).await?;
+        // partial and conditional responses keep their own status
+        if response.status() == axum::http::StatusCode::OK {
+            *response.status_mut() = response_details.status_code;
+        }
+
+        response
+    } else if let Some(stream) = response_details.stream.clone() {
+        let mut response = stream.into_response(lua, &server.lifecycle)?;
+        *response.status_mut() = response_details.status_code;
+
+        response
+    } else {
+        let mut response = match result {
             mlua::Value::String(plain) => plain.to_string_lossy().into_response(),
             mlua::Value::Table(ref table) => {
                 if let Ok(true) = crate::components::is_table_byte_array(table) {
@@ -87,86 +490,281 @@ pub async fn route(
             }
             _ => axum::http::StatusCode::OK.into_response(),
         };
-        *resulting_response.status_mut() = response_details.status_code;
+        *response.status_mut() = response_details.status_code;
 
-        for (key, value) in response_details.headers.iter() {
-            resulting_response.headers_mut().insert(key, value.clone());
-        }
+        response
+    };
 
-        for cookie_operation in response_details.cookie_operations.clone().into_iter() {
-            match cookie_operation {
-                CookieOperation::Add(cookie) => {
-                    cookie_jar = cookie_jar.clone().remove(cookie.0.clone());
-                    cookie_jar = cookie_jar.clone().add(cookie.0);
-                }
-                CookieOperation::Remove { key } => {
-                    cookie_jar = cookie_jar.clone().remove(Cookie::from(key));
-                }
-            };
+    // replaces the headers the response came with, keeping every value set for them
+    for key in response_details.headers.keys() {
+        let headers = resulting_response.headers_mut();
+        headers.remove(key);
+        for value in response_details.headers.get_all(key) {
+            headers.append(key, value.clone());
         }
+    }
+
+    let (cookie_jar, signed_cookie_jar, private_cookie_jar) =
+        apply_cookie_operations(&response_details.cookie_operations, cookie_jar, server)?;
+
+    Ok((
+        cookie_jar,
+        signed_cookie_jar,
+        private_cookie_jar,
+        resulting_response,
+    ))
+}
+
+pub fn apply_cookie_operations(
+    cookie_operations: &[CookieOperation<'static>],
+    mut cookie_jar: CookieJar,
+    server: &ServerConfiguration,
+) -> mlua::Result<(CookieJar, Option<SignedCookieJar>, Option<PrivateCookieJar>)> {
+    let mut signed_cookie_jar = None;
+    let mut private_cookie_jar = None;
+
+    for cookie_operation in cookie_operations.iter().cloned() {
+        match cookie_operation {
+            CookieOperation::Add(cookie) => {
+                cookie_jar = cookie_jar.clone().remove(cookie.0.clone());
+                cookie_jar = cookie_jar.clone().add(cookie.0);
+            }
+            CookieOperation::AddSigned(cookie) => {
+                let jar = match signed_cookie_jar.take() {
+                    Some(jar) => jar,
+                    None => SignedCookieJar::new(server.cookie_keys.primary()?),
+                };
+                signed_cookie_jar = Some(jar.add(cookie.0));
+            }
+            CookieOperation::AddPrivate(cookie) => {
+                let jar = match private_cookie_jar.take() {
+                    Some(jar) => jar,
+                    None => PrivateCookieJar::new(server.cookie_keys.primary()?),
+                };
+                private_cookie_jar = Some(jar.add(cookie.0));
+            }
+            CookieOperation::Remove { key } => {
+                cookie_jar = cookie_jar.clone().remove(Cookie::from(key));
+            }
+        };
+    }
+
+    Ok((cookie_jar, signed_cookie_jar, private_cookie_jar))
+}
+
+pub fn load_routes(
+    lua: &mlua::Lua,
+    server: mlua::Table,
+    configuration: ServerConfiguration,
+) -> mlua::Result<Router> {
+    let mut router = build_router(
+        lua,
+        server,
+        "",
+        &configuration,
+        &RouteConfiguration {
+            body_limit: configuration.body_limit,
+            ..Default::default()
+        },
+        &Default::default(),
+    )?;
 
-        Ok((cookie_jar, resulting_response))
+    if configuration.csrf.is_some() {
+        router = router.layer(axum::middleware::from_fn_with_state(
+            configuration.clone(),
+            csrf::layer,
+        ));
     }
 
-    match route_inner(lua, details, cookie_jar.clone(), request).await {
-        Ok(response) => Ok(response),
-        Err(e) => {
-            tracing::error!("Error executing the route: {e}");
+    if let Some(sessions) = configuration.sessions.clone() {
+        router = router.layer(axum::middleware::from_fn_with_state(
+            sessions,
+            session::layer,
+        ));
+    }
 
-            Err(axum::http::StatusCode::INTERNAL_SERVER_ERROR)
+    if let Some(rate_limit) = configuration.rate_limit.clone() {
+        router = router.layer(axum::middleware::from_fn_with_state(
+            rate_limit,
+            rate_limit::layer,
+        ));
+    }
+
+    // health checks are answered before anything else gets a say
+    router = router.layer(axum::middleware::from_fn_with_state(
+        configuration.lifecycle.clone(),
+        lifecycle::layer,
+    ));
+
+    Ok(router)
+}
+
+/// The methods a route answers, `None` for any of them.
+fn route_methods(route: &Route) -> Option<Vec<axum::http::Method>> {
+    use axum::http::Method as HttpMethod;
+
+    Some(match route.method {
+        Method::Get => vec![HttpMethod::GET, HttpMethod::HEAD],
+        Method::Post => vec![HttpMethod::POST],
+        Method::Put => vec![HttpMethod::PUT],
+        Method::Delete => vec![HttpMethod::DELETE],
+        Method::Options => vec![HttpMethod::OPTIONS],
+        Method::Patch => vec![HttpMethod::PATCH],
+        Method::Trace => vec![HttpMethod::TRACE],
+        Method::Head => vec![HttpMethod::HEAD],
+        Method::Connect => vec![HttpMethod::CONNECT],
+        Method::Route => route
+            .methods
+            .iter()
+            .flatten()
+            .filter_map(|method| HttpMethod::from_bytes(method.to_uppercase().as_bytes()).ok())
+            .collect(),
+        _ => return None,
+    })
+}
+
+/// Sets the headers configured for a static file or directory, replacing those of the files.
+fn static_headers(
+    config: &RouteConfiguration,
+) -> impl Fn(axum::response::Response) -> axum::response::Response + Clone + use<> {
+    let headers = config
+        .headers
+        .iter()
+        .flatten()
+        .filter_map(|(k, v)| {
+            Some((
+                k.parse::<axum::http::HeaderName>().ok()?,
+                v.parse::<axum::http::HeaderValue>().ok()?,
+            ))
+        })
+        .collect::<Vec<_>>();
+
+    move |mut response| {
+        for (name, value) in &headers {
+            response.headers_mut().insert(name.clone(), value.clone());
         }
+        response
     }
 }
 
-pub fn load_routes(server: mlua::Table) -> Router {
+/// Builds the router for the routes of a server or group, recursing into nested groups.
+/// Routes take the configuration of their groups unless they override it.
+fn build_router(
+    lua: &mlua::Lua,
+    server: mlua::Table,
+    prefix: &str,
+    configuration: &ServerConfiguration,
+    inherited: &RouteConfiguration,
+    inherited_params: &std::collections::HashMap<String, ParamType>,
+) -> mlua::Result<Router> {
     let mut router = Router::new();
-    #[allow(clippy::expect_used)]
-    let lua = LUA.get().expect("Could not get access to the global VM");
 
     let mut routes = Vec::new();
     let mut parse_route = |entry: &mlua::Table| -> mlua::Result<()> {
+        let (path, types) = params::parse_path(&lua.from_value::<String>(entry.get("path")?)?)?;
+        let mut param_types = inherited_params.clone();
+        param_types.extend(types);
+
         routes.push(routes::Route {
-            path: lua.from_value(entry.get("path")?)?,
+            path,
             method: lua.from_value(entry.get("method")?)?,
             function: entry.get::<mlua::Function>("func")?,
             static_dir: lua.from_value(entry.get("static_dir")?)?,
             static_file: lua.from_value(entry.get("static_file")?)?,
-            config: lua.from_value(entry.get("config")?)?,
+            config: RouteConfiguration::from_lua(lua, entry.get("config")?)?.inherit(inherited),
+            group: entry.get("group")?,
+            methods: lua.from_value(entry.get("methods")?)?,
+            param_types: std::sync::Arc::new(param_types),
         });
 
         Ok(())
     };
 
     if let Ok(server) = server.get::<mlua::Table>("routes") {
-        #[allow(clippy::expect_used)]
-        server
-            .for_each(|_key: mlua::Value, entry: mlua::Value| {
-                if let Some(entry) = entry.as_table() {
-                    let _ = parse_route(entry);
-                }
+        server.for_each(|_key: mlua::Value, entry: mlua::Value| {
+            if let Some(entry) = entry.as_table() {
+                parse_route(entry)?;
+            }
 
-                Ok(())
-            })
-            .expect("Could not parse the routes");
+            Ok(())
+        })?;
 
         for route_values in routes.clone() {
             let path = route_values.path.clone();
             let path = path.as_str();
 
+            let configuration = configuration.clone();
             let config = route_values.config.clone();
             let body_limit = config.body_limit;
             let compression = config.compression;
+            // a route level configuration replaces the server wide one
+            let cors = match &config.cors {
+                Some(cors) => Some(cors.layer()?),
+                None => configuration.cors.clone(),
+            };
+            let headers = config
+                .headers
+                .iter()
+                .flatten()
+                .filter_map(|(k, v)| {
+                    Some((
+                        k.parse::<axum::http::HeaderName>().ok()?,
+                        v.parse::<axum::http::HeaderValue>().ok()?,
+                    ))
+                })
+                .collect::<Vec<_>>();
+            let rate_limit = match &config.rate_limit {
+                Some(rate_limit) => Some(axum::middleware::from_fn_with_state(
+                    RateLimiter::new(rate_limit.clone(), configuration.cookie_keys.clone())?,
+                    rate_limit::layer,
+                )),
+                None => None,
+            };
+
+            // the check runs before the routes, and finds their configuration on its own
+            if let Some(csrf) = &configuration.csrf {
+                match route_values.method {
+                    Method::Fallback => csrf.add_fallback(
+                        prefix.trim_end_matches('/').to_string(),
+                        &route_values.config,
+                    ),
+                    Method::Group | Method::StaticDir | Method::StaticFile => {}
+                    _ => csrf.add_route(
+                        openapi::join(prefix, path),
+                        route_methods(&route_values),
+                        &route_values.config,
+                    ),
+                }
+            }
 
             macro_rules! match_routes {
-                ($route_function:expr) => {{
+                ($route_function:expr) => {
+                    match_routes!($route_function, route)
+                };
+                ($route_function:expr, $handler:ident) => {{
+                    let lua = lua.clone();
                     let mut route_function =
                         $route_function(move |request: Request<Body>| async move {
-                            route(lua, route_values, request).await
+                            $handler(&lua, route_values, configuration, request).await
                         });
 
+                    if let Some(rate_limit) = rate_limit {
+                        route_function = route_function.layer(rate_limit)
+                    }
+                    if let Some(cors) = cors {
+                        route_function = route_function.layer(cors)
+                    }
                     if let Some(body_limit) = body_limit {
                         route_function = route_function.layer(DefaultBodyLimit::max(body_limit))
                     }
+                    for (header_name, header_value) in headers {
+                        route_function = route_function.layer(
+                            tower_http::set_header::SetResponseHeaderLayer::if_not_present(
+                                header_name,
+                                header_value,
+                            ),
+                        )
+                    }
                     if let Some(compression) = compression
                         && compression
                     {
@@ -189,78 +787,154 @@ pub fn load_routes(server: mlua::Table) -> Router {
                 Method::Options => match_routes!(options),
                 Method::Patch => match_routes!(patch),
                 Method::Trace => match_routes!(trace),
+                Method::Head => match_routes!(head),
+                Method::Connect => match_routes!(connect),
+                Method::Any => match_routes!(any),
+                Method::Route => {
+                    let methods = route_values
+                        .methods
+                        .iter()
+                        .flatten()
+                        .map(|method| {
+                            axum::http::Method::from_bytes(method.to_uppercase().as_bytes())
+                                .map_err(|e| {
+                                    mlua::Error::runtime(format!(
+                                        "Invalid HTTP method {method}: {e}"
+                                    ))
+                                })
+                        })
+                        .collect::<mlua::Result<Vec<_>>>()?;
+
+                    // custom verbs have no filter of their own, those are checked by hand instead
+                    let filter = methods.iter().try_fold(None, |filter, method| {
+                        let method = MethodFilter::try_from(method.clone()).ok()?;
+                        Some(Some(match filter {
+                            Some(filter) => method.or(filter),
+                            None => method,
+                        }))
+                    });
+
+                    match filter {
+                        Some(Some(filter)) => {
+                            match_routes!(|handler| axum::routing::on(filter, handler))
+                        }
+                        Some(None) => {
+                            return Err(mlua::Error::runtime(format!(
+                                "The route {path} needs at least one HTTP method"
+                            )));
+                        }
+                        None => match_routes!(|handler| any(handler).layer(
+                            axum::middleware::from_fn_with_state(
+                                std::sync::Arc::new(methods),
+                                allow_methods,
+                            )
+                        )),
+                    }
+                }
                 Method::StaticDir => {
                     if let Some(serve_path) = route_values.static_dir {
-                        let service = tower_http::services::ServeDir::new(serve_path);
-                        let mut router_part = if path == "/" {
+                        let service = tower::ServiceBuilder::new()
+                            .option_layer(cors)
+                            .option_layer(rate_limit)
+                            .map_response(static_headers(&route_values.config))
+                            .map_response(IntoResponse::into_response)
+                            .service(
+                                StaticFiles::new(&serve_path, &route_values.config)?.into_service(),
+                            );
+                        if path == "/" {
                             router.fallback_service(service)
                         } else {
                             router.nest_service(path, service)
-                        };
-                        if let Some(headers) = &route_values.config.headers {
-                            for (k, v) in headers {
-                                if let Ok(header_name) = k.parse::<axum::http::HeaderName>()
-                                    && let Ok(header_value) = v.parse::<axum::http::HeaderValue>()
-                                {
-                                    router_part = router_part.layer(
-                                        tower_http::set_header::SetResponseHeaderLayer::overriding(
-                                            header_name,
-                                            header_value,
-                                        ),
-                                    );
-                                }
-                            }
                         }
-                        router_part
                     } else {
                         router
                     }
                 }
                 Method::StaticFile => {
                     if let Some(serve_path) = route_values.static_file {
-                        let service = tower_http::services::ServeFile::new(serve_path);
-                        let mut router_part = if path == "/" {
+                        let service = tower::ServiceBuilder::new()
+                            .option_layer(cors)
+                            .option_layer(rate_limit)
+                            .map_response(static_headers(&route_values.config))
+                            .map_response(IntoResponse::into_response)
+                            .service(tower_http::services::ServeFile::new(serve_path));
+                        if path == "/" {
                             router.fallback_service(service)
                         } else {
                             router.nest_service(path, service)
-                        };
-                        if let Some(headers) = &route_values.config.headers {
-                            for (k, v) in headers {
-                                if let Ok(header_name) = k.parse::<axum::http::HeaderName>()
-                                    && let Ok(header_value) = v.parse::<axum::http::HeaderValue>()
-                                {
-                                    router_part = router_part.layer(
-                                        tower_http::set_header::SetResponseHeaderLayer::overriding(
-                                            header_name,
-                                            header_value,
-                                        ),
-                                    );
-                                }
-                            }
                         }
-                        router_part
                     } else {
                         router
                     }
                 }
-                Method::WebSocket => router.route(
-                    &route_values.path,
-                    any(|ws: WebSocketUpgrade| async {
-                        ws.on_failed_upgrade(|err| {
-                            mlua::Error::runtime(format!("failed to upgrade connection: {err}"));
-                        })
-                        .on_upgrade(|socket| async move {
-                            let lua_socket = AstraWebSocket(socket);
-                            let _ = route_values.function.call_async::<()>(lua_socket).await;
-                        })
-                    }),
-                ),
+                Method::WebSocket => match_routes!(any, websocket),
                 Method::Fallback => {
-                    router.fallback(|request: Request<Body>| route(lua, route_values, request))
+                    let lua = lua.clone();
+                    let mut fallback = any(move |request: Request<Body>| async move {
+                        route(&lua, route_values, configuration, request).await
+                    });
+                    if let Some(rate_limit) = rate_limit {
+                        fallback = fallback.layer(rate_limit);
+                    }
+                    if let Some(cors) = cors {
+                        fallback = fallback.layer(cors);
+                    }
+
+                    router.fallback_service(fallback)
+                }
+                Method::Group => {
+                    let Some(group) = route_values.group else {
+                        continue;
+                    };
+
+                    // the limit is shared by all of the routes in the group
+                    let group_config = RouteConfiguration {
+                        rate_limit: None,
+                        ..route_values.config
+                    };
+                    let group_configuration = ServerConfiguration {
+                        middleware: std::sync::Arc::new(middleware::Middleware::from_table(
+                            &group,
+                        )?),
+                        body_limit: group_config.body_limit,
+                        ..configuration
+                    };
+
+                    let mut group_router = build_router(
+                        lua,
+                        group,
+                        &openapi::join(prefix, path),
+                        &group_configuration,
+                        &group_config,
+                        &route_values.param_types,
+                    )?;
+                    if let Some(rate_limit) = rate_limit {
+                        group_router = group_router.layer(rate_limit);
+                    }
+
+                    match path.trim_end_matches('/') {
+                        "" => router.merge(group_router),
+                        prefix if prefix.starts_with('/') => router.nest(prefix, group_router),
+                        _ => {
+                            return Err(mlua::Error::runtime(format!(
+                                "The group prefix must start with a `/`, got {path}"
+                            )));
+                        }
+                    }
                 }
             }
         }
 
+        if !configuration.middleware.is_empty() {
+            let lua = lua.clone();
+            let configuration = configuration.clone();
+            router = router.layer(axum::middleware::from_fn(
+                move |request: Request<Body>, next: axum::middleware::Next| {
+                    middleware::layer(lua.clone(), configuration.clone(), request, next)
+                },
+            ));
+        }
+
         if let Ok(should_compress) = server.get::<bool>("compression")
             && should_compress
         {
@@ -272,5 +946,5 @@ pub fn load_routes(server: mlua::Table) -> Router {
         }
     }
 
-    router
+    Ok(router)
 }
@@ -0,0 +1,591 @@
+use crate::components::database::{Database, DatabaseType};
+use axum::{
+    body::Body,
+    extract::State,
+    http::{HeaderValue, Request, header::SET_COOKIE},
+    middleware::Next,
+    response::Response,
+};
+use axum_extra::extract::{CookieJar, cookie::Cookie};
+use mlua::{LuaSerdeExt, UserData};
+use std::{
+    collections::HashMap,
+    path::PathBuf,
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicUsize, Ordering},
+    },
+};
+
+/// Expired sessions are cleared out of the store once every this many saves.
+const PRUNE_INTERVAL: usize = 100;
+/// Key the flash messages for the next request are kept under within the session data.
+const FLASH_KEY: &str = "_flash";
+
+type SessionData = serde_json::Map<String, serde_json::Value>;
+
+#[derive(Debug, Clone, Default, serde::Deserialize)]
+pub struct SessionConfiguration {
+    /// Name of the session cookie, defaults to `astra_session`
+    pub cookie: Option<String>,
+    /// Seconds a session lives after it was last used, defaults to a day
+    pub max_age: Option<i64>,
+    /// Only sends the session cookie over HTTPS
+    pub secure: Option<bool>,
+    /// Directory of the file store, defaults to `sessions`
+    pub path: Option<String>,
+    /// Table of the SQLite store, defaults to `sessions`
+    pub table: Option<String>,
+}
+
+#[derive(Debug)]
+enum SessionStore {
+    Memory(Mutex<HashMap<String, (i64, SessionData)>>),
+    File(PathBuf),
+    Sqlite {
+        pool: sqlx::Pool<sqlx::Sqlite>,
+        table: String,
+        created: tokio::sync::OnceCell<()>,
+    },
+}
+impl SessionStore {
+    async fn load(&self, id: &str) -> mlua::Result<Option<SessionData>> {
+        let now = chrono::Utc::now().timestamp();
+
+        match self {
+            Self::Memory(sessions) => {
+                let mut sessions = sessions
+                    .lock()
+                    .map_err(|e| mlua::Error::runtime(format!("{e}")))?;
+                match sessions.get(id) {
+                    Some((expires_at, data)) if *expires_at > now => Ok(Some(data.clone())),
+                    Some(_) => {
+                        sessions.remove(id);
+                        Ok(None)
+                    }
+                    None => Ok(None),
+                }
+            }
+            Self::File(directory) => {
+                let path = directory.join(format!("{id}.json"));
+                let Ok(content) = tokio::fs::read(&path).await else {
+                    return Ok(None);
+                };
+
+                match serde_json::from_slice::<(i64, SessionData)>(&content) {
+                    Ok((expires_at, data)) if expires_at > now => Ok(Some(data)),
+                    _ => {
+                        let _ = tokio::fs::remove_file(&path).await;
+                        Ok(None)
+                    }
+                }
+            }
+            Self::Sqlite { pool, table, .. } => {
+                self.create_table().await?;
+                let row: Option<(String,)> = sqlx::query_as(sqlx::AssertSqlSafe(format!(
+                    "SELECT data FROM {table} WHERE id = ? AND expires_at > ?"
+                )))
+                .bind(id)
+                .bind(now)
+                .fetch_optional(pool)
+                .await
+                .map_err(|e| mlua::Error::runtime(format!("Error loading the session: {e}")))?;
+
+                Ok(row.and_then(|(data,)| serde_json::from_str(&data).ok()))
+            }
+        }
+    }
+
+    async fn save(&self, id: &str, data: &SessionData, expires_at: i64) -> mlua::Result<()> {
+        match self {
+            Self::Memory(sessions) => {
+                sessions
+                    .lock()
+                    .map_err(|e| mlua::Error::runtime(format!("{e}")))?
+                    .insert(id.to_string(), (expires_at, data.clone()));
+            }
+            Self::File(directory) => {
+                tokio::fs::create_dir_all(directory).await?;
+                let content = serde_json::to_vec(&(expires_at, data))
+                    .map_err(|e| mlua::Error::runtime(format!("Error saving the session: {e}")))?;
+                tokio::fs::write(directory.join(format!("{id}.json")), content).await?;
+            }
+            Self::Sqlite { pool, table, .. } => {
+                self.create_table().await?;
+                sqlx::query(sqlx::AssertSqlSafe(format!(
+                    "INSERT INTO {table} (id, data, expires_at) VALUES (?, ?, ?) \
+                    ON CONFLICT(id) DO UPDATE SET data = excluded.data, expires_at = excluded.expires_at"
+                )))
+                .bind(id)
+                .bind(serde_json::Value::Object(data.clone()).to_string())
+                .bind(expires_at)
+                .execute(pool)
+                .await
+                .map_err(|e| mlua::Error::runtime(format!("Error saving the session: {e}")))?;
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn remove(&self, id: &str) -> mlua::Result<()> {
+        match self {
+            Self::Memory(sessions) => {
+                sessions
+                    .lock()
+                    .map_err(|e| mlua::Error::runtime(format!("{e}")))?
+                    .remove(id);
+            }
+            Self::File(directory) => {
+                let _ = tokio::fs::remove_file(directory.join(format!("{id}.json"))).await;
+            }
+            Self::Sqlite { pool, table, .. } => {
+                self.create_table().await?;
+                sqlx::query(sqlx::AssertSqlSafe(format!(
+                    "DELETE FROM {table} WHERE id = ?"
+                )))
+                .bind(id)
+                .execute(pool)
+                .await
+                .map_err(|e| mlua::Error::runtime(format!("Error removing the session: {e}")))?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Clears out the sessions that have expired without being used again.
+    async fn prune(&self) -> mlua::Result<()> {
+        let now = chrono::Utc::now().timestamp();
+
+        match self {
+            Self::Memory(sessions) => {
+                sessions
+                    .lock()
+                    .map_err(|e| mlua::Error::runtime(format!("{e}")))?
+                    .retain(|_, (expires_at, _)| *expires_at > now);
+            }
+            Self::File(directory) => {
+                let Ok(mut entries) = tokio::fs::read_dir(directory).await else {
+                    return Ok(());
+                };
+                while let Ok(Some(entry)) = entries.next_entry().await {
+                    let expired = match tokio::fs::read(entry.path()).await {
+                        Ok(content) => serde_json::from_slice::<(i64, serde_json::Value)>(&content)
+                            .is_ok_and(|(expires_at, _)| expires_at <= now),
+                        Err(_) => false,
+                    };
+                    if expired {
+                        let _ = tokio::fs::remove_file(entry.path()).await;
+                    }
+                }
+            }
+            Self::Sqlite { pool, table, .. } => {
+                sqlx::query(sqlx::AssertSqlSafe(format!(
+                    "DELETE FROM {table} WHERE expires_at <= ?"
+                )))
+                .bind(now)
+                .execute(pool)
+                .await
+                .map_err(|e| mlua::Error::runtime(format!("Error pruning the sessions: {e}")))?;
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn create_table(&self) -> mlua::Result<()> {
+        let Self::Sqlite {
+            pool,
+            table,
+            created,
+        } = self
+        else {
+            return Ok(());
+        };
+
+        created
+            .get_or_try_init(|| async {
+                sqlx::query(sqlx::AssertSqlSafe(format!(
+                    "CREATE TABLE IF NOT EXISTS {table} \
+                    (id TEXT PRIMARY KEY, data TEXT NOT NULL, expires_at INTEGER NOT NULL)"
+                )))
+                .execute(pool)
+                .await
+                .map(|_| ())
+                .map_err(|e| mlua::Error::runtime(format!("Error creating the session table: {e}")))
+            })
+            .await
+            .map(|_| ())
+    }
+}
+
+/// The session store of a server along with the settings of its cookie.
+#[derive(Debug)]
+pub struct Sessions {
+    store: SessionStore,
+    cookie: String,
+    max_age: i64,
+    secure: bool,
+    saves: AtomicUsize,
+}
+impl Sessions {
+    pub fn from_lua(lua: &mlua::Lua, value: mlua::Value) -> mlua::Result<Arc<Self>> {
+        let store = match &value {
+            mlua::Value::Table(table) => table.get::<mlua::Value>("store")?,
+            _ => mlua::Value::Nil,
+        };
+        let configuration: SessionConfiguration = lua.from_value_with(
+            value,
+            mlua::DeserializeOptions::new().deny_unsupported_types(false),
+        )?;
+
+        let store = match store {
+            mlua::Value::Nil => SessionStore::Memory(Mutex::new(HashMap::new())),
+            mlua::Value::String(store) => match store.to_str()?.as_ref() {
+                "memory" => SessionStore::Memory(Mutex::new(HashMap::new())),
+                "file" => SessionStore::File(PathBuf::from(
+                    configuration.path.as_deref().unwrap_or("sessions"),
+                )),
+                store => {
+                    return Err(mlua::Error::runtime(format!(
+                        "Unknown session store {store}, expected memory, file or a database"
+                    )));
+                }
+            },
+            mlua::Value::UserData(database) => match &database.borrow::<Database>()?.db {
+                Some(DatabaseType::Sqlite(pool)) => {
+                    let table = configuration.table.as_deref().unwrap_or("sessions");
+                    if table.is_empty()
+                        || !table.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+                    {
+                        return Err(mlua::Error::runtime(format!(
+                            "Invalid session table name {table}"
+                        )));
+                    }
+
+                    SessionStore::Sqlite {
+                        pool: pool.clone(),
+                        table: table.to_string(),
+                        created: tokio::sync::OnceCell::new(),
+                    }
+                }
+                Some(DatabaseType::Postgres(_)) => {
+                    return Err(mlua::Error::runtime(
+                        "Sessions can only be stored in SQLite databases",
+                    ));
+                }
+                None => return Err(mlua::Error::runtime("The connection is closed")),
+            },
+            _ => {
+                return Err(mlua::Error::runtime(
+                    "The session store must be memory, file or a database",
+                ));
+            }
+        };
+
+        let max_age = configuration.max_age.unwrap_or(60 * 60 * 24);
+        if max_age <= 0 {
+            return Err(mlua::Error::runtime(format!(
+                "The session max_age must be a positive amount of seconds, got {max_age}"
+            )));
+        }
+
+        Ok(Arc::new(Self {
+            store,
+            cookie: configuration
+                .cookie
+                .unwrap_or_else(|| "astra_session".to_string()),
+            max_age,
+            secure: configuration.secure.unwrap_or(false),
+            saves: AtomicUsize::new(0),
+        }))
+    }
+
+    fn cookie(&self, id: String, max_age: i64) -> Cookie<'static> {
+        Cookie::build((self.cookie.clone(), id))
+            .path("/")
+            .http_only(true)
+            .secure(self.secure)
+            .same_site(axum_extra::extract::cookie::SameSite::Lax)
+            .max_age(time::Duration::seconds(max_age))
+            .build()
+    }
+}
+
+/// Session ids are only ever generated by the server, anything else sent by the client is
+/// ignored.
+fn new_id() -> String {
+    format!(
+        "{}{}",
+        uuid::Uuid::new_v4().simple(),
+        uuid::Uuid::new_v4().simple()
+    )
+}
+fn is_valid_id(id: &str) -> bool {
+    id.len() == 64 && id.chars().all(|c| c.is_ascii_hexdigit())
+}
+
+#[derive(Debug, Default)]
+struct SessionState {
+    id: Option<String>,
+    loaded: bool,
+    /// Whether the session was found in the store, which keeps it alive on every request
+    existed: bool,
+    data: SessionData,
+    /// Flash messages set by the previous request
+    flash: SessionData,
+    modified: bool,
+    destroyed: bool,
+    /// Session that has to be removed from the store, after a regeneration or destruction
+    replaced: Option<String>,
+}
+
+/// The session of a request, only loaded from the store once the handler asks for it.
+#[derive(Debug, Clone)]
+pub struct AstraSession {
+    sessions: Arc<Sessions>,
+    state: Arc<Mutex<SessionState>>,
+}
+impl AstraSession {
+    fn state(&self) -> mlua::Result<std::sync::MutexGuard<'_, SessionState>> {
+        self.state
+            .lock()
+            .map_err(|e| mlua::Error::runtime(format!("Could not access the session: {e}")))
+    }
+
+    /// Reads the session from the store on first use.
+    pub async fn load(&self) -> mlua::Result<()> {
+        let id = {
+            let state = self.state()?;
+            if state.loaded {
+                return Ok(());
+            }
+            state.id.clone()
+        };
+
+        let data = match &id {
+            Some(id) => self.sessions.store.load(id).await?,
+            None => None,
+        };
+
+        let mut state = self.state()?;
+        if state.loaded {
+            return Ok(());
+        }
+        state.loaded = true;
+        match data {
+            Some(mut data) => {
+                state.existed = true;
+                if let Some(serde_json::Value::Object(flash)) = data.remove(FLASH_KEY) {
+                    state.flash = flash;
+                    state.modified = true;
+                }
+                state.data = data;
+            }
+            // an unknown id is never taken over, which keeps sessions from being fixated
+            None => state.id = None,
+        }
+
+        Ok(())
+    }
+
+    /// Saves the session after the request, returning the cookie the client has to receive.
+    async fn commit(&self) -> mlua::Result<Option<Cookie<'static>>> {
+        let (id, data, replaced, destroyed) = {
+            let mut state = self.state()?;
+            if !state.loaded || (!state.modified && !state.existed && !state.destroyed) {
+                return Ok(None);
+            }
+
+            let id = if state.destroyed {
+                None
+            } else {
+                Some(state.id.get_or_insert_with(new_id).clone())
+            };
+            (
+                id,
+                state.data.clone(),
+                state.replaced.take(),
+                state.destroyed,
+            )
+        };
+
+        if let Some(replaced) = replaced {
+            self.sessions.store.remove(&replaced).await?;
+        }
+
+        let Some(id) = id else {
+            return Ok(destroyed.then(|| self.sessions.cookie(String::new(), 0)));
+        };
+
+        let expires_at = chrono::Utc::now().timestamp() + self.sessions.max_age;
+        self.sessions.store.save(&id, &data, expires_at).await?;
+        if self.sessions.saves.fetch_add(1, Ordering::Relaxed) % PRUNE_INTERVAL
+            == PRUNE_INTERVAL - 1
+        {
+            self.sessions.store.prune().await?;
+        }
+
+        Ok(Some(self.sessions.cookie(id, self.sessions.max_age)))
+    }
+
+    fn get(&self, lua: &mlua::Lua, key: &str) -> mlua::Result<mlua::Value> {
+        match self.state()?.data.get(key) {
+            Some(value) => lua.to_value_with(value, serialize_options()),
+            None => Ok(mlua::Value::Nil),
+        }
+    }
+
+    fn set(&self, lua: &mlua::Lua, key: String, value: mlua::Value) -> mlua::Result<()> {
+        if key == FLASH_KEY {
+            return Err(mlua::Error::runtime(format!(
+                "The session key {FLASH_KEY} is reserved for flash messages"
+            )));
+        }
+
+        let mut state = self.state()?;
+        if value.is_nil() {
+            state.data.remove(&key);
+        } else {
+            state
+                .data
+                .insert(key, lua.from_value::<serde_json::Value>(value)?);
+        }
+        state.modified = true;
+        state.destroyed = false;
+
+        Ok(())
+    }
+}
+impl UserData for AstraSession {
+    fn add_methods<M: mlua::UserDataMethods<Self>>(methods: &mut M) {
+        methods.add_method("get", |lua, this, key: String| this.get(lua, &key));
+        methods.add_method("set", |lua, this, (key, value): (String, mlua::Value)| {
+            this.set(lua, key, value)
+        });
+        methods.add_method("remove", |lua, this, key: String| {
+            this.set(lua, key, mlua::Value::Nil)
+        });
+        methods.add_method("all", |lua, this, ()| {
+            lua.to_value_with(&this.state()?.data, serialize_options())
+        });
+        methods.add_method("clear", |_, this, ()| {
+            let mut state = this.state()?;
+            state.data.clear();
+            state.modified = true;
+
+            Ok(())
+        });
+        methods.add_method("id", |_, this, ()| {
+            let mut state = this.state()?;
+            state.modified = true;
+            state.destroyed = false;
+
+            Ok(state.id.get_or_insert_with(new_id).clone())
+        });
+        methods.add_method("regenerate", |_, this, ()| {
+            let mut state = this.state()?;
+            let previous = state.id.replace(new_id());
+            if state.replaced.is_none() {
+                state.replaced = previous;
+            }
+            state.modified = true;
+            state.destroyed = false;
+
+            Ok(state.id.clone())
+        });
+        methods.add_method("destroy", |_, this, ()| {
+            let mut state = this.state()?;
+            let previous = state.id.take();
+            if state.replaced.is_none() {
+                state.replaced = previous;
+            }
+            state.data.clear();
+            state.flash.clear();
+            state.destroyed = true;
+
+            Ok(())
+        });
+        methods.add_method("flash", |lua, this, (key, value): (String, mlua::Value)| {
+            let value = lua.from_value::<serde_json::Value>(value)?;
+            let mut state = this.state()?;
+            match state.data.entry(FLASH_KEY) {
+                serde_json::map::Entry::Occupied(mut entry) => {
+                    if let serde_json::Value::Object(flash) = entry.get_mut() {
+                        flash.insert(key, value);
+                    }
+                }
+                serde_json::map::Entry::Vacant(entry) => {
+                    entry.insert(serde_json::Value::Object(SessionData::from_iter([(
+                        key, value,
+                    )])));
+                }
+            }
+            state.modified = true;
+            state.destroyed = false;
+
+            Ok(())
+        });
+        methods.add_method("get_flash", |lua, this, key: Option<String>| {
+            let state = this.state()?;
+            match key {
+                Some(key) => match state.flash.get(&key) {
+                    Some(value) => lua.to_value_with(value, serialize_options()),
+                    None => Ok(mlua::Value::Nil),
+                },
+                None => lua.to_value_with(&state.flash, serialize_options()),
+            }
+        });
+
+        methods.add_meta_method(mlua::MetaMethod::Index, |lua, this, key: String| {
+            this.get(lua, &key)
+        });
+        methods.add_meta_method(
+            mlua::MetaMethod::NewIndex,
+            |lua, this, (key, value): (String, mlua::Value)| this.set(lua, key, value),
+        );
+    }
+}
+
+fn serialize_options() -> mlua::SerializeOptions {
+    mlua::SerializeOptions::new()
+        .serialize_none_to_null(false)
+        .serialize_unit_to_null(false)
+}
+
+/// Hands every request a session backed by the cookie it came with, saving the session and
+/// setting its cookie once the response is ready.
+pub async fn layer(
+    State(sessions): State<Arc<Sessions>>,
+    mut request: Request<Body>,
+    next: Next,
+) -> Response {
+    let id = CookieJar::from_headers(request.headers())
+        .get(&sessions.cookie)
+        .map(|cookie| cookie.value().to_string())
+        .filter(|id| is_valid_id(id));
+
+    let session = AstraSession {
+        sessions,
+        state: Arc::new(Mutex::new(SessionState {
+            id,
+            ..Default::default()
+        })),
+    };
+    request.extensions_mut().insert(session.clone());
+
+    let mut response = next.run(request).await;
+
+    match session.commit().await {
+        Ok(Some(cookie)) => {
+            if let Ok(cookie) = HeaderValue::from_str(&cookie.to_string()) {
+                response.headers_mut().append(SET_COOKIE, cookie);
+            }
+        }
+        Ok(None) => {}
+        Err(e) => tracing::error!("Error saving the session: {e}"),
+    }
+
+    response
+}
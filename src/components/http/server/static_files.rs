@@ -0,0 +1,300 @@
+use super::{configs::RouteConfiguration, errors::escape};
+use axum::{
+    body::Body,
+    extract::{OriginalUri, State},
+    http::{HeaderValue, Request, StatusCode, header, request::Parts},
+    response::{Html, IntoResponse, Response},
+};
+use std::{path::PathBuf, sync::Arc};
+use tower::ServiceExt;
+use tower_http::services::{ServeDir, ServeFile};
+
+const IMMUTABLE: HeaderValue = HeaderValue::from_static("public, max-age=31536000, immutable");
+
+/// A directory served by a `static_dir` route along with the options of the route.
+#[derive(Debug, Clone)]
+pub struct StaticFiles {
+    root: PathBuf,
+    serve_dir: ServeDir,
+    /// Served instead of a missing file so that client side routing works
+    spa: Option<ServeFile>,
+    /// Served with 404 instead of a missing file
+    not_found: Option<ServeFile>,
+    cache_control: Option<HeaderValue>,
+    /// Paths of content hashed assets, which are cached for good
+    immutable: Option<regex::Regex>,
+    listing: bool,
+}
+impl StaticFiles {
+    pub fn new(root: &str, config: &RouteConfiguration) -> mlua::Result<Self> {
+        let root = PathBuf::from(root);
+        let precompressed = config.precompressed.unwrap_or(false);
+        let serve_file = |path: PathBuf| {
+            let file = ServeFile::new(path);
+            if precompressed {
+                file.precompressed_br()
+                    .precompressed_gzip()
+                    .precompressed_zstd()
+            } else {
+                file
+            }
+        };
+
+        let mut serve_dir = ServeDir::new(&root);
+        if precompressed {
+            serve_dir = serve_dir
+                .precompressed_br()
+                .precompressed_gzip()
+                .precompressed_zstd();
+        }
+
+        let spa = config.spa.unwrap_or(false);
+        if spa && config.not_found.is_some() {
+            return Err(mlua::Error::runtime(
+                "A static directory cannot have both an SPA fallback and a not found page",
+            ));
+        }
+
+        Ok(Self {
+            spa: spa.then(|| serve_file(root.join("index.html"))),
+            not_found: config
+                .not_found
+                .as_ref()
+                .map(|path| serve_file(root.join(path))),
+            cache_control: config
+                .cache_control
+                .as_ref()
+                .map(|value| {
+                    HeaderValue::from_str(value).map_err(|e| {
+                        mlua::Error::runtime(format!("Invalid cache_control value {value}: {e}"))
+                    })
+                })
+                .transpose()?,
+            immutable: config
+                .immutable
+                .as_ref()
+                .map(|pattern| {
+                    regex::Regex::new(pattern).map_err(|e| {
+                        mlua::Error::runtime(format!("Invalid immutable pattern {pattern}: {e}"))
+                    })
+                })
+                .transpose()?,
+            listing: config.listing.unwrap_or(false),
+            root,
+            serve_dir,
+        })
+    }
+
+    /// Turns the directory into a service that can be nested in the router.
+    pub fn into_service(self) -> axum::routing::MethodRouter {
+        axum::routing::any(serve).with_state(Arc::new(self))
+    }
+
+    /// Maps the path of a request onto the directory, refusing anything that leaves it.
+    fn resolve(&self, path: &str) -> Option<PathBuf> {
+        let path = percent_encoding::percent_decode_str(path)
+            .decode_utf8()
+            .ok()?;
+        let mut resolved = self.root.clone();
+
+        for segment in path.split('/').filter(|segment| !segment.is_empty()) {
+            let mut components = std::path::Path::new(segment).components();
+            match (components.next(), components.next()) {
+                (Some(std::path::Component::Normal(component)), None) => resolved.push(component),
+                _ => return None,
+            }
+        }
+
+        Some(resolved)
+    }
+
+    /// Lists the contents of a directory that has no `index.html` of its own.
+    async fn listing(&self, parts: &Parts) -> Option<Response> {
+        let directory = self.resolve(parts.uri.path())?;
+        let mut entries = tokio::fs::read_dir(&directory).await.ok()?;
+
+        let mut names = Vec::new();
+        while let Ok(Some(entry)) = entries.next_entry().await {
+            let name = entry.file_name().to_string_lossy().to_string();
+            // hidden files are left out of the listing
+            if name.starts_with('.') {
+                continue;
+            }
+
+            match entry.file_type().await {
+                Ok(file_type) if file_type.is_dir() => names.push(format!("{name}/")),
+                Ok(_) => names.push(name),
+                Err(_) => continue,
+            }
+        }
+        names.sort();
+
+        let path = parts.extensions.get::<OriginalUri>().map_or_else(
+            || parts.uri.path().to_string(),
+            |uri| uri.path().to_string(),
+        );
+        // the links are relative, which only works from within the directory
+        if !path.ends_with('/') {
+            return Some(axum::response::Redirect::permanent(&format!("{path}/")).into_response());
+        }
+        let title = escape(&path);
+        let items = names
+            .iter()
+            .map(|name| {
+                let href = percent_encoding::utf8_percent_encode(
+                    name.trim_end_matches('/'),
+                    percent_encoding::NON_ALPHANUMERIC,
+                );
+                let slash = if name.ends_with('/') { "/" } else { "" };
+                format!("<li><a href=\"{href}{slash}\">{}</a></li>", escape(name))
+            })
+            .collect::<String>();
+
+        Some(
+            Html(format!(
+                "<!DOCTYPE html><html><head><meta charset=\"utf-8\"><title>Index of {title}</title></head>\
+                <body><h1>Index of {title}</h1><ul><li><a href=\"../\">../</a></li>{items}</ul></body></html>"
+            ))
+            .into_response(),
+        )
+    }
+
+    /// Sets the `Cache-Control` header of files that were found.
+    fn cache_control(&self, path: &str, response: &mut Response) {
+        if !response.status().is_success() && response.status() != StatusCode::NOT_MODIFIED {
+            return;
+        }
+
+        let value = match &self.immutable {
+            Some(immutable) if immutable.is_match(path) => IMMUTABLE,
+            _ => match &self.cache_control {
+                Some(value) => value.clone(),
+                None => return,
+            },
+        };
+        response.headers_mut().insert(header::CACHE_CONTROL, value);
+    }
+}
+
+async fn serve(State(files): State<Arc<StaticFiles>>, request: Request<Body>) -> Response {
+    let (parts, body) = request.into_parts();
+    let Ok(response) = files
+        .serve_dir
+        .clone()
+        .oneshot(Request::from_parts(parts.clone(), body))
+        .await;
+    let mut response = response.map(Body::new);
+
+    if response.status() != StatusCode::NOT_FOUND {
+        files.cache_control(parts.uri.path(), &mut response);
+        return response;
+    }
+
+    if files.listing
+        && let Some(listing) = files.listing(&parts).await
+    {
+        return listing;
+    }
+
+    let fallback = files.spa.as_ref().or(files.not_found.as_ref());
+    if let Some(fallback) = fallback {
+        let Ok(fallback_response) = fallback
+            .clone()
+            .oneshot(Request::from_parts(parts.clone(), Body::empty()))
+            .await;
+        response = fallback_response.map(Body::new);
+
+        if files.spa.is_some() {
+            // the index is not a hashed asset, even if the requested path looks like one
+            if let Some(cache_control) = &files.cache_control
+                && response.status().is_success()
+            {
+                response
+                    .headers_mut()
+                    .insert(header::CACHE_CONTROL, cache_control.clone());
+            }
+        } else if response.status().is_success() {
+            *response.status_mut() = StatusCode::NOT_FOUND;
+        }
+    }
+
+    response
+}
+
+/// A file sent from a handler through `response:send_file`.
+#[derive(Debug, Clone, Default, serde::Deserialize)]
+pub struct SendFile {
+    #[serde(skip)]
+    pub path: PathBuf,
+    /// Name the client saves the file under
+    pub filename: Option<String>,
+    /// Asks the client to download the file rather than display it
+    pub attachment: Option<bool>,
+    /// Replaces the type guessed from the extension of the file
+    pub content_type: Option<String>,
+}
+impl SendFile {
+    /// Streams the file from disk, answering range and conditional requests along the way.
+    pub async fn into_response(self, parts: &Parts) -> mlua::Result<Response> {
+        let mut parts = parts.clone();
+        // the file is sent the same way no matter which method the route answers to
+        if parts.method != axum::http::Method::HEAD {
+            parts.method = axum::http::Method::GET;
+        }
+
+        let Ok(response) = ServeFile::new(&self.path)
+            .oneshot(Request::from_parts(parts, Body::empty()))
+            .await;
+        let mut response = response.map(Body::new);
+        if !response.status().is_success() {
+            return Ok(response);
+        }
+
+        if let Some(content_type) = &self.content_type {
+            let content_type = HeaderValue::from_str(content_type).map_err(|e| {
+                mlua::Error::runtime(format!("Invalid content type {content_type}: {e}"))
+            })?;
+            response
+                .headers_mut()
+                .insert(header::CONTENT_TYPE, content_type);
+        }
+
+        if self.filename.is_some() || self.attachment.is_some() {
+            let filename = match &self.filename {
+                Some(filename) => filename.clone(),
+                None => self
+                    .path
+                    .file_name()
+                    .map(|name| name.to_string_lossy().to_string())
+                    .unwrap_or_default(),
+            };
+            let kind = if self.attachment.unwrap_or(true) {
+                "attachment"
+            } else {
+                "inline"
+            };
+            // plain ASCII for older clients, the exact name for everyone else
+            let fallback = filename
+                .chars()
+                .map(|c| match c {
+                    ' '..='~' if c != '"' && c != '\\' => c,
+                    _ => '_',
+                })
+                .collect::<String>();
+            let encoded = percent_encoding::utf8_percent_encode(
+                &filename,
+                percent_encoding::NON_ALPHANUMERIC,
+            );
+
+            if let Ok(disposition) = HeaderValue::from_str(&format!(
+                "{kind}; filename=\"{fallback}\"; filename*=UTF-8''{encoded}"
+            )) {
+                response
+                    .headers_mut()
+                    .insert(header::CONTENT_DISPOSITION, disposition);
+            }
+        }
+
+        Ok(response)
+    }
+}
@@ -0,0 +1,166 @@
+use super::lifecycle::Lifecycle;
+use crate::components::value_to_bytes;
+use axum::{
+    body::Body,
+    response::{
+        IntoResponse,
+        sse::{Event, KeepAlive, Sse},
+    },
+};
+use bytes::Bytes;
+use futures::StreamExt;
+use mlua::{ExternalError, LuaSerdeExt, UserData};
+use tokio_stream::wrappers::ReceiverStream;
+
+/// Amount of chunks that can be queued up before `write` waits for the client to catch up.
+const STREAM_BUFFER: usize = 16;
+
+#[derive(Debug, Clone)]
+pub enum ResponseStream {
+    Body(mlua::Function),
+    Sse {
+        function: mlua::Function,
+        keep_alive: Option<u64>,
+    },
+}
+impl ResponseStream {
+    /// Runs the stream callback in the background and returns a response whose body is fed
+    /// by whatever the callback writes, until the server shuts down.
+    pub fn into_response(
+        self,
+        lua: &mlua::Lua,
+        lifecycle: &std::sync::Arc<Lifecycle>,
+    ) -> mlua::Result<axum::response::Response> {
+        let lifecycle = lifecycle.clone();
+        let closing = async move { lifecycle.closing().await };
+
+        Ok(match self {
+            Self::Body(function) => {
+                let (sender, receiver) = tokio::sync::mpsc::channel(STREAM_BUFFER);
+                Self::spawn(function, lua.create_userdata(AstraResponseStream(sender))?);
+
+                Body::from_stream(
+                    ReceiverStream::new(receiver)
+                        .take_until(closing)
+                        .map(Ok::<_, std::convert::Infallible>),
+                )
+                .into_response()
+            }
+            Self::Sse {
+                function,
+                keep_alive,
+            } => {
+                let (sender, receiver) = tokio::sync::mpsc::channel(STREAM_BUFFER);
+                Self::spawn(function, lua.create_userdata(AstraSSEStream(sender))?);
+
+                let sse = Sse::new(
+                    ReceiverStream::new(receiver)
+                        .take_until(closing)
+                        .map(Ok::<_, std::convert::Infallible>),
+                );
+                match keep_alive {
+                    Some(0) => sse.into_response(),
+                    Some(interval) => sse
+                        .keep_alive(
+                            KeepAlive::new().interval(std::time::Duration::from_secs(interval)),
+                        )
+                        .into_response(),
+                    None => sse.keep_alive(KeepAlive::default()).into_response(),
+                }
+            }
+        })
+    }
+
+    fn spawn(function: mlua::Function, stream: mlua::AnyUserData) {
+        tokio::spawn(async move {
+            if let Err(e) = function.call_async::<()>(&stream).await {
+                tracing::error!("Error executing the response stream: {e}");
+            }
+
+            // drops the sender so the body ends even if Lua still holds on to the writer
+            let _ = stream.destroy();
+        });
+    }
+}
+
+/// Writer handed to `response:stream` callbacks.
+#[derive(Debug, Clone)]
+pub struct AstraResponseStream(tokio::sync::mpsc::Sender<Bytes>);
+impl UserData for AstraResponseStream {
+    fn add_methods<M: mlua::UserDataMethods<Self>>(methods: &mut M) {
+        // returns false once the client has gone away
+        methods.add_async_method("write", |_, this, chunk: mlua::Value| async move {
+            let chunk = value_to_bytes(&chunk)?;
+
+            Ok(this.0.send(chunk).await.is_ok())
+        });
+        methods.add_method("is_closed", |_, this, ()| Ok(this.0.is_closed()));
+    }
+}
+
+/// Event names, IDs and comments are single line fields, anything else would break the framing.
+fn single_line(field: &str, value: String) -> mlua::Result<String> {
+    if value.contains(['\n', '\r', '\0']) {
+        Err(mlua::Error::runtime(format!(
+            "the event {field} cannot contain newlines or null characters"
+        )))
+    } else {
+        Ok(value)
+    }
+}
+
+/// Writer handed to `response:sse` callbacks.
+#[derive(Debug, Clone)]
+pub struct AstraSSEStream(tokio::sync::mpsc::Sender<Event>);
+impl AstraSSEStream {
+    fn event_from_lua(lua: &mlua::Lua, value: mlua::Value) -> mlua::Result<Event> {
+        let table = match value {
+            mlua::Value::Table(table) => table,
+            mlua::Value::String(data) => {
+                return Ok(Event::default().data(data.to_string_lossy()));
+            }
+            _ => return Err(mlua::Error::runtime("an event must be a string or a table")),
+        };
+
+        let mut event = Event::default();
+        match table.get::<mlua::Value>("data")? {
+            mlua::Value::Nil => {}
+            mlua::Value::String(data) => event = event.data(data.to_string_lossy()),
+            data => {
+                event = event
+                    .json_data(lua.from_value::<serde_json::Value>(data)?)
+                    .map_err(|e| e.into_lua_err())?
+            }
+        }
+        if let Some(name) = table.get::<Option<String>>("event")? {
+            event = event.event(single_line("name", name)?);
+        }
+        if let Some(id) = table.get::<Option<String>>("id")? {
+            event = event.id(single_line("id", id)?);
+        }
+        if let Some(retry) = table.get::<Option<u64>>("retry")? {
+            event = event.retry(std::time::Duration::from_millis(retry));
+        }
+        if let Some(comment) = table.get::<Option<String>>("comment")? {
+            event = event.comment(single_line("comment", comment)?);
+        }
+
+        Ok(event)
+    }
+}
+impl UserData for AstraSSEStream {
+    fn add_methods<M: mlua::UserDataMethods<Self>>(methods: &mut M) {
+        // returns false once the client has gone away
+        methods.add_async_method("send", |lua, this, event: mlua::Value| async move {
+            let event = Self::event_from_lua(&lua, event)?;
+
+            Ok(this.0.send(event).await.is_ok())
+        });
+        methods.add_async_method("comment", |_, this, comment: String| async move {
+            let event = Event::default().comment(single_line("comment", comment)?);
+
+            Ok(this.0.send(event).await.is_ok())
+        });
+        methods.add_method("is_closed", |_, this, ()| Ok(this.0.is_closed()));
+    }
+}
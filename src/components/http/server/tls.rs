@@ -0,0 +1,120 @@
+use mlua::ExternalError;
+use std::sync::{Arc, RwLock};
+use tokio_rustls::{
+    TlsAcceptor,
+    rustls::{
+        self, RootCertStore, ServerConfig,
+        pki_types::{CertificateDer, PrivateKeyDer, pem::PemObject},
+        server::WebPkiClientVerifier,
+    },
+};
+
+/// How long a client gets to finish the TLS handshake before the connection is dropped.
+pub const HANDSHAKE_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(10);
+
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct TlsConfiguration {
+    /// Path to the PEM encoded certificate chain
+    pub cert: String,
+    /// Path to the PEM encoded private key
+    pub key: String,
+    /// Path to the PEM encoded CA bundle used to verify client certificates (mTLS)
+    pub client_ca: Option<String>,
+    /// Accept clients that do not present a certificate when `client_ca` is set
+    pub client_auth_optional: Option<bool>,
+}
+impl TlsConfiguration {
+    /// Reads the certificates and keys from disk and builds a rustls server config out of them.
+    pub fn load(&self) -> mlua::Result<ServerConfig> {
+        let provider = Arc::new(rustls::crypto::aws_lc_rs::default_provider());
+
+        let certs = CertificateDer::pem_file_iter(&self.cert)
+            .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+            .map_err(|e| {
+                mlua::Error::runtime(format!(
+                    "Could not read the TLS certificate {}: {e}",
+                    self.cert
+                ))
+            })?;
+        let key = PrivateKeyDer::from_pem_file(&self.key).map_err(|e| {
+            mlua::Error::runtime(format!(
+                "Could not read the TLS private key {}: {e}",
+                self.key
+            ))
+        })?;
+
+        let builder = ServerConfig::builder_with_provider(provider.clone())
+            .with_safe_default_protocol_versions()
+            .map_err(|e| e.into_lua_err())?;
+
+        let builder = match &self.client_ca {
+            Some(client_ca) => {
+                let mut roots = RootCertStore::empty();
+                for cert in CertificateDer::pem_file_iter(client_ca)
+                    .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+                    .map_err(|e| {
+                        mlua::Error::runtime(format!(
+                            "Could not read the TLS client CA {client_ca}: {e}"
+                        ))
+                    })?
+                {
+                    roots.add(cert).map_err(|e| e.into_lua_err())?;
+                }
+
+                let mut verifier =
+                    WebPkiClientVerifier::builder_with_provider(Arc::new(roots), provider);
+                if self.client_auth_optional.unwrap_or(false) {
+                    verifier = verifier.allow_unauthenticated();
+                }
+
+                builder.with_client_cert_verifier(verifier.build().map_err(|e| e.into_lua_err())?)
+            }
+            None => builder.with_no_client_auth(),
+        };
+
+        let mut config = builder
+            .with_single_cert(certs, key)
+            .map_err(|e| e.into_lua_err())?;
+        config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+
+        Ok(config)
+    }
+}
+
+/// Shared handle to the active TLS config, swapped out on reload so that new
+/// handshakes pick up the fresh certificates while existing connections stay intact.
+#[derive(Debug, Clone)]
+pub struct TlsState {
+    pub configuration: TlsConfiguration,
+    pub server_config: Arc<RwLock<Arc<ServerConfig>>>,
+}
+impl TlsState {
+    pub fn new(configuration: TlsConfiguration) -> mlua::Result<Self> {
+        let server_config = Arc::new(configuration.load()?);
+
+        Ok(Self {
+            configuration,
+            server_config: Arc::new(RwLock::new(server_config)),
+        })
+    }
+
+    pub fn reload(&self) -> mlua::Result<()> {
+        let server_config = Arc::new(self.configuration.load()?);
+        match self.server_config.write() {
+            Ok(mut current) => {
+                *current = server_config;
+                Ok(())
+            }
+            Err(e) => Err(mlua::Error::runtime(format!(
+                "Could not reload the TLS configuration: {e}"
+            ))),
+        }
+    }
+
+    pub fn acceptor(&self) -> Option<TlsAcceptor> {
+        self.server_config
+            .read()
+            .ok()
+            .map(|config| TlsAcceptor::from(config.clone()))
+    }
+}
@@ -1,169 +1,238 @@
+use super::hub;
+use crate::components::{http::websocket, value_to_bytes};
 use axum::extract::ws::{CloseFrame, Message, Utf8Bytes, WebSocket};
-use bytes::Bytes;
+use futures::{SinkExt, StreamExt, stream::SplitStream};
 use mlua::{ExternalError, UserData};
+use std::time::Duration;
 
-pub struct AstraWebSocket(pub WebSocket);
+pub struct AstraWebSocket {
+    id: String,
+    sink: hub::Sink,
+    stream: tokio::sync::Mutex<SplitStream<WebSocket>>,
+    /// The subprotocol agreed on during the handshake
+    protocol: Option<String>,
+    /// How long `recv` waits for anything from the peer before closing the connection
+    idle_timeout: Option<Duration>,
+}
 impl AstraWebSocket {
-    fn value_to_bytes(value: &mlua::Value) -> Result<Bytes, mlua::Error> {
-        if let Some(table) = value.as_table() {
-            Ok(Bytes::from_iter(
-                table.sequence_values::<u8>().filter_map(|x| x.ok()),
-            ))
-        } else if value.is_string() {
-            Ok(Bytes::from(value.to_string()?))
+    /// Splits the socket so that broadcasts can reach it while its handler waits for messages.
+    /// It stays in the hub until the returned presence is dropped.
+    pub fn connect(socket: WebSocket, idle_timeout: Option<Duration>) -> (Self, hub::Presence) {
+        let protocol = socket
+            .protocol()
+            .and_then(|protocol| protocol.to_str().ok())
+            .map(str::to_string);
+        let (sink, stream) = socket.split();
+        let sink = std::sync::Arc::new(tokio::sync::Mutex::new(sink));
+        let (id, presence) = hub::connect(sink.clone());
+
+        (
+            Self {
+                id,
+                sink,
+                stream: tokio::sync::Mutex::new(stream),
+                protocol,
+                idle_timeout,
+            },
+            presence,
+        )
+    }
+
+    /// Pings the peer at the interval for as long as it is polled, never finishing on its own.
+    pub fn keep_alive(
+        &self,
+        interval: Option<Duration>,
+    ) -> impl Future<Output = ()> + Send + 'static {
+        websocket::keep_alive(self.sink.clone(), interval)
+    }
+
+    /// Closes the connection with 1001 Going Away once awaited, for when the server shuts down.
+    pub fn going_away(&self) -> impl Future<Output = ()> + Send + 'static {
+        let sink = self.sink.clone();
+        async move {
+            let _ = sink
+                .lock()
+                .await
+                .send(Message::Close(Some(CloseFrame {
+                    code: 1001,
+                    reason: Utf8Bytes::from_static("The server is shutting down"),
+                })))
+                .await;
+        }
+    }
+
+    async fn send(&self, message: Message) -> mlua::Result<()> {
+        self.sink
+            .lock()
+            .await
+            .send(message)
+            .await
+            .map_err(|e| e.into_lua_err())
+    }
+
+    async fn next_message(&self) -> mlua::Result<Option<Message>> {
+        websocket::next_message(&self.stream, &self.sink, self.idle_timeout).await
+    }
+
+    /// Tables are sent as JSON.
+    fn value_to_text(value: &mlua::Value) -> Result<Utf8Bytes, mlua::Error> {
+        Ok(Utf8Bytes::from(
+            if let Some(table_message) = value.as_table() {
+                serde_json::to_string(&table_message.clone()).map_err(|e| e.into_lua_err())?
+            } else if let Some(string_message) = value.as_string() {
+                string_message.to_string_lossy()
+            } else {
+                value.to_string()?
+            },
+        ))
+    }
+
+    fn close_frame(value: Option<mlua::Value>) -> Message {
+        match value {
+            Some(mlua::Value::Integer(close_code)) => Message::Close(Some(CloseFrame {
+                code: u16::try_from(close_code).unwrap_or(1006),
+                reason: Utf8Bytes::from_static(""),
+            })),
+            Some(mlua::Value::Table(table)) => Message::Close(Some(CloseFrame {
+                code: table.get::<u16>(1).unwrap_or(1005),
+                reason: Utf8Bytes::from(table.get::<String>(2).unwrap_or("".to_string())),
+            })),
+            _ => Message::Close(None),
+        }
+    }
+
+    /// Reads the message of a broadcast, which is text unless `binary` is set in its options.
+    fn hub_message(message: &mlua::Value, options: &Option<mlua::Table>) -> mlua::Result<Message> {
+        let binary = match options {
+            Some(options) => options.get::<Option<bool>>("binary")?.unwrap_or(false),
+            None => false,
+        };
+        if binary {
+            Ok(Message::Binary(value_to_bytes(message)?))
         } else {
-            Err(mlua::Error::runtime("type cannot be accepted as bytes"))
+            Ok(Message::Text(Self::value_to_text(message)?))
         }
     }
+
+    pub fn register_to_lua(lua: &mlua::Lua) -> mlua::Result<()> {
+        lua.globals().set(
+            "astra_internal__ws_broadcast",
+            lua.create_async_function(
+                |_, (room, message, options): (String, mlua::Value, Option<mlua::Table>)| async move {
+                    let except = match &options {
+                        Some(options) => options.get::<Option<String>>("except")?,
+                        None => None,
+                    };
+                    let message = Self::hub_message(&message, &options)?;
+                    Ok(hub::broadcast(&room, message, except.as_deref()).await)
+                },
+            )?,
+        )?;
+
+        lua.globals().set(
+            "astra_internal__ws_send",
+            lua.create_async_function(
+                |_, (id, message, options): (String, mlua::Value, Option<mlua::Table>)| async move {
+                    let message = Self::hub_message(&message, &options)?;
+                    Ok(hub::send(&id, message).await)
+                },
+            )?,
+        )?;
+
+        lua.globals().set(
+            "astra_internal__ws_count",
+            lua.create_function(|_, room: String| Ok(hub::count(&room)))?,
+        )?;
+
+        lua.globals().set(
+            "astra_internal__ws_members",
+            lua.create_function(|_, room: String| Ok(hub::members(&room)))?,
+        )?;
+
+        Ok(())
+    }
 }
 impl UserData for AstraWebSocket {
     fn add_methods<M: mlua::UserDataMethods<Self>>(methods: &mut M) {
-        methods.add_async_method_mut("recv", |lua, mut this, ()| async move {
-            match this.0.recv().await {
-                Some(msg) => match msg {
-                    Ok(msg) => {
-                        let recv = lua.create_table()?;
-                        match msg {
-                            Message::Text(utf8_bytes) => {
-                                recv.set("type", "text")?;
-                                recv.set("value", utf8_bytes.to_string())?;
-                            }
-                            Message::Binary(bytes) => {
-                                recv.set("type", "bytes")?;
-                                recv.set("value", bytes.to_vec())?;
-                            }
-                            Message::Close(close_frame) => match close_frame {
-                                Some(frame) => {
-                                    recv.set("type", "close")?;
-                                    let close_frame = lua.create_table()?;
-                                    close_frame.set("code", frame.code)?;
-                                    close_frame.set("reason", frame.reason.to_string())?;
-                                    recv.set("value", close_frame)?;
-                                }
-                                None => {
-                                    recv.set("type", "Close")?;
-                                    recv.set("value", mlua::Value::Nil)?;
-                                }
-                            },
-                            _ => {}
-                        };
-
-                        Ok(recv)
+        methods.add_async_method("recv", |lua, this, ()| async move {
+            let Some(msg) = this.next_message().await? else {
+                return Ok(None);
+            };
+
+            let recv = lua.create_table()?;
+            match msg {
+                Message::Text(utf8_bytes) => {
+                    recv.set("type", "text")?;
+                    recv.set("value", utf8_bytes.to_string())?;
+                }
+                Message::Binary(bytes) => {
+                    recv.set("type", "bytes")?;
+                    recv.set("value", bytes.to_vec())?;
+                }
+                Message::Close(close_frame) => {
+                    recv.set("type", "close")?;
+                    if let Some(frame) = close_frame {
+                        let close_frame = lua.create_table()?;
+                        close_frame.set("code", frame.code)?;
+                        close_frame.set("reason", frame.reason.to_string())?;
+                        recv.set("value", close_frame)?;
                     }
-                    Err(e) => Err(mlua::Error::runtime(format!(
-                        "failed to receive a frame: {e}",
-                    ))),
-                },
-                None => Err(mlua::Error::runtime("No message received!")),
-            }
+                }
+                Message::Ping(_) | Message::Pong(_) => {}
+            };
+
+            Ok(Some(recv))
         });
 
-        methods.add_async_method_mut(
+        methods.add_async_method(
             "send",
-            |_, mut this, (message_type, message): (String, mlua::Value)| async move {
+            |_, this, (message_type, message): (String, mlua::Value)| async move {
                 let msg = match message_type.to_lowercase().as_str() {
-                    "text" => Ok(Message::Text(Utf8Bytes::from(
-                        if let Some(table_message) = message.as_table() {
-                            serde_json::to_string(&table_message.clone())
-                                .map_err(|e| e.into_lua_err())?
-                        } else if let Some(string_message) = message.as_string() {
-                            string_message.to_string_lossy()
-                        } else {
-                            message.to_string()?
-                        },
-                    ))),
-                    "bytes" => Ok(Message::Binary(Self::value_to_bytes(&message)?)),
-                    "close" => match message {
-                        mlua::Value::Integer(close_code) => Ok(Message::Close(Some(CloseFrame {
-                            code: u16::try_from(close_code).unwrap_or(1006),
-                            reason: Utf8Bytes::from_static(""),
-                        }))),
-                        mlua::Value::Table(table) => Ok(Message::Close(Some(CloseFrame {
-                            code: table.get::<u16>(1).unwrap_or(1005),
-                            reason: Utf8Bytes::from(
-                                table.get::<String>(2).unwrap_or("".to_string()),
-                            ),
-                        }))),
-                        _ => Ok(Message::Close(None)),
-                    },
-                    _ => Err(mlua::Error::runtime("invalid message type")),
+                    "text" => Message::Text(Self::value_to_text(&message)?),
+                    "bytes" => Message::Binary(value_to_bytes(&message)?),
+                    "close" => Self::close_frame(Some(message)),
+                    _ => return Err(mlua::Error::runtime("invalid message type")),
                 };
 
-                match msg {
-                    Ok(msg) => match this.0.send(msg).await {
-                        Ok(_) => Ok(()),
-                        Err(e) => Err(e.into_lua_err()),
-                    },
-                    Err(e) => Err(e.into_lua_err()),
-                }
+                this.send(msg).await
             },
         );
 
-        methods.add_async_method_mut("send_text", |_, mut this, message: String| async move {
-            match this.0.send(Message::Text(Utf8Bytes::from(message))).await {
-                Ok(_) => Ok(()),
-                Err(e) => Err(e.into_lua_err()),
-            }
+        methods.add_async_method("send_text", |_, this, message: String| async move {
+            this.send(Message::Text(Utf8Bytes::from(message))).await
         });
 
-        methods.add_async_method_mut("send_bytes", |_, mut this, bytes: mlua::Value| async move {
-            match this
-                .0
-                .send(Message::Binary(Self::value_to_bytes(&bytes)?))
-                .await
-            {
-                Ok(_) => Ok(()),
-                Err(e) => Err(e.into_lua_err()),
-            }
+        methods.add_async_method("send_bytes", |_, this, bytes: mlua::Value| async move {
+            this.send(Message::Binary(value_to_bytes(&bytes)?)).await
         });
 
-        methods.add_async_method_mut("send_ping", |_, mut this, bytes: mlua::Value| async move {
-            match this
-                .0
-                .send(Message::Ping(Self::value_to_bytes(&bytes)?))
-                .await
-            {
-                Ok(_) => Ok(()),
-                Err(e) => Err(e.into_lua_err()),
-            }
+        methods.add_async_method("send_ping", |_, this, bytes: mlua::Value| async move {
+            this.send(Message::Ping(value_to_bytes(&bytes)?)).await
         });
 
-        methods.add_async_method_mut("send_pong", |_, mut this, bytes: mlua::Value| async move {
-            match this
-                .0
-                .send(Message::Pong(Self::value_to_bytes(&bytes)?))
-                .await
-            {
-                Ok(_) => Ok(()),
-                Err(e) => Err(e.into_lua_err()),
-            }
+        methods.add_async_method("send_pong", |_, this, bytes: mlua::Value| async move {
+            this.send(Message::Pong(value_to_bytes(&bytes)?)).await
         });
 
-        methods.add_async_method_mut(
+        methods.add_async_method(
             "send_close",
-            |_, mut this, close_frame: Option<mlua::Value>| async move {
-                let close_frame: Message = match close_frame {
-                    Some(frame) => match frame {
-                        mlua::Value::Integer(close_code) => Message::Close(Some(CloseFrame {
-                            code: u16::try_from(close_code).unwrap_or(1006),
-                            reason: Utf8Bytes::from_static(""),
-                        })),
-                        mlua::Value::Table(table) => Message::Close(Some(CloseFrame {
-                            code: table.get::<u16>(1).unwrap_or(1005),
-                            reason: Utf8Bytes::from(
-                                table.get::<String>(2).unwrap_or("".to_string()),
-                            ),
-                        })),
-                        _ => Message::Close(None),
-                    },
-                    None => Message::Close(None),
-                };
-
-                match this.0.send(close_frame).await {
-                    Ok(_) => Ok(()),
-                    Err(e) => Err(e.into_lua_err()),
-                }
+            |_, this, close_frame: Option<mlua::Value>| async move {
+                this.send(Self::close_frame(close_frame)).await
             },
         );
+
+        methods.add_method("id", |_, this, ()| Ok(this.id.clone()));
+
+        methods.add_method("protocol", |_, this, ()| Ok(this.protocol.clone()));
+
+        methods.add_method("join", |_, this, room: String| {
+            Ok(hub::join(&this.id, &room))
+        });
+
+        methods.add_method("leave", |_, this, room: String| {
+            Ok(hub::leave(&this.id, &room))
+        });
+
+        methods.add_method("rooms", |_, this, ()| Ok(hub::rooms(&this.id)));
     }
 }
@@ -0,0 +1,103 @@
+use futures::{Sink, SinkExt, Stream, StreamExt};
+use std::{sync::Arc, time::Duration};
+
+/// The parts of a WebSocket message the server and the client handle alike, as axum and
+/// reqwest-websocket each come with their own message type.
+pub trait Frame: Send + 'static {
+    fn ping() -> Self;
+    /// Pings and pongs, which the protocol answers on its own
+    fn is_control(&self) -> bool;
+    /// Closes the connection with `1001 Going Away`
+    fn going_away(reason: &'static str) -> Self;
+}
+impl Frame for axum::extract::ws::Message {
+    fn ping() -> Self {
+        Self::Ping(bytes::Bytes::new())
+    }
+
+    fn is_control(&self) -> bool {
+        matches!(self, Self::Ping(_) | Self::Pong(_))
+    }
+
+    fn going_away(reason: &'static str) -> Self {
+        Self::Close(Some(axum::extract::ws::CloseFrame {
+            code: 1001,
+            reason: axum::extract::ws::Utf8Bytes::from_static(reason),
+        }))
+    }
+}
+impl Frame for reqwest_websocket::Message {
+    fn ping() -> Self {
+        Self::Ping(bytes::Bytes::new())
+    }
+
+    fn is_control(&self) -> bool {
+        matches!(self, Self::Ping(_) | Self::Pong(_))
+    }
+
+    fn going_away(reason: &'static str) -> Self {
+        Self::Close {
+            code: reqwest_websocket::CloseCode::Away,
+            reason: reason.to_string(),
+        }
+    }
+}
+
+/// Pings the peer at the interval for as long as it is polled, never finishing on its own.
+pub async fn keep_alive<S, M>(sink: Arc<tokio::sync::Mutex<S>>, interval: Option<Duration>)
+where
+    S: Sink<M> + Unpin,
+    M: Frame,
+{
+    if let Some(interval) = interval {
+        let mut ticker = tokio::time::interval_at(tokio::time::Instant::now() + interval, interval);
+        loop {
+            ticker.tick().await;
+            if sink.lock().await.send(M::ping()).await.is_err() {
+                break;
+            }
+        }
+    }
+
+    std::future::pending::<()>().await
+}
+
+/// Waits for the next message, leaving pings and pongs to the protocol. `None` once the
+/// connection has closed, or has been idle for longer than the idle timeout.
+pub async fn next_message<T, S, M, E>(
+    stream: &tokio::sync::Mutex<T>,
+    sink: &tokio::sync::Mutex<S>,
+    idle_timeout: Option<Duration>,
+) -> mlua::Result<Option<M>>
+where
+    T: Stream<Item = Result<M, E>> + Unpin,
+    S: Sink<M> + Unpin,
+    M: Frame,
+    E: std::fmt::Display,
+{
+    let mut stream = stream.lock().await;
+    loop {
+        let next = match idle_timeout {
+            Some(idle_timeout) => match tokio::time::timeout(idle_timeout, stream.next()).await {
+                Ok(next) => next,
+                Err(_) => {
+                    // the peer may not be there to read it anymore
+                    let _ = sink.lock().await.send(M::going_away("idle timeout")).await;
+                    return Ok(None);
+                }
+            },
+            None => stream.next().await,
+        };
+
+        match next {
+            Some(Ok(message)) if message.is_control() => continue,
+            Some(Ok(message)) => return Ok(Some(message)),
+            Some(Err(e)) => {
+                return Err(mlua::Error::runtime(format!(
+                    "failed to receive a frame: {e}",
+                )));
+            }
+            None => return Ok(None),
+        }
+    }
+}
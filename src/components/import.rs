@@ -1,5 +1,14 @@
 use crate::ASTRA_STD_LIBS;
-use std::path::{MAIN_SEPARATOR_STR, PathBuf};
+use std::{
+    collections::HashSet,
+    path::{MAIN_SEPARATOR_STR, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+/// Files on disk loaded through `require`, which `astra run --watch` reruns the script for
+/// when they change. Only tracked for VMs that have it in their app data.
+#[derive(Debug, Clone, Default)]
+pub struct RequiredFiles(pub Arc<Mutex<HashSet<PathBuf>>>);
 
 async fn import(lua: &mlua::Lua, key_id: &str, path: &str) -> mlua::Result<mlua::Value> {
     let current_script_path: String = lua.globals().get::<String>("CURRENT_SCRIPT")?;
@@ -11,6 +20,13 @@ async fn import(lua: &mlua::Lua, key_id: &str, path: &str) -> mlua::Result<mlua:
             .to_string_lossy()
             .to_string();
 
+        if let Some(required) = lua.app_data_ref::<RequiredFiles>()
+            && std::path::Path::new(&file_path).is_file()
+            && let Ok(mut files) = required.0.lock()
+        {
+            files.insert(PathBuf::from(&file_path));
+        }
+
         lua.globals().set("CURRENT_SCRIPT", file_path.clone())?;
         let result = lua
             .load(content)
@@ -142,6 +142,19 @@ pub(crate) fn is_table_byte_array(table: &mlua::Table) -> mlua::Result<bool> {
     Ok(true)
 }
 
+/// Takes the bytes of a string, or of a table holding a byte array.
+pub(crate) fn value_to_bytes(value: &mlua::Value) -> mlua::Result<bytes::Bytes> {
+    if let Some(table) = value.as_table() {
+        Ok(bytes::Bytes::from_iter(
+            table.sequence_values::<u8>().filter_map(|x| x.ok()),
+        ))
+    } else if let Some(string) = value.as_string() {
+        Ok(bytes::Bytes::from(string.as_bytes().to_vec()))
+    } else {
+        Err(mlua::Error::runtime("type cannot be accepted as bytes"))
+    }
+}
+
 #[allow(dead_code)]
 pub async fn read_from_stdlib(
     stdlib_path: &std::path::Path,
@@ -1,4 +1,3 @@
-use crate::LUA;
 use minijinja::{ErrorKind::UndefinedError, path_loader};
 use mlua::{ExternalError, FromLua, LuaSerdeExt, UserData};
 use std::sync::Arc;
@@ -26,6 +25,15 @@ pub fn register_to_lua(lua: &mlua::Lua) -> mlua::Result<()> {
                 templates: Vec::new(),
                 exclusions: Vec::new(),
             };
+            // the token of the request being handled, when the server has CSRF protection
+            engine.env.add_function("csrf_token", || {
+                super::http::server::csrf_token().ok_or_else(|| {
+                    minijinja::Error::new(
+                        minijinja::ErrorKind::InvalidOperation,
+                        "csrf_token() needs a request from a server with `csrf` enabled",
+                    )
+                })
+            });
 
             if let Some(dir) = dir {
                 let matches = super::file_system::GlobResult::parse_glob_pattern(&dir)?;
@@ -187,11 +195,13 @@ impl UserData for TemplatingEngine<'_> {
         methods.add_method_mut("reload_templates", |_, this, _: ()| this.reload_templates());
         methods.add_method_mut(
             "add_function",
-            |_, this, (name, func): (String, mlua::Function)| {
+            |lua, this, (name, func): (String, mlua::Function)| {
+                // the function lives in the VM, which would never be freed if it held on to it
+                let lua = lua.weak();
                 let function = move |args: minijinja::Value|
                                                                             -> Result<minijinja::Value, minijinja::Error> {
                     futures::executor::block_on(async {
-                      if let Some(lua) = LUA.get() {
+                      if let Some(lua) = lua.try_upgrade() {
                       let lua_value = lua.to_value(&args).map_err(|e| minijinja::Error::new(UndefinedError,
                               format!("ERROR TEMPLATE FUNCTION - Could not convert arguments into Lua table: {e}")))?;
 
@@ -7,9 +7,6 @@ use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
 mod commands;
 mod components;
 
-/// Global Lua instance.
-pub static LUA: std::sync::OnceLock<mlua::Lua> = std::sync::OnceLock::new();
-
 #[derive(Debug, Clone)]
 pub struct RuntimeFlags {
     pub stdlib_path: std::path::PathBuf,
@@ -50,6 +47,9 @@ enum AstraCLI {
         /// Enables safe mode by removing access to dangerous standard library and behaviors
         #[arg(long, action)]
         safe: bool,
+        /// Reruns the script when it or a module it requires changes, keeping the server sockets open
+        #[arg(short, long, action, conflicts_with = "code")]
+        watch: bool,
         /// Extra arguments to pass to the script.
         #[arg(trailing_var_arg = true, allow_hyphen_values = true)]
         extra_args: Option<Vec<String>>,
@@ -102,9 +102,8 @@ pub async fn main() -> std::io::Result<()> {
         if let Some(content) = commands::PACKED_FILES.get()
             && let Some(entry_code) = content.entries.get(&content.start.clone())
         {
-            create_lua_vm(true)?;
-
             commands::run_command(
+                create_lua_vm(true)?,
                 Some(content.start.clone()),
                 Some(entry_code.clone()),
                 None,
@@ -119,10 +118,21 @@ pub async fn main() -> std::io::Result<()> {
                 code,
                 stdlib_path,
                 safe,
+                watch,
                 extra_args,
             } => {
-                create_lua_vm(safe)?;
-                commands::run_command(file_path, code, stdlib_path, extra_args).await
+                if watch {
+                    commands::watch_command(file_path, stdlib_path, safe, extra_args).await?
+                } else {
+                    commands::run_command(
+                        create_lua_vm(safe)?,
+                        file_path,
+                        code,
+                        stdlib_path,
+                        extra_args,
+                    )
+                    .await
+                }
             }
             AstraCLI::Init { path } => commands::export_bundle_command(path).await?,
             AstraCLI::Upgrade { user_agent } => {
@@ -145,31 +155,14 @@ pub async fn main() -> std::io::Result<()> {
     Ok(())
 }
 
-fn create_lua_vm(is_safe: bool) -> std::io::Result<()> {
+pub fn create_lua_vm(is_safe: bool) -> std::io::Result<mlua::Lua> {
+    let options =
+        mlua::LuaOptions::new().thread_pool_size(std::thread::available_parallelism()?.get());
+
     if is_safe {
-        #[allow(clippy::expect_used)]
-        LUA.set(
-            #[allow(clippy::expect_used)]
-            mlua::Lua::new_with(
-                mlua::StdLib::ALL_SAFE,
-                mlua::LuaOptions::new()
-                    .thread_pool_size(std::thread::available_parallelism()?.get()),
-            )
-            .expect("Could not start the safe runtime"),
-        )
-        .expect("Could not set up the global VM");
+        mlua::Lua::new_with(mlua::StdLib::ALL_SAFE, options)
+            .map_err(|e| std::io::Error::other(format!("Could not start the safe runtime: {e}")))
     } else {
-        #[allow(clippy::expect_used)]
-        LUA.set(unsafe {
-            #[allow(clippy::expect_used)]
-            mlua::Lua::unsafe_new_with(
-                mlua::StdLib::ALL,
-                mlua::LuaOptions::new()
-                    .thread_pool_size(std::thread::available_parallelism()?.get()),
-            )
-        })
-        .expect("Could not set up the global VM");
+        Ok(unsafe { mlua::Lua::unsafe_new_with(mlua::StdLib::ALL, options) })
     }
-
-    Ok(())
 }
@@ -1,5 +1,7 @@
 local database = require("database")
 local fs = require("fs")
+local http = require("http")
+local utils = require("utils")
 require("test")
 
 ---@param test Test
@@ -220,4 +222,76 @@ return function(test)
       end).to.fail()
     end)
   end)
+
+  describe("Session Store", function()
+    local db_path = "tests/_tmp/sessions.db"
+    local port = 18085
+    local db
+    local server
+
+    test.before(function()
+      pcall(fs.create_dir, "tests/_tmp")
+      db = database.new("sqlite", db_path)
+      server = http.server.new()
+      server.port = port
+      server.session = { store = db, table = "web_sessions" }
+      server:post("/login", function(request)
+        local session = request:session()
+        session:regenerate()
+        session.user = "alice"
+      end)
+      server:get("/me", function(request)
+        return request:session().user or "none"
+      end)
+      server:post("/logout", function(request)
+        request:session():destroy()
+      end)
+      utils.spawn_task(function()
+        server:run()
+      end)
+      utils.spawn_timeout(function() end, 150):await()
+    end)
+
+    test.after(function()
+      server:shutdown(server)
+      utils.spawn_timeout(function() end, 10):await()
+      db:close()
+      fs.remove(db_path)
+    end)
+
+    local function send(method, path, cookie)
+      return http
+        .request({
+          url = "http://127.0.0.1:" .. port .. path,
+          method = method,
+          headers = cookie and { Cookie = cookie } or nil,
+        })
+        :execute()
+    end
+
+    it("keeps sessions in an SQLite table", function()
+      local cookie = send("POST", "/login"):headers()["set-cookie"]:match("^([^;]+)")
+      expect(send("GET", "/me", cookie):body():text()).to.equal("alice")
+
+      local id = cookie:match("=(%x+)$")
+      local row = db:query_one("SELECT data FROM web_sessions WHERE id = ?", { id })
+      expect(row).to.exist()
+      expect(row.data).to.equal('{"user":"alice"}')
+
+      send("POST", "/logout", cookie)
+      expect(send("GET", "/me", cookie):body():text()).to.equal("none")
+      expect(db:query_one("SELECT data FROM web_sessions WHERE id = ?", { id })).to.equal(nil)
+    end)
+
+    it("fails to start when the connection is closed", function()
+      local closed = database.new("sqlite", ":memory:")
+      closed:close()
+      local failing = http.server.new()
+      failing.port = 18448
+      failing.session = { store = closed }
+      expect(function()
+        failing:run()
+      end).to.fail()
+    end)
+  end)
 end
@@ -9,6 +9,26 @@ return function(test)
   local it = test.it
   local expect = test.expect
 
+  -- runs curl in the background, since blocking on it would keep the server in this process
+  -- from answering, and returns the status code and body
+  local function curl(args)
+    local out = os.tmpname()
+    os.remove(out)
+    os.execute("(curl -s -w '\\n%{http_code}' " .. args .. " > " .. out .. ".part; mv " .. out .. ".part " .. out .. ") &")
+    for _ = 1, 250 do
+      local file = io.open(out)
+      if file then
+        local response = file:read("*a")
+        file:close()
+        os.remove(out)
+        local body, status = response:match("^(.*)\n(%d+)$")
+        return tonumber(status), body
+      end
+      utils.spawn_timeout(function() end, 20):await()
+    end
+    error("curl " .. args .. " did not finish")
+  end
+
   -------------------------------------------------------------------------------
   -- HTTP Status Codes
   -------------------------------------------------------------------------------
@@ -211,6 +231,19 @@ return function(test)
         expect(server.routes[3].method).to.equal("trace")
       end)
 
+      it("registers HEAD, CONNECT, any and multi-method routes", function()
+        local server = http.server.new()
+        server:head("/h", function() end)
+        server:connect("/c", function() end)
+        server:any("/a", function() end)
+        server:route("GET", "/r", function() end)
+        expect(server.routes[1].method).to.equal("head")
+        expect(server.routes[2].method).to.equal("connect")
+        expect(server.routes[3].method).to.equal("any")
+        expect(server.routes[4].method).to.equal("route")
+        expect(server.routes[4].methods[1]).to.equal("GET")
+      end)
+
       it("prepends root path to the front of routes", function()
         local server = http.server.new()
         server:get("/users", function() end)
@@ -260,6 +293,42 @@ return function(test)
         end)
       end
 
+      it("fails to start when the TLS certificate is missing", function()
+        local server = http.server.new()
+        server.port = 18443
+        server.tls = { cert = "tests/_missing_cert.pem", key = "tests/_missing_key.pem" }
+        expect(function()
+          server:run()
+        end).to.fail()
+      end)
+
+      it("fails to start when CORS allows any origin with credentials", function()
+        local server = http.server.new()
+        server.port = 18444
+        server.cors = { credentials = true }
+        expect(function()
+          server:run()
+        end).to.fail()
+      end)
+
+      it("fails to start with an invalid timeout", function()
+        local server = http.server.new()
+        server.port = 18445
+        server.timeout = -1
+        expect(function()
+          server:run()
+        end).to.fail()
+      end)
+
+      it("fails to start with an unknown path parameter type", function()
+        local server = http.server.new()
+        server.port = 18446
+        server:get("/users/{id:color}", function() end)
+        expect(function()
+          server:run()
+        end).to.fail()
+      end)
+
       it("stores route configuration", function()
         local server = http.server.new()
         server:get("/upload", function() end, {
@@ -289,10 +358,21 @@ return function(test)
       fs.create_dir(tmp_dir)
       fs.write_file(tmp_dir .. "/hello.txt", "Hello, World!")
       fs.write_file(tmp_dir .. "/data.json", '{"key": "value"}')
+      fs.create_dir_all(tmp_dir .. "/app/assets")
+      fs.write_file(tmp_dir .. "/app/index.html", "<p>app</p>")
+      fs.write_file(tmp_dir .. "/app/404.html", "<p>missing</p>")
+      fs.write_file(tmp_dir .. "/app/assets/main.3f2a9c1d.js", "console.log(1)")
+      fs.write_file(tmp_dir .. "/app/assets/main.3f2a9c1d.js.br", "compressed")
+      fs.create_dir_all(tmp_dir .. "/app/assets/nested")
 
       -- Create and configure server
       server = http.server.new()
       server.port = port
+      server.cookie_key = {
+        "a-fresh-secret-that-is-at-least-32-bytes",
+        "an-older-secret-that-is-at-least-32-bytes",
+      }
+      server.cors = { origins = { "https://example.com" }, max_age = 600 }
 
       server:get("/ping", function()
         return "pong"
@@ -309,6 +389,38 @@ return function(test)
         return { id = request:params().id }
       end)
 
+      server:get("/typed/{id:int}/{flag:bool}", function(request)
+        local params = request:params()
+        return { id = params.id, flag = params.flag, kind = type(params.id) }
+      end)
+
+      server:get("/tokens/{token:uuid}", function(request)
+        return request:params().token
+      end)
+
+      server:get("/codes/{code}", function(request)
+        return { code = request:params().code }
+      end)
+
+      server:get("/posts/{year:int}", function(request)
+        return { year = request:params().year }
+      end, { params = require("validation").types.struct({ year = require("validation").types.range({ min = 2000 }) }) })
+
+      local t = require("validation").types
+      server:post("/validated", function(request)
+        return { name = request:body():json().name, page = request:queries().page }
+      end, {
+        body_schema = t.struct({ name = t.string(), age = t.integer(), tags = t.optional(t.array(t.string())) }),
+        query_schema = t.struct({ page = t.pattern("^%d+$") }),
+      })
+
+      server:group("/shops/{shop:int}", function(shop)
+        shop:get("/items/{item}", function(request)
+          local params = request:params()
+          return { shop = params.shop, item = params.item }
+        end)
+      end)
+
       server:get("/search", function(request)
         return { q = request:queries().q }
       end)
@@ -318,6 +430,18 @@ return function(test)
         return { received = body }
       end)
 
+      server:post("/data-stream", function(request)
+        local chunks = {}
+        for chunk in request:body_stream() do
+          table.insert(chunks, chunk)
+        end
+        return { received = table.concat(chunks) }
+      end)
+
+      server:post("/limited", function(request)
+        return request:body():text()
+      end, { body_limit = 8 })
+
       server:get("/echo-header", function(request)
         local headers = request:headers()
         return { x_test = headers["X-Test"] }
@@ -337,7 +461,211 @@ return function(test)
         return "ok"
       end)
 
+      server:get("/multi-cookie", function(request, response)
+        response:set_cookie(request:new_cookie("a", "1"))
+        response:set_cookie(request:new_cookie("b", "2"))
+        return { ok = true }
+      end)
+
+      server:get("/public", function()
+        return "public"
+      end, { cors = { origins = { "*" } } })
+
+      server:get("/throttled", function()
+        return "ok"
+      end, { rate_limit = { requests = 2, window = 60 } })
+
+      server:get("/throttled-by-header", function()
+        return "ok"
+      end, { rate_limit = { requests = 1, window = 60, header = "X-Api-Key" } })
+
+      server:get("/throttled-by-key", function()
+        return "ok"
+      end, {
+        rate_limit = {
+          requests = 1,
+          window = 60,
+          key = function(request)
+            return request:headers()["x-user"]
+          end,
+        },
+      })
+
+      server:get("/slow", function()
+        utils.spawn_timeout(function() end, 1000):await()
+        return "too late"
+      end, { timeout = 0.1 })
+
+      server:get("/deadline", function(request)
+        return { remaining = request:time_remaining() }
+      end, { timeout = 5 })
+
+      server:get("/cached", function(_request, response)
+        response:set_header("Cache-Control", "max-age=60")
+        return "cached"
+      end)
+
+      server:get("/explode", function()
+        error("kaboom")
+      end)
+
+      server:get("/explode-debug", function()
+        error("<kaboom>")
+      end)
+
+      server.development = true
+      server:on_error(function(err, request, response)
+        if request:uri() ~= "/explode" then
+          error(err)
+        end
+        response:set_header("X-Error", "handled")
+        return { error = err }
+      end)
+
+      server:group("/api/v1", { headers = { ["X-Group"] = "v1" } }, function(api)
+        api:before(function(_request, response)
+          response:set_header("X-Group-Middleware", "yes")
+        end)
+
+        api:get("/items", function()
+          return { "a", "b" }
+        end)
+
+        api:get("/items/{id}", function(request)
+          return { id = request:params().id }
+        end, { headers = { ["X-Group"] = "override" } })
+
+        api:group("/admin", function(admin)
+          admin:get("/stats", function()
+            return "stats"
+          end)
+        end)
+
+        api:fallback(function(_request, response)
+          response:set_status_code(http.status_codes.NOT_FOUND)
+          return "no such api"
+        end)
+      end)
+
+      server:head("/head-only", function(_request, response)
+        response:set_header("X-Head", "yes")
+      end)
+
+      server:any("/any-method", function(request)
+        return request:method()
+      end)
+
+      server:route({ "get", "POST" }, "/some-methods", function(request)
+        return request:method()
+      end)
+
+      server:route({ "GET", "PURGE" }, "/custom-method", function(request)
+        return request:method()
+      end)
+
+      server:get("/set-signed-cookie", function(request, response)
+        response:set_signed_cookie(request:new_cookie("signed", "hello"))
+        return "ok"
+      end)
+
+      server:get("/set-private-cookie", function(request, response)
+        response:set_private_cookie(request:new_cookie("private", "secret"))
+        return "ok"
+      end)
+
+      server:get("/read-cookies", function(request)
+        local signed = request:get_signed_cookie("signed")
+        local private = request:get_private_cookie("private")
+        return {
+          signed = signed and signed:get_value() or "none",
+          private = private and private:get_value() or "none",
+        }
+      end)
+
+      server:get("/stream", function(_request, response)
+        response:set_header("Content-Type", "text/plain")
+        response:stream(function(stream)
+          for i = 1, 3 do
+            stream:write("chunk" .. i .. ";")
+          end
+        end)
+      end)
+
+      server:get("/events", function(_request, response)
+        response:sse(function(sse)
+          sse:send({ event = "tick", id = "1", data = "first" })
+          sse:send({ data = { count = 2 } })
+          sse:send("plain")
+        end, { keep_alive = false })
+      end)
+
       server:static_dir("/files", tmp_dir)
+      server:post("/upload", function(request)
+        local upload = request:multipart_stream({
+          max_field_size = 64,
+          field_sizes = { big = 1024 },
+          content_types = { "text/*" },
+        })
+        local result = {}
+        local field = upload:next_field()
+        while field do
+          if field:name() == "file" or field:name() == "big" then
+            result[field:name()] = field:save_file(tmp_dir .. "/upload-" .. field:name() .. ".txt")
+          elseif field:name() == "note" then
+            result.note = field:text()
+          end
+          field = upload:next_field()
+        end
+        return result
+      end)
+
+      server:post("/upload-buffered", function(request)
+        return request:multipart():save_file(tmp_dir .. "/buffered.txt", "second")
+      end)
+      server:post("/upload-named", function(request)
+        local ok, result = pcall(function()
+          return request:multipart():save_file(tmp_dir .. "/uploads")
+        end)
+        return ok and result or "rejected"
+      end)
+
+      server:get("/download", function(_request, response)
+        response:send_file(tmp_dir .. "/hello.txt", { filename = "grüße.txt" })
+      end)
+      server:post("/download-inline", function(_request, response)
+        response:send_file(tmp_dir .. "/data.json", { attachment = false, content_type = "text/plain" })
+      end)
+      server:static_dir("/spa", tmp_dir .. "/app", {
+        spa = true,
+        precompressed = true,
+        cache_control = "no-cache",
+        immutable = "\\.[0-9a-f]{8}\\.",
+      })
+      server:static_dir("/site", tmp_dir .. "/app", { not_found = "404.html", listing = true })
+      server:static_file("/hello", tmp_dir .. "/hello.txt", { headers = { ["Cache-Control"] = "no-store" } })
+
+      server:after(function(_request, response)
+        response:set_header("X-After", "yes")
+      end)
+
+      server:before(function(request, response)
+        if request:uri() == "/multi-cookie" then
+          response:set_header("Set-Cookie", "c=3")
+          response:set_header("Content-Type", "text/plain")
+        end
+        if request:uri() == "/blocked" then
+          response:set_status_code(http.status_codes.FORBIDDEN)
+          return "blocked"
+        end
+      end)
+
+      server:use(function(request, response, next)
+        if request:headers()["x-skip"] then
+          return "skipped"
+        end
+        next()
+        response:set_header("X-Status", tostring(response:get_status_code()))
+      end)
 
       server:fallback(function(_request, response)
         response:set_status_code(http.status_codes.NOT_FOUND)
@@ -355,6 +683,8 @@ return function(test)
     test.after(function()
       -- Shutdown server and clean up temp files
       server:shutdown(server)
+      -- give the server a chance to release the port before the next test binds it again
+      utils.spawn_timeout(function() end, 10):await()
       fs.remove_dir_all(tmp_dir)
     end)
 
@@ -380,6 +710,97 @@ return function(test)
       expect(body.id).to.equal(42)
     end)
 
+    it("converts typed path parameters and rejects mismatches", function()
+      local res = http.request({ url = "http://127.0.0.1:" .. port .. "/typed/12/true", method = "GET" }):execute()
+      expect(res:status_code()).to.equal(200)
+      local body = res:body():json()
+      expect(body.id).to.equal(12)
+      expect(body.flag).to.equal(true)
+      expect(body.kind).to.equal("number")
+
+      res = http.request({ url = "http://127.0.0.1:" .. port .. "/typed/abc/true", method = "GET" }):execute()
+      expect(res:status_code()).to.equal(404)
+      res = http.request({ url = "http://127.0.0.1:" .. port .. "/typed/12/maybe", method = "GET" }):execute()
+      expect(res:status_code()).to.equal(404)
+
+      local token = "67e55044-10b1-426f-9247-bb680e5fe0c8"
+      res = http.request({ url = "http://127.0.0.1:" .. port .. "/tokens/" .. token, method = "GET" }):execute()
+      expect(res:body():text()).to.equal(token)
+      res = http.request({ url = "http://127.0.0.1:" .. port .. "/tokens/not-a-uuid", method = "GET" }):execute()
+      expect(res:status_code()).to.equal(404)
+    end)
+
+    it("keeps untyped parameters that are not plain numbers as strings", function()
+      local res = http.request({ url = "http://127.0.0.1:" .. port .. "/codes/007", method = "GET" }):execute()
+      expect(res:body():json().code).to.equal("007")
+    end)
+
+    it("does not round integers beyond 2^53 on versions without 64 bit integers", function()
+      local native_integers = math.type ~= nil
+      local res = http.request({ url = "http://127.0.0.1:" .. port .. "/codes/9007199254740993", method = "GET" }):execute()
+      expect(res:body():text()).to.equal(
+        native_integers and '{"code":9007199254740993}' or '{"code":"9007199254740993"}'
+      )
+
+      res = http.request({ url = "http://127.0.0.1:" .. port .. "/typed/9007199254740993/true", method = "GET" }):execute()
+      expect(res:status_code()).to.equal(native_integers and 200 or 404)
+      res = http.request({ url = "http://127.0.0.1:" .. port .. "/typed/9007199254740992/true", method = "GET" }):execute()
+      expect(res:status_code()).to.equal(200)
+    end)
+
+    it("validates path parameters against a schema", function()
+      local res = http.request({ url = "http://127.0.0.1:" .. port .. "/posts/2024", method = "GET" }):execute()
+      expect(res:body():json().year).to.equal(2024)
+
+      res = http.request({ url = "http://127.0.0.1:" .. port .. "/posts/1999", method = "GET" }):execute()
+      expect(res:status_code()).to.equal(400)
+      expect(res:body():text():find("year", 1, true) ~= nil).to.be.truthy()
+    end)
+
+    it("validates the body and query string against schemas", function()
+      local res = http.request({
+        url = "http://127.0.0.1:" .. port .. "/validated?page=2",
+        method = "POST",
+        headers = { ["Content-Type"] = "application/json" },
+        body = '{"name": "astra", "age": 3, "tags": null}',
+      }):execute()
+      expect(res:status_code()).to.equal(200)
+      expect(res:body():json().name).to.equal("astra")
+
+      res = http.request({
+        url = "http://127.0.0.1:" .. port .. "/validated?page=two",
+        method = "POST",
+        headers = { ["Content-Type"] = "application/json" },
+        body = '{"name": 1, "tags": ["a", 2]}',
+      }):execute()
+      expect(res:status_code()).to.equal(422)
+      local errors = res:body():json().errors
+      expect(#errors).to.equal(4)
+      expect(errors[1].location).to.equal("query")
+      expect(errors[1].path).to.equal("page")
+      expect(errors[2].path).to.equal("age")
+      expect(errors[3].path).to.equal("name")
+      expect(errors[4].path).to.equal("tags[2]")
+
+      res = http.request({
+        url = "http://127.0.0.1:" .. port .. "/validated?page=1",
+        method = "POST",
+        body = "not json",
+      }):execute()
+      expect(res:status_code()).to.equal(400)
+      expect(res:body():json().errors[1].location).to.equal("body")
+    end)
+
+    it("applies the parameter types of a group prefix", function()
+      local res = http.request({ url = "http://127.0.0.1:" .. port .. "/shops/3/items/hat", method = "GET" }):execute()
+      local body = res:body():json()
+      expect(body.shop).to.equal(3)
+      expect(body.item).to.equal("hat")
+
+      res = http.request({ url = "http://127.0.0.1:" .. port .. "/shops/three/items/hat", method = "GET" }):execute()
+      expect(res:status_code()).to.equal(404)
+    end)
+
     it("handles query string parameters", function()
       local req = http.request({
         url = "http://127.0.0.1:" .. port .. "/search?q=hello+world",
@@ -401,6 +822,34 @@ return function(test)
       expect(body.received).to.equal("test payload")
     end)
 
+    it("streams the request body in chunks", function()
+      local req = http.request({
+        url = "http://127.0.0.1:" .. port .. "/data-stream",
+        method = "POST",
+        body = "streamed payload",
+      })
+      local res = req:execute()
+      local body = res:body():json()
+      expect(body.received).to.equal("streamed payload")
+    end)
+
+    it("rejects bodies over the route body limit", function()
+      local small = http.request({
+        url = "http://127.0.0.1:" .. port .. "/limited",
+        method = "POST",
+        body = "tiny",
+      }):execute()
+      expect(small:status_code()).to.equal(200)
+      expect(small:body():text()).to.equal("tiny")
+
+      local large = http.request({
+        url = "http://127.0.0.1:" .. port .. "/limited",
+        method = "POST",
+        body = "way past the limit",
+      }):execute()
+      expect(large:status_code()).to.equal(413)
+    end)
+
     it("echoes custom request headers", function()
       local req = http.request({ url = "http://127.0.0.1:" .. port .. "/echo-header", method = "GET" })
       req:set_header("X-Test", "custom-value")
@@ -430,6 +879,15 @@ return function(test)
       expect(res:body():text()).to.equal("Hello, World!")
     end)
 
+    it("keeps the headers of static files to their own route", function()
+      local res = http.request({ url = "http://127.0.0.1:" .. port .. "/hello", method = "GET" }):execute()
+      expect(res:body():text()).to.equal("Hello, World!")
+      expect(res:headers()["cache-control"]).to.equal("no-store")
+
+      res = http.request({ url = "http://127.0.0.1:" .. port .. "/cached", method = "GET" }):execute()
+      expect(res:headers()["cache-control"]).to.equal("max-age=60")
+    end)
+
     it("serves static JSON files", function()
       local req = http.request({ url = "http://127.0.0.1:" .. port .. "/files/data.json", method = "GET" })
       local res = req:execute()
@@ -437,11 +895,1581 @@ return function(test)
       expect(body.key).to.equal("value")
     end)
 
-    it("sets cookies on response", function()
-      local req = http.request({ url = "http://127.0.0.1:" .. port .. "/set-cookie", method = "GET" })
-      local res = req:execute()
-      local headers = res:headers()
-      expect(headers).to.be.a("table")
+    it("falls back to the index of single page apps", function()
+      local res = http.request({ url = "http://127.0.0.1:" .. port .. "/spa/some/client/route", method = "GET" }):execute()
+      expect(res:status_code()).to.equal(200)
+      expect(res:body():text()).to.equal("<p>app</p>")
+      expect(res:headers()["cache-control"]).to.equal("no-cache")
+    end)
+
+    it("serves precompressed and immutable static assets", function()
+      local res = http.request({
+        url = "http://127.0.0.1:" .. port .. "/spa/assets/main.3f2a9c1d.js",
+        method = "GET",
+        headers = { ["Accept-Encoding"] = "br" },
+      }):execute()
+      expect(res:status_code()).to.equal(200)
+      expect(res:headers()["content-encoding"]).to.equal("br")
+      expect(res:body():text()).to.equal("compressed")
+      expect(res:headers()["cache-control"]).to.equal("public, max-age=31536000, immutable")
+
+      res = http.request({ url = "http://127.0.0.1:" .. port .. "/spa/assets/main.3f2a9c1d.js", method = "GET" }):execute()
+      expect(res:headers()["content-encoding"]).to.equal(nil)
+      expect(res:body():text()).to.equal("console.log(1)")
+    end)
+
+    it("serves a custom 404 page and directory listings", function()
+      local res = http.request({ url = "http://127.0.0.1:" .. port .. "/site/nope.html", method = "GET" }):execute()
+      expect(res:status_code()).to.equal(404)
+      expect(res:body():text()).to.equal("<p>missing</p>")
+
+      res = http.request({ url = "http://127.0.0.1:" .. port .. "/site/assets/", method = "GET" }):execute()
+      expect(res:status_code()).to.equal(200)
+      local listing = res:body():text()
+      expect(listing:find("main.3f2a9c1d.js", 1, true) ~= nil).to.be.truthy()
+      expect(listing:find('href="nested/"', 1, true) ~= nil).to.be.truthy()
+
+      res = http.request({ url = "http://127.0.0.1:" .. port .. "/site/../hello.txt", method = "GET" }):execute()
+      expect(res:status_code()).to.equal(404)
+    end)
+
+    local function multipart_body(parts)
+      local body = {}
+      for _, part in ipairs(parts) do
+        local disposition = 'form-data; name="' .. part.name .. '"'
+        if part.file_name then
+          disposition = disposition .. '; filename="' .. part.file_name .. '"'
+        end
+        table.insert(body, "--XBOUNDARY\r\nContent-Disposition: " .. disposition .. "\r\n")
+        if part.content_type then
+          table.insert(body, "Content-Type: " .. part.content_type .. "\r\n")
+        end
+        table.insert(body, "\r\n" .. part.data .. "\r\n")
+      end
+      table.insert(body, "--XBOUNDARY--\r\n")
+      return table.concat(body)
+    end
+
+    local function upload(path, parts)
+      return http.request({
+        url = "http://127.0.0.1:" .. port .. path,
+        method = "POST",
+        headers = { ["Content-Type"] = "multipart/form-data; boundary=XBOUNDARY" },
+        body = multipart_body(parts),
+      }):execute()
+    end
+
+    it("streams multipart uploads field by field", function()
+      local res = upload("/upload", {
+        { name = "note", data = "hello" },
+        { name = "skipped", data = "ignored" },
+        { name = "file", file_name = "a.txt", content_type = "text/plain", data = "file contents" },
+        { name = "big", file_name = "b.txt", content_type = "text/plain", data = string.rep("x", 100) },
+      })
+      expect(res:status_code()).to.equal(200)
+      local body = res:body():json()
+      expect(body.note).to.equal("hello")
+      expect(body.file).to.equal(13)
+      expect(body.big).to.equal(100)
+      expect(fs.read_file(tmp_dir .. "/upload-file.txt")).to.equal("file contents")
+    end)
+
+    it("enforces multipart size and content type limits", function()
+      local res = upload("/upload", {
+        { name = "file", file_name = "a.txt", content_type = "text/plain", data = string.rep("x", 100) },
+      })
+      expect(res:status_code()).to.equal(413)
+      expect(fs.exists(tmp_dir .. "/upload-file.txt")).to.equal(false)
+
+      res = upload("/upload", {
+        { name = "file", file_name = "a.png", content_type = "image/png", data = "png" },
+      })
+      expect(res:status_code()).to.equal(415)
+    end)
+
+    it("saves only the chosen multipart field", function()
+      local res = upload("/upload-buffered", {
+        { name = "first", file_name = "first.txt", content_type = "text/plain", data = "one" },
+        { name = "second", file_name = "second.txt", content_type = "text/plain", data = "two" },
+      })
+      expect(res:status_code()).to.equal(200)
+      expect(fs.read_file(tmp_dir .. "/buffered.txt")).to.equal("two")
+    end)
+
+    it("keeps uploads named by the client inside of the directory", function()
+      fs.create_dir_all(tmp_dir .. "/uploads")
+      local res = upload("/upload-named", {
+        { name = "file", file_name = "../../escaped.txt", content_type = "text/plain", data = "one" },
+      })
+      expect(res:status_code()).to.equal(200)
+      expect(res:body():text()).to.equal(tmp_dir .. "/uploads/escaped.txt")
+      expect(fs.read_file(tmp_dir .. "/uploads/escaped.txt")).to.equal("one")
+      expect(fs.exists(tmp_dir .. "/escaped.txt")).to.equal(false)
+      expect(fs.exists("tests/escaped.txt")).to.equal(false)
+
+      res = upload("/upload-named", {
+        { name = "file", file_name = "..", content_type = "text/plain", data = "two" },
+      })
+      expect(res:body():text()).to.equal("rejected")
+    end)
+
+    it("sends files from handlers", function()
+      local res = http.request({ url = "http://127.0.0.1:" .. port .. "/download", method = "GET" }):execute()
+      expect(res:status_code()).to.equal(200)
+      expect(res:body():text()).to.equal("Hello, World!")
+      expect(res:headers()["content-type"]:find("text/plain", 1, true) ~= nil).to.be.truthy()
+      expect(res:headers()["content-disposition"]).to.equal(
+        "attachment; filename=\"gr__e.txt\"; filename*=UTF-8''gr%C3%BC%C3%9Fe%2Etxt"
+      )
+      local etag = res:headers()["etag"]
+      expect(etag ~= nil).to.be.truthy()
+
+      res = http.request({
+        url = "http://127.0.0.1:" .. port .. "/download",
+        method = "GET",
+        headers = { ["Range"] = "bytes=0-4" },
+      }):execute()
+      expect(res:status_code()).to.equal(206)
+      expect(res:body():text()).to.equal("Hello")
+
+      res = http.request({
+        url = "http://127.0.0.1:" .. port .. "/download",
+        method = "GET",
+        headers = { ["If-None-Match"] = etag },
+      }):execute()
+      expect(res:status_code()).to.equal(304)
+
+      res = http.request({ url = "http://127.0.0.1:" .. port .. "/download-inline", method = "POST" }):execute()
+      expect(res:status_code()).to.equal(200)
+      expect(res:headers()["content-type"]).to.equal("text/plain")
+      expect(res:headers()["content-disposition"]:sub(1, 7)).to.equal("inline;")
+    end)
+
+    it("runs server middleware around routes and static files", function()
+      local res = http.request({ url = "http://127.0.0.1:" .. port .. "/ping", method = "GET" }):execute()
+      expect(res:body():text()).to.equal("pong")
+      expect(res:headers()["x-after"]).to.equal("yes")
+      expect(res:headers()["x-status"]).to.equal("200")
+
+      res = http.request({ url = "http://127.0.0.1:" .. port .. "/files/hello.txt", method = "GET" }):execute()
+      expect(res:body():text()).to.equal("Hello, World!")
+      expect(res:headers()["x-after"]).to.equal("yes")
+
+      res = http.request({ url = "http://127.0.0.1:" .. port .. "/missing", method = "GET" }):execute()
+      expect(res:status_code()).to.equal(404)
+      expect(res:headers()["x-status"]).to.equal("404")
+    end)
+
+    it("keeps every header set by the handler and the server middleware", function()
+      local status, response = curl("-i http://127.0.0.1:" .. port .. "/multi-cookie")
+      expect(status).to.equal(200)
+      response = response:lower()
+      for _, cookie in ipairs({ "a=1", "b=2", "c=3" }) do
+        expect(response:find("set-cookie: " .. cookie, 1, true) ~= nil).to.be.truthy()
+      end
+      expect(response:find("x-after: yes", 1, true) ~= nil).to.be.truthy()
+    end)
+
+    it("replaces the headers of the handler that the server middleware sets again", function()
+      local status, response = curl("-i http://127.0.0.1:" .. port .. "/multi-cookie")
+      expect(status).to.equal(200)
+      local _, content_types = response:lower():gsub("content%-type:", "")
+      expect(content_types).to.equal(1)
+      expect(response:lower():find("content-type: text/plain", 1, true) ~= nil).to.be.truthy()
+    end)
+
+    it("applies the body limit of the server to server middleware", function()
+      local server = http.server.new()
+      server.port = 0
+      server.body_limit = 16
+      server:before(function(request)
+        request:body()
+      end)
+      server:post("/echo", function(request)
+        return request:body():text()
+      end)
+      local server_task = utils.spawn_task(function()
+        server:run()
+      end)
+      utils.spawn_timeout(function() end, 150):await()
+
+      local url = "http://127.0.0.1:" .. server.port .. "/echo"
+      local res = http.request({ url = url, method = "POST", body = "small" }):execute()
+      expect(res:status_code()).to.equal(200)
+      expect(res:body():text()).to.equal("small")
+
+      res = http.request({ url = url, method = "POST", body = string.rep("x", 64) }):execute()
+      expect(res:status_code()).to.equal(413)
+
+      server:shutdown(server)
+      server_task:await()
+    end)
+
+    it("short-circuits requests from server middleware", function()
+      local res = http.request({ url = "http://127.0.0.1:" .. port .. "/blocked", method = "GET" }):execute()
+      expect(res:status_code()).to.equal(403)
+      expect(res:body():text()).to.equal("blocked")
+      expect(res:headers()["x-after"]).to.equal("yes")
+
+      res = http
+        .request({ url = "http://127.0.0.1:" .. port .. "/ping", method = "GET", headers = { ["X-Skip"] = "1" } })
+        :execute()
+      expect(res:body():text()).to.equal("skipped")
+      expect(res:headers()["x-status"]).to.equal(nil)
+    end)
+
+    it("answers CORS preflight requests", function()
+      local res = http
+        .request({
+          url = "http://127.0.0.1:" .. port .. "/ping",
+          method = "OPTIONS",
+          headers = { Origin = "https://example.com", ["Access-Control-Request-Method"] = "GET" },
+        })
+        :execute()
+      expect(res:status_code()).to.equal(200)
+      expect(res:headers()["access-control-allow-origin"]).to.equal("https://example.com")
+      expect(res:headers()["access-control-max-age"]).to.equal("600")
+    end)
+
+    it("only allows the configured CORS origins", function()
+      local res = http
+        .request({ url = "http://127.0.0.1:" .. port .. "/ping", method = "GET", headers = { Origin = "https://example.com" } })
+        :execute()
+      expect(res:headers()["access-control-allow-origin"]).to.equal("https://example.com")
+
+      res = http
+        .request({ url = "http://127.0.0.1:" .. port .. "/ping", method = "GET", headers = { Origin = "https://evil.dev" } })
+        :execute()
+      expect(res:headers()["access-control-allow-origin"]).to.equal(nil)
+
+      res = http
+        .request({ url = "http://127.0.0.1:" .. port .. "/public", method = "GET", headers = { Origin = "https://evil.dev" } })
+        :execute()
+      expect(res:headers()["access-control-allow-origin"]).to.equal("*")
+    end)
+
+    it("rate limits routes by IP address", function()
+      local function get()
+        return http.request({ url = "http://127.0.0.1:" .. port .. "/throttled", method = "GET" }):execute()
+      end
+      expect(get():status_code()).to.equal(200)
+      expect(get():status_code()).to.equal(200)
+      local res = get()
+      expect(res:status_code()).to.equal(429)
+      expect(tonumber(res:headers()["retry-after"]) >= 1).to.be.truthy()
+    end)
+
+    it("rate limits routes by header and key function", function()
+      local function get(path, headers)
+        return http
+          .request({ url = "http://127.0.0.1:" .. port .. path, method = "GET", headers = headers })
+          :execute()
+          :status_code()
+      end
+      expect(get("/throttled-by-header", { ["X-Api-Key"] = "a" })).to.equal(200)
+      expect(get("/throttled-by-header", { ["X-Api-Key"] = "a" })).to.equal(429)
+      expect(get("/throttled-by-header", { ["X-Api-Key"] = "b" })).to.equal(200)
+
+      expect(get("/throttled-by-key", { ["X-User"] = "astra" })).to.equal(200)
+      expect(get("/throttled-by-key", { ["X-User"] = "astra" })).to.equal(429)
+      -- no key means the request is not limited
+      expect(get("/throttled-by-key", {})).to.equal(200)
+      expect(get("/throttled-by-key", {})).to.equal(200)
+    end)
+
+    it("cuts off handlers that go over their timeout", function()
+      local res = http.request({ url = "http://127.0.0.1:" .. port .. "/slow", method = "GET" }):execute()
+      expect(res:status_code()).to.equal(504)
+    end)
+
+    it("exposes the remaining time to the handler", function()
+      local res = http.request({ url = "http://127.0.0.1:" .. port .. "/deadline", method = "GET" }):execute()
+      local remaining = res:body():json().remaining
+      expect(remaining > 0 and remaining <= 5).to.be.truthy()
+    end)
+
+    it("renders handler errors through on_error", function()
+      local res = http.request({ url = "http://127.0.0.1:" .. port .. "/explode", method = "GET" }):execute()
+      expect(res:status_code()).to.equal(500)
+      expect(res:headers()["x-error"]).to.equal("handled")
+      expect(res:body():json().error:find("kaboom", 1, true) ~= nil).to.be.truthy()
+    end)
+
+    it("shows a debug page in development mode", function()
+      local res = http.request({ url = "http://127.0.0.1:" .. port .. "/explode-debug", method = "GET" }):execute()
+      expect(res:status_code()).to.equal(500)
+      expect(res:headers()["content-type"]:find("text/html", 1, true) ~= nil).to.be.truthy()
+      local text = res:body():text()
+      expect(text:find("&lt;kaboom&gt;", 1, true) ~= nil).to.be.truthy()
+      expect(text:find("/explode-debug", 1, true) ~= nil).to.be.truthy()
+    end)
+
+    it("serves routes registered in groups", function()
+      local res = http.request({ url = "http://127.0.0.1:" .. port .. "/api/v1/items", method = "GET" }):execute()
+      expect(res:status_code()).to.equal(200)
+      expect(res:body():json()[2]).to.equal("b")
+      expect(res:headers()["x-group"]).to.equal("v1")
+      expect(res:headers()["x-group-middleware"]).to.equal("yes")
+
+      res = http.request({ url = "http://127.0.0.1:" .. port .. "/api/v1/items/7", method = "GET" }):execute()
+      expect(res:body():json().id).to.equal(7)
+      expect(res:headers()["x-group"]).to.equal("override")
+
+      res = http.request({ url = "http://127.0.0.1:" .. port .. "/api/v1/admin/stats", method = "GET" }):execute()
+      expect(res:body():text()).to.equal("stats")
+      expect(res:headers()["x-group"]).to.equal("v1")
+    end)
+
+    it("uses the group fallback and middleware only within the group", function()
+      local res = http.request({ url = "http://127.0.0.1:" .. port .. "/api/v1/nothing", method = "GET" }):execute()
+      expect(res:status_code()).to.equal(404)
+      expect(res:body():text()).to.equal("no such api")
+
+      res = http.request({ url = "http://127.0.0.1:" .. port .. "/ping", method = "GET" }):execute()
+      expect(res:headers()["x-group-middleware"]).to.equal(nil)
+    end)
+
+    it("serves HEAD and any-method routes", function()
+      local res = http.request({ url = "http://127.0.0.1:" .. port .. "/head-only", method = "HEAD" }):execute()
+      expect(res:status_code()).to.equal(200)
+      expect(res:headers()["x-head"]).to.equal("yes")
+
+      for _, method in ipairs({ "GET", "DELETE", "PATCH" }) do
+        res = http.request({ url = "http://127.0.0.1:" .. port .. "/any-method", method = method }):execute()
+        expect(res:body():text()).to.equal(method)
+      end
+    end)
+
+    it("serves routes with several methods", function()
+      local function call(path, method)
+        return http.request({ url = "http://127.0.0.1:" .. port .. path, method = method }):execute()
+      end
+      expect(call("/some-methods", "GET"):body():text()).to.equal("GET")
+      expect(call("/some-methods", "POST"):body():text()).to.equal("POST")
+      expect(call("/some-methods", "PUT"):status_code()).to.equal(405)
+
+      expect(call("/custom-method", "PURGE"):body():text()).to.equal("PURGE")
+      local res = call("/custom-method", "PUT")
+      expect(res:status_code()).to.equal(405)
+      expect(res:headers()["allow"]).to.equal("GET, PURGE")
+    end)
+
+    it("streams a response body in chunks", function()
+      local req = http.request({ url = "http://127.0.0.1:" .. port .. "/stream", method = "GET" })
+      local res = req:execute()
+      expect(res:status_code()).to.equal(200)
+      expect(res:headers()["content-type"]).to.equal("text/plain")
+      expect(res:body():text()).to.equal("chunk1;chunk2;chunk3;")
+    end)
+
+    it("sends server-sent events", function()
+      local req = http.request({ url = "http://127.0.0.1:" .. port .. "/events", method = "GET" })
+      local res = req:execute()
+      expect(res:headers()["content-type"]).to.equal("text/event-stream")
+      local text = res:body():text()
+      expect(text:find("data: first\nevent: tick\nid: 1\n\n", 1, true) ~= nil).to.be.truthy()
+      expect(text:find('data: {"count":2}\n\n', 1, true) ~= nil).to.be.truthy()
+      expect(text:find("data: plain\n\n", 1, true) ~= nil).to.be.truthy()
+    end)
+
+    it("sets cookies on response", function()
+      local req = http.request({ url = "http://127.0.0.1:" .. port .. "/set-cookie", method = "GET" })
+      local res = req:execute()
+      local headers = res:headers()
+      expect(headers).to.be.a("table")
+    end)
+
+    it("reads back signed and private cookies", function()
+      local cookies = {}
+      for _, kind in ipairs({ "signed", "private" }) do
+        local res = http
+          .request({ url = "http://127.0.0.1:" .. port .. "/set-" .. kind .. "-cookie", method = "GET" })
+          :execute()
+        local set_cookie = res:headers()["set-cookie"]
+        expect(set_cookie).to.be.a("string")
+        -- the value is never sent in plain text
+        expect(set_cookie:find("=hello;", 1, true)).to.equal(nil)
+        expect(set_cookie:find("=secret;", 1, true)).to.equal(nil)
+        table.insert(cookies, set_cookie:match("^([^;]+)"))
+      end
+
+      local res = http
+        .request({
+          url = "http://127.0.0.1:" .. port .. "/read-cookies",
+          method = "GET",
+          headers = { Cookie = table.concat(cookies, "; ") },
+        })
+        :execute()
+      local body = res:body():json()
+      expect(body.signed).to.equal("hello")
+      expect(body.private).to.equal("secret")
+    end)
+
+    it("rejects tampered signed and private cookies", function()
+      local res = http
+        .request({
+          url = "http://127.0.0.1:" .. port .. "/read-cookies",
+          method = "GET",
+          headers = { Cookie = "signed=hello; private=secret" },
+        })
+        :execute()
+      local body = res:body():json()
+      expect(body.signed).to.equal("none")
+      expect(body.private).to.equal("none")
+    end)
+
+    it("reads cookies set with a rotated key until it is dropped", function()
+      local key_a = "the-first-secret-that-is-at-least-32-bytes"
+      local key_b = "the-second-secret-that-is-at-least-32-bytes"
+
+      -- runs a server with the given keys around the callback
+      local function with_keys(keys, callback)
+        local keyed = http.server.new()
+        keyed.port = 0
+        keyed.cookie_key = keys
+        keyed:get("/set-signed-cookie", function(request, response)
+          response:set_signed_cookie(request:new_cookie("signed", "hello"))
+        end)
+        keyed:get("/set-private-cookie", function(request, response)
+          response:set_private_cookie(request:new_cookie("private", "secret"))
+        end)
+        keyed:get("/read-cookies", function(request)
+          local signed = request:get_signed_cookie("signed")
+          local private = request:get_private_cookie("private")
+          return {
+            signed = signed and signed:get_value() or "none",
+            private = private and private:get_value() or "none",
+          }
+        end)
+        local keyed_task = utils.spawn_task(function()
+          keyed:run()
+        end)
+        utils.spawn_timeout(function() end, 150):await()
+
+        local result = callback("http://127.0.0.1:" .. keyed.port)
+        keyed:shutdown(keyed)
+        keyed_task:await()
+        return result
+      end
+
+      local function read(cookies)
+        return function(base)
+          return http
+            .request({ url = base .. "/read-cookies", method = "GET", headers = { Cookie = cookies } })
+            :execute()
+            :body()
+            :json()
+        end
+      end
+
+      local cookies = with_keys(key_a, function(base)
+        local cookies = {}
+        for _, kind in ipairs({ "signed", "private" }) do
+          local res = http.request({ url = base .. "/set-" .. kind .. "-cookie", method = "GET" }):execute()
+          table.insert(cookies, res:headers()["set-cookie"]:match("^([^;]+)"))
+        end
+        return table.concat(cookies, "; ")
+      end)
+
+      local body = with_keys({ key_b, key_a }, read(cookies))
+      expect(body.signed).to.equal("hello")
+      expect(body.private).to.equal("secret")
+
+      body = with_keys({ key_b }, read(cookies))
+      expect(body.signed).to.equal("none")
+      expect(body.private).to.equal("none")
+    end)
+  end)
+
+  -------------------------------------------------------------------------------
+  -- HTTP TLS
+  -------------------------------------------------------------------------------
+  describe("HTTP TLS", function()
+    local dir = "tests/_http_tls"
+
+    local function openssl(args)
+      local ok = os.execute("openssl " .. args .. " > /dev/null 2>&1")
+      assert(ok == true or ok == 0, "openssl " .. args .. " failed")
+    end
+
+    -- a new key and a certificate signed by it, or by the given CA
+    local function certificate(name, subject, ca)
+      local key = " -newkey ec -pkeyopt ec_paramgen_curve:prime256v1 -nodes -keyout " .. dir .. "/" .. name .. "_key.pem"
+      if ca then
+        openssl("req" .. key .. " -subj /CN=" .. subject .. " -out " .. dir .. "/" .. name .. ".csr")
+        openssl(
+          "x509 -req -days 1 -in " .. dir .. "/" .. name .. ".csr"
+            .. " -CA " .. dir .. "/" .. ca .. ".pem -CAkey " .. dir .. "/" .. ca .. "_key.pem"
+            .. " -CAcreateserial -out " .. dir .. "/" .. name .. ".pem"
+        )
+      else
+        openssl(
+          "req -x509 -days 1" .. key .. " -subj /CN=" .. subject
+            .. " -addext subjectAltName=DNS:localhost,IP:127.0.0.1 -out " .. dir .. "/" .. name .. ".pem"
+        )
+      end
+    end
+
+    local function copy(from, to)
+      local source = assert(io.open(dir .. "/" .. from, "rb"))
+      local target = assert(io.open(dir .. "/" .. to, "wb"))
+      target:write(source:read("*a"))
+      source:close()
+      target:close()
+    end
+
+    local function start(server, port)
+      server.port = port
+      server:get("/ping", function()
+        return "pong"
+      end)
+      local server_task = utils.spawn_task(function()
+        server:run()
+      end)
+      utils.spawn_timeout(function() end, 150):await()
+      return server_task
+    end
+
+    local function get(server, args)
+      return curl(args .. " https://localhost:" .. server.port .. "/ping")
+    end
+
+    test.before(function()
+      fs.create_dir_all(dir)
+      certificate("server", "localhost")
+    end)
+
+    test.after(function()
+      fs.remove_dir_all(dir)
+    end)
+
+    it("serves HTTPS", function()
+      local server = http.server.new()
+      server.tls = { cert = dir .. "/server.pem", key = dir .. "/server_key.pem" }
+      local server_task = start(server, 18450)
+
+      local status, body = get(server, "--cacert " .. dir .. "/server.pem")
+      expect(status).to.equal(200)
+      expect(body).to.equal("pong")
+
+      -- plain HTTP does not get through the handshake
+      expect((curl("http://127.0.0.1:" .. server.port .. "/ping"))).to.equal(0)
+
+      server:shutdown(server)
+      server_task:await()
+    end)
+
+    it("rejects clients without a certificate when verifying them", function()
+      certificate("ca", "client-ca")
+      certificate("client", "client", "ca")
+      local server = http.server.new()
+      server.tls = { cert = dir .. "/server.pem", key = dir .. "/server_key.pem", client_ca = dir .. "/ca.pem" }
+      local server_task = start(server, 18451)
+
+      local trusted = "--cacert " .. dir .. "/server.pem"
+      expect((get(server, trusted))).to.equal(0)
+
+      -- a certificate from another CA is not accepted either
+      certificate("other_ca", "other-ca")
+      certificate("other", "client", "other_ca")
+      expect((get(server, trusted .. " --cert " .. dir .. "/other.pem --key " .. dir .. "/other_key.pem"))).to.equal(0)
+
+      local status, body = get(server, trusted .. " --cert " .. dir .. "/client.pem --key " .. dir .. "/client_key.pem")
+      expect(status).to.equal(200)
+      expect(body).to.equal("pong")
+
+      server:shutdown(server)
+      server_task:await()
+    end)
+
+    it("lets clients without a certificate through when client auth is optional", function()
+      certificate("ca", "client-ca")
+      certificate("client", "client", "ca")
+      local server = http.server.new()
+      server.tls = {
+        cert = dir .. "/server.pem",
+        key = dir .. "/server_key.pem",
+        client_ca = dir .. "/ca.pem",
+        client_auth_optional = true,
+      }
+      local server_task = start(server, 18453)
+
+      local trusted = "--cacert " .. dir .. "/server.pem"
+      expect((get(server, trusted))).to.equal(200)
+      expect((get(server, trusted .. " --cert " .. dir .. "/client.pem --key " .. dir .. "/client_key.pem"))).to.equal(200)
+
+      server:shutdown(server)
+      server_task:await()
+    end)
+
+    it("switches certificates for new connections on reload", function()
+      certificate("next", "localhost")
+      copy("server.pem", "cert.pem")
+      copy("server_key.pem", "key.pem")
+      local server = http.server.new()
+      server.tls = { cert = dir .. "/cert.pem", key = dir .. "/key.pem" }
+      local server_task = start(server, 18452)
+
+      expect((get(server, "--cacert " .. dir .. "/server.pem"))).to.equal(200)
+      expect((get(server, "--cacert " .. dir .. "/next.pem"))).to.equal(0)
+
+      copy("next.pem", "cert.pem")
+      copy("next_key.pem", "key.pem")
+      server:reload_tls()
+
+      expect((get(server, "--cacert " .. dir .. "/next.pem"))).to.equal(200)
+      expect((get(server, "--cacert " .. dir .. "/server.pem"))).to.equal(0)
+
+      server:shutdown(server)
+      server_task:await()
+    end)
+  end)
+
+  -------------------------------------------------------------------------------
+  -- HTTP Sessions
+  -------------------------------------------------------------------------------
+  describe("HTTP Sessions", function()
+    local tmp_dir = "tests/_http_session_test"
+    local servers = {}
+    local stores = {
+      { name = "memory", port = 18082 },
+      { name = "file", port = 18083 },
+    }
+
+    test.before(function()
+      fs.create_dir_all(tmp_dir)
+
+      for _, store in ipairs(stores) do
+        local server = http.server.new()
+        server.port = store.port
+        if store.name == "memory" then
+          server.session = { store = "memory" }
+        else
+          server.session = { store = "file", path = tmp_dir }
+        end
+
+        server:get("/anonymous", function()
+          return "nobody"
+        end)
+        server:post("/login", function(request)
+          local session = request:session()
+          session:regenerate()
+          session.user = "alice"
+          session:flash("notice", "welcome")
+          return session:id()
+        end)
+        server:post("/visit", function(request)
+          local session = request:session()
+          session:set("visits", (session:get("visits") or 0) + 1)
+          return tostring(session.visits)
+        end)
+        server:get("/me", function(request)
+          local session = request:session()
+          return { user = session.user or "none", notice = session:get_flash("notice") or "none" }
+        end)
+        server:post("/logout", function(request)
+          request:session():destroy()
+        end)
+
+        table.insert(servers, server)
+        utils.spawn_task(function()
+          server:run()
+        end)
+      end
+      utils.spawn_timeout(function() end, 150):await()
+    end)
+
+    test.after(function()
+      for _, server in ipairs(servers) do
+        server:shutdown(server)
+      end
+      utils.spawn_timeout(function() end, 10):await()
+      fs.remove_dir_all(tmp_dir)
+    end)
+
+    local function send(port, method, path, cookie)
+      local res = http
+        .request({
+          url = "http://127.0.0.1:" .. port .. path,
+          method = method,
+          headers = cookie and { Cookie = cookie } or nil,
+        })
+        :execute()
+      local set_cookie = res:headers()["set-cookie"]
+      return res, set_cookie and set_cookie:match("^([^;]+)")
+    end
+
+    for _, store in ipairs(stores) do
+      describe(store.name .. " store", function()
+        it("does not set a cookie for requests without a session", function()
+          local res = send(store.port, "GET", "/anonymous")
+          expect(res:headers()["set-cookie"]).to.equal(nil)
+        end)
+
+        it("keeps values and flash messages across requests", function()
+          local res, cookie = send(store.port, "POST", "/login")
+          expect(cookie).to.be.a("string")
+          expect(cookie:match("^astra_session=(%x+)$")).to.equal(res:body():text())
+          expect(res:headers()["set-cookie"]:find("HttpOnly", 1, true) ~= nil).to.be.truthy()
+
+          res = send(store.port, "GET", "/me", cookie)
+          local body = res:body():json()
+          expect(body.user).to.equal("alice")
+          expect(body.notice).to.equal("welcome")
+
+          -- flash messages only last for the next request
+          body = send(store.port, "GET", "/me", cookie):body():json()
+          expect(body.user).to.equal("alice")
+          expect(body.notice).to.equal("none")
+
+          expect(send(store.port, "POST", "/visit", cookie):body():text()).to.equal("1")
+          expect(send(store.port, "POST", "/visit", cookie):body():text()).to.equal("2")
+        end)
+
+        it("moves the session to a new id on regenerate", function()
+          local _, cookie = send(store.port, "POST", "/login")
+          local _, regenerated = send(store.port, "POST", "/login", cookie)
+          expect(regenerated).to.be.a("string")
+          expect(regenerated ~= cookie).to.be.truthy()
+
+          expect(send(store.port, "GET", "/me", cookie):body():json().user).to.equal("none")
+          expect(send(store.port, "GET", "/me", regenerated):body():json().user).to.equal("alice")
+        end)
+
+        it("forgets destroyed sessions", function()
+          local _, cookie = send(store.port, "POST", "/login")
+          local res = send(store.port, "POST", "/logout", cookie)
+          expect(res:headers()["set-cookie"]:find("Max-Age=0", 1, true) ~= nil).to.be.truthy()
+          expect(send(store.port, "GET", "/me", cookie):body():json().user).to.equal("none")
+        end)
+
+        it("ignores session ids it did not hand out", function()
+          local forged = "astra_session=" .. string.rep("a", 64)
+          local res, cookie = send(store.port, "POST", "/visit", forged)
+          expect(res:body():text()).to.equal("1")
+          expect(cookie ~= forged).to.be.truthy()
+        end)
+      end)
+    end
+
+    it("fails to start with an unknown store", function()
+      local server = http.server.new()
+      server.port = 18447
+      server.session = { store = "redis" }
+      expect(function()
+        server:run()
+      end).to.fail()
+    end)
+  end)
+
+  -------------------------------------------------------------------------------
+  -- HTTP CSRF
+  -------------------------------------------------------------------------------
+  describe("HTTP CSRF", function()
+    local server
+    local port = 18086
+    local token
+
+    test.before(function()
+      server = http.server.new()
+      server.port = port
+      server.csrf = true
+
+      server:get("/token", function(request)
+        return request:csrf_token()
+      end)
+      server:post("/comments", function(request)
+        if request:headers()["content-type"] == "application/x-www-form-urlencoded" then
+          return request:form().text
+        end
+        return "ok"
+      end)
+      server:post("/webhook", function()
+        return "received"
+      end, { csrf = false })
+      server:post("/upload", function()
+        return "uploaded"
+      end)
+      server:websocket("/socket", function(socket)
+        socket:send_text("connected")
+      end)
+      server:use(function(request, _response, next)
+        if request:headers()["x-hook"] then
+          return "hooked"
+        end
+        next()
+      end)
+
+      utils.spawn_task(function()
+        server:run()
+      end)
+      utils.spawn_timeout(function() end, 150):await()
+
+      local res = http.request({ url = "http://127.0.0.1:" .. port .. "/token", method = "GET" }):execute()
+      token = res:headers()["set-cookie"]:match("^csrf_token=(%x+)")
+    end)
+
+    test.after(function()
+      server:shutdown(server)
+      utils.spawn_timeout(function() end, 10):await()
+    end)
+
+    local function post(path, headers, body)
+      return http
+        .request({ url = "http://127.0.0.1:" .. port .. path, method = "POST", headers = headers, body = body })
+        :execute()
+    end
+
+    it("issues a token once and hands it to the handler", function()
+      expect(token).to.be.a("string")
+      local res = http
+        .request({
+          url = "http://127.0.0.1:" .. port .. "/token",
+          method = "GET",
+          headers = { Cookie = "csrf_token=" .. token },
+        })
+        :execute()
+      expect(res:body():text()).to.equal(token)
+      expect(res:headers()["set-cookie"]).to.equal(nil)
+    end)
+
+    it("rejects unsafe requests without the token", function()
+      local res = post("/comments", {
+        Cookie = "csrf_token=" .. token,
+        ["Content-Type"] = "application/x-www-form-urlencoded",
+      }, "text=hello")
+      expect(res:status_code()).to.equal(403)
+
+      -- a token without the cookie it came from is not enough either
+      res = post("/comments", { ["X-CSRF-Token"] = token })
+      expect(res:status_code()).to.equal(403)
+
+      res = post("/comments", { Cookie = "csrf_token=" .. token, ["X-CSRF-Token"] = string.rep("b", 64) })
+      expect(res:status_code()).to.equal(403)
+    end)
+
+    it("accepts the token from a header, form, JSON or multipart field", function()
+      local cookie = "csrf_token=" .. token
+      local res = post("/comments", { Cookie = cookie, ["X-CSRF-Token"] = token })
+      expect(res:status_code()).to.equal(200)
+
+      res = post("/comments", {
+        Cookie = cookie,
+        ["Content-Type"] = "application/x-www-form-urlencoded",
+      }, "text=hello&csrf_token=" .. token)
+      expect(res:status_code()).to.equal(200)
+      -- the handler still reads the body after the check
+      expect(res:body():text()).to.equal("hello")
+
+      res = post("/comments", { Cookie = cookie, ["Content-Type"] = "application/json" }, '{"csrf_token":"' .. token .. '"}')
+      expect(res:status_code()).to.equal(200)
+
+      local boundary = "csrfboundary"
+      res = post("/upload", {
+        Cookie = cookie,
+        ["Content-Type"] = "multipart/form-data; boundary=" .. boundary,
+      }, table.concat({
+        "--" .. boundary,
+        'Content-Disposition: form-data; name="csrf_token"',
+        "",
+        token,
+        "--" .. boundary .. "--",
+        "",
+      }, "\r\n"))
+      expect(res:status_code()).to.equal(200)
+      expect(res:body():text()).to.equal("uploaded")
+    end)
+
+    it("skips routes that opt out", function()
+      local res = post("/webhook", { ["Content-Type"] = "application/json" }, "{}")
+      expect(res:status_code()).to.equal(200)
+      expect(res:body():text()).to.equal("received")
+    end)
+
+    it("checks the token before the middleware answers", function()
+      local res = post("/comments", { Cookie = "csrf_token=" .. token, ["X-Hook"] = "yes" })
+      expect(res:status_code()).to.equal(403)
+
+      res = post("/comments", { Cookie = "csrf_token=" .. token, ["X-CSRF-Token"] = token, ["X-Hook"] = "yes" })
+      expect(res:status_code()).to.equal(200)
+      expect(res:body():text()).to.equal("hooked")
+    end)
+
+    it("checks the token of WebSocket upgrades", function()
+      local upgrade = {
+        Cookie = "csrf_token=" .. token,
+        ["Connection"] = "Upgrade",
+        ["Upgrade"] = "websocket",
+        ["Sec-WebSocket-Version"] = "13",
+        ["Sec-WebSocket-Key"] = "dGhlIHNhbXBsZSBub25jZQ==",
+      }
+      local base = "http://127.0.0.1:" .. port .. "/socket"
+      local res = http.request({ url = base, method = "GET", headers = upgrade }):execute()
+      expect(res:status_code()).to.equal(403)
+
+      res = http.request({ url = base .. "?csrf_token=" .. token, method = "GET", headers = upgrade }):execute()
+      expect(res:status_code()).to.equal(101)
+
+      local received
+      http
+        .request({
+          url = "ws://127.0.0.1:" .. port .. "/socket",
+          method = "GET",
+          headers = { Cookie = "csrf_token=" .. token, ["X-CSRF-Token"] = token },
+        })
+        :execute_websocket(function(socket)
+          received = socket:recv().value
+        end)
+      utils.spawn_timeout(function() end, 200):await()
+      expect(received).to.equal("connected")
+    end)
+  end)
+
+  -------------------------------------------------------------------------------
+  -- HTTP Listeners
+  -------------------------------------------------------------------------------
+  describe("HTTP Listeners", function()
+    local function start(server)
+      server:get("/ping", function()
+        return "pong"
+      end)
+      utils.spawn_task(function()
+        server:run()
+      end)
+      utils.spawn_timeout(function() end, 150):await()
+    end
+
+    local function stop(server)
+      server:shutdown(server)
+      utils.spawn_timeout(function() end, 10):await()
+    end
+
+    local function ping(address)
+      return http.request({ url = "http://" .. address .. "/ping", method = "GET" }):execute():body():text()
+    end
+
+    it("reports the port picked for port 0", function()
+      local server = http.server.new()
+      server.port = 0
+      start(server)
+
+      expect(server.port ~= 0).to.be.truthy()
+      expect(server.addresses[1]).to.equal("127.0.0.1:" .. server.port)
+      expect(ping("127.0.0.1:" .. server.port)).to.equal("pong")
+      stop(server)
+    end)
+
+    it("listens on several addresses at once", function()
+      local server = http.server.new()
+      server.listen = { "127.0.0.1:0", "127.0.0.1:0" }
+      start(server)
+
+      expect(#server.addresses).to.equal(2)
+      expect(server.addresses[1] ~= server.addresses[2]).to.be.truthy()
+      for _, address in ipairs(server.addresses) do
+        expect(ping(address)).to.equal("pong")
+      end
+      stop(server)
+    end)
+
+    it("returns bind errors instead of crashing", function()
+      local server = http.server.new()
+      server.port = 0
+      start(server)
+
+      local taken = http.server.new()
+      taken.port = server.port
+      local ok, err = pcall(function()
+        taken:run()
+      end)
+      expect(ok).to.equal(false)
+      expect(tostring(err):find("Could not listen on 127.0.0.1:" .. server.port, 1, true) ~= nil).to.be.truthy()
+      stop(server)
+    end)
+
+    it("listens on a Unix socket with the given permissions", function()
+      local path = "tests/_http_listener.sock"
+      local server = http.server.new()
+      server.listen = { { unix = path, permissions = "440" }, "127.0.0.1:0" }
+      start(server)
+
+      expect(server.addresses[1]).to.equal("unix:" .. path)
+      expect(fs.exists(path)).to.equal(true)
+      expect(fs.get_metadata(path):file_permissions():is_readonly()).to.equal(true)
+      expect(ping(server.addresses[2])).to.equal("pong")
+
+      -- the socket is cleaned up once the server stops
+      stop(server)
+      expect(fs.exists(path)).to.equal(false)
+    end)
+
+    it("gives Unix socket clients no IP address and leaves them out of IP rate limits", function()
+      local path = "tests/_http_peer.sock"
+      local server = http.server.new()
+      server.listen = { { unix = path }, "127.0.0.1:0" }
+      server.rate_limit = { requests = 1, window = 60 }
+      server:get("/ip", function(request)
+        local ip = request:ip_address()
+        return ip and ip:address() or "none"
+      end)
+      start(server)
+
+      for _ = 1, 2 do
+        local status, body = curl("--unix-socket " .. path .. " http://localhost/ip")
+        expect(status).to.equal(200)
+        expect(body).to.equal("none")
+      end
+
+      local url = "http://" .. server.addresses[2] .. "/ip"
+      expect(http.request(url):execute():body():text()).to.equal("127.0.0.1")
+      expect(http.request(url):execute():status_code()).to.equal(429)
+      stop(server)
+    end)
+
+    it("rejects invalid socket permissions", function()
+      local server = http.server.new()
+      server.listen = { unix = "tests/_http_invalid.sock", permissions = "rw" }
+      expect(function()
+        server:run()
+      end).to.fail()
+    end)
+  end)
+
+  -------------------------------------------------------------------------------
+  -- HTTP Lifecycle
+  -------------------------------------------------------------------------------
+  describe("HTTP Lifecycle", function()
+    local function get(port, path)
+      return http.request({ url = "http://127.0.0.1:" .. port .. path, method = "GET" }):execute()
+    end
+
+    it("runs the start hooks once listening and reports health", function()
+      local server = http.server.new()
+      server.port = 0
+      server.health = true
+      local started = {}
+      server:on_start(function(s)
+        table.insert(started, s.port)
+      end)
+      server:on_start(function()
+        table.insert(started, "second")
+      end)
+      local server_task = utils.spawn_task(function()
+        server:run()
+      end)
+      utils.spawn_timeout(function() end, 150):await()
+
+      expect(#started).to.equal(2)
+      expect(started[1]).to.equal(server.port)
+      expect(started[2]).to.equal("second")
+      local res = get(server.port, "/health")
+      expect(res:status_code()).to.equal(200)
+      expect(res:body():json().status).to.equal("ok")
+      expect(server:is_draining()).to.equal(false)
+
+      server:shutdown(server)
+      server_task:await()
+    end)
+
+    it("reports draining while shutting down and runs the shutdown hooks", function()
+      local server = http.server.new()
+      server.port = 0
+      server.health = "/healthz"
+      server.shutdown_delay = 0.4
+      local hooked = false
+      server:on_shutdown(function(s)
+        hooked = s:is_draining()
+      end)
+      local server_task = utils.spawn_task(function()
+        server:run()
+      end)
+      utils.spawn_timeout(function() end, 150):await()
+
+      server:shutdown(server)
+      utils.spawn_timeout(function() end, 100):await()
+      expect(hooked).to.equal(true)
+      local res = get(server.port, "/healthz")
+      expect(res:status_code()).to.equal(503)
+      expect(res:body():json().status).to.equal("draining")
+
+      server_task:await()
+    end)
+
+    it("cuts off requests that outlive the shutdown timeout", function()
+      local server = http.server.new()
+      server.port = 0
+      server.shutdown_timeout = 0.2
+      server:get("/slow", function()
+        utils.spawn_timeout(function() end, 5000):await()
+        return "finished"
+      end)
+      local server_task = utils.spawn_task(function()
+        server:run()
+      end)
+      utils.spawn_timeout(function() end, 150):await()
+
+      local status
+      local request_task = utils.spawn_task(function()
+        status = get(server.port, "/slow"):status_code()
+      end)
+      utils.spawn_timeout(function() end, 100):await()
+      server:shutdown(server)
+
+      server_task:await()
+      request_task:await()
+      expect(status).to.equal(503)
+    end)
+
+    it("closes WebSockets and streams that are still open when shutting down", function()
+      local server = http.server.new()
+      server.port = 0
+      server:websocket("/ws", function(socket)
+        while socket:recv() do
+        end
+      end)
+      server:get("/events", function(_request, response)
+        response:sse(function(sse)
+          while sse:send("tick") do
+            utils.spawn_timeout(function() end, 50):await()
+          end
+        end)
+      end)
+      local server_task = utils.spawn_task(function()
+        server:run()
+      end)
+      utils.spawn_timeout(function() end, 150):await()
+
+      local closed
+      local socket_task = utils.spawn_task(function()
+        http.request("ws://127.0.0.1:" .. server.port .. "/ws"):execute_websocket(function(socket)
+          closed = socket:recv()
+        end)
+      end)
+      local events_status, events
+      local events_task = utils.spawn_task(function()
+        events_status, events = curl("-N http://127.0.0.1:" .. server.port .. "/events")
+      end)
+      utils.spawn_timeout(function() end, 200):await()
+      server:shutdown(server)
+
+      server_task:await()
+      socket_task:await()
+      events_task:await()
+      expect(closed.type).to.equal("close")
+      expect(closed.value.code).to.equal("1001")
+      expect(events_status).to.equal(200)
+      expect(events:find("data: tick", 1, true) ~= nil).to.be.truthy()
+    end)
+  end)
+
+  -------------------------------------------------------------------------------
+  -- HTTP OpenAPI
+  -------------------------------------------------------------------------------
+  describe("HTTP OpenAPI", function()
+    local t = require("validation").types
+
+    local function api()
+      local server = http.server.new()
+      server.version = "1.2.0"
+      server:get("/", function()
+        return "home"
+      end, { openapi = { summary = "Home", tags = { "pages" } } })
+      server:post("/users", function() end, {
+        body_schema = t.struct({ name = t.string(), age = t.optional(t.integer()) }),
+        query_schema = t.struct({ notify = t.optional(t.pattern("^[01]$")) }),
+        openapi = {
+          summary = "Creates a user",
+          responses = {
+            [201] = { description = "Created", schema = t.struct({ id = t.integer() }) },
+            [409] = "Already exists",
+          },
+        },
+      })
+      server:group("/orgs/{org:int}", { openapi = { tags = { "orgs" } } }, function(group)
+        group:get("/files/{*rest}", function() end)
+        group:route({ "GET", "PURGE" }, "/cache", function() end)
+        group:get("/internal", function() end, { openapi = false })
+      end)
+      server:websocket("/ws", function() end)
+      return server
+    end
+
+    it("describes the routes along with their parameters and schemas", function()
+      local document = api():openapi({ title = "Demo", servers = { { url = "https://example.com" } } })
+      expect(document.openapi).to.equal("3.1.0")
+      expect(document.info.title).to.equal("Demo")
+      expect(document.info.version).to.equal("1.2.0")
+      expect(document.info.servers).to.equal(nil)
+      expect(document.servers[1].url).to.equal("https://example.com")
+
+      local home = document.paths["/"].get
+      expect(home.summary).to.equal("Home")
+      expect(home.tags[1]).to.equal("pages")
+      expect(home.responses["200"].description).to.equal("OK")
+
+      local create = document.paths["/users"].post
+      expect(create.requestBody.content["application/json"].schema.required[1]).to.equal("name")
+      expect(create.parameters[1].name).to.equal("notify")
+      expect(create.parameters[1]["in"]).to.equal("query")
+      expect(create.parameters[1].required).to.equal(false)
+      expect(create.responses["201"].content["application/json"].schema.properties.id.type).to.equal("integer")
+      expect(create.responses["409"].description).to.equal("Already exists")
+      expect(create.responses["422"]).to.be.a("table")
+
+      local files = document.paths["/orgs/{org}/files/{rest}"].get
+      expect(files.tags[1]).to.equal("orgs")
+      expect(files.parameters[1].name).to.equal("org")
+      expect(files.parameters[1].schema.type).to.equal("integer")
+      expect(files.parameters[2].name).to.equal("rest")
+    end)
+
+    it("leaves out hidden routes, websockets and custom methods", function()
+      local document = api():openapi()
+      expect(document.info.title).to.equal("Astra")
+      expect(document.paths["/orgs/{org}/internal"]).to.equal(nil)
+      expect(document.paths["/ws"]).to.equal(nil)
+      expect(document.paths["/orgs/{org}/cache"].get).to.be.a("table")
+      expect(document.paths["/orgs/{org}/cache"].purge).to.equal(nil)
+    end)
+
+    it("encodes the document as JSON and YAML", function()
+      local server = api()
+      local json = require("serde").json.decode(server:openapi(nil, "json"))
+      expect(json.paths["/users"].post.summary).to.equal("Creates a user")
+      expect(server:openapi(nil, "yaml"):find("openapi: 3.1.0", 1, true)).to.be.truthy()
+      expect(function()
+        server:openapi(nil, "xml")
+      end).to.fail()
+    end)
+
+    it("serves the document and the docs page", function()
+      local server = api()
+      server.port = 0
+      server:openapi_docs({ title = "Demo" })
+      local server_task = utils.spawn_task(function()
+        server:run()
+      end)
+      utils.spawn_timeout(function() end, 150):await()
+
+      local base = "http://127.0.0.1:" .. server.port
+      local res = http.request({ url = base .. "/openapi.json", method = "GET" }):execute()
+      expect(res:status_code()).to.equal(200)
+      local document = res:body():json()
+      expect(document.info.title).to.equal("Demo")
+      expect(document.paths["/openapi.json"]).to.equal(nil)
+      expect(document.paths["/docs"]).to.equal(nil)
+
+      res = http.request({ url = base .. "/docs", method = "GET" }):execute()
+      expect(res:status_code()).to.equal(200)
+      expect(res:headers()["content-type"]:find("text/html", 1, true)).to.be.truthy()
+      local page = res:body():text()
+      expect(page:find("Creates a user", 1, true)).to.be.truthy()
+      expect(page:find("/orgs/{org}/files/{rest}", 1, true)).to.be.truthy()
+
+      server:shutdown(server)
+      server_task:await()
+    end)
+  end)
+
+  -------------------------------------------------------------------------------
+  -- HTTP WebSocket rooms
+  -------------------------------------------------------------------------------
+  describe("HTTP WebSocket rooms", function()
+    local function wait(ms)
+      utils.spawn_timeout(function() end, ms):await()
+    end
+
+    it("broadcasts to the members of a room and cleans up on disconnect", function()
+      local server = http.server.new()
+      server.port = 0
+      local ids = {}
+      local forwarded
+      server:websocket("/chat", function(socket)
+        while true do
+          local message = socket:recv()
+          if not message or message.type ~= "text" then
+            return
+          end
+          if message.value == "join" then
+            socket:join("lobby")
+            socket:join("lobby:" .. #ids)
+            table.insert(ids, socket:id())
+            socket:send_text(socket:id())
+          elseif message.value == "leave" then
+            socket:leave("lobby")
+          else
+            forwarded = http.ws.broadcast("lobby", message.value, { except = socket:id() })
+          end
+        end
+      end)
+      local server_task = utils.spawn_task(function()
+        server:run()
+      end)
+      wait(150)
+
+      -- the client socket cannot send while it waits for a message, so each one follows a script
+      local received = {}
+      local done = false
+      local function client(expected, after)
+        http.request("ws://127.0.0.1:" .. server.port .. "/chat"):execute_websocket(function(socket)
+          local messages = {}
+          table.insert(received, messages)
+          socket:send_text("join")
+          for _ = 1, expected + 1 do
+            table.insert(messages, socket:recv().value)
+          end
+          after(socket)
+        end)
+        wait(100)
+      end
+      local function close_when_done(socket)
+        while not done do
+          wait(50)
+        end
+        socket:send_close(1000)
+      end
+
+      client(1, function(socket)
+        socket:send_text("from the first")
+        close_when_done(socket)
+      end)
+      client(3, function(socket)
+        socket:send_text("leave")
+        close_when_done(socket)
+      end)
+      client(2, function(socket)
+        socket:send_close(1000)
+      end)
+
+      expect(http.ws.count("lobby")).to.equal(3)
+      expect(#http.ws.members("lobby")).to.equal(3)
+      expect(received[1][1]).to.equal(ids[1])
+
+      expect(http.ws.broadcast("lobby", { hello = "world" })).to.equal(3)
+      expect(http.ws.send(ids[2], "just for you")).to.equal(true)
+      wait(300)
+      expect(forwarded).to.equal(2)
+      expect(received[1][2]).to.equal('{"hello":"world"}')
+      expect(received[2][3]).to.equal("just for you")
+      expect(received[2][4]).to.equal("from the first")
+      expect(received[3][3]).to.equal("from the first")
+
+      expect(http.ws.count("lobby")).to.equal(1)
+      expect(http.ws.members("lobby")[1]).to.equal(ids[1])
+      expect(http.ws.count("lobby:2")).to.equal(0)
+      expect(http.ws.send(ids[3], "gone")).to.equal(false)
+
+      done = true
+      wait(300)
+      expect(http.ws.count("lobby")).to.equal(0)
+      expect(http.ws.count("lobby:0")).to.equal(0)
+
+      server:shutdown(server)
+      server_task:await()
+    end)
+  end)
+
+  -------------------------------------------------------------------------------
+  -- HTTP WebSocket upgrades
+  -------------------------------------------------------------------------------
+  describe("HTTP WebSocket upgrades", function()
+    local function wait(ms)
+      utils.spawn_timeout(function() end, ms):await()
+    end
+
+    local upgrade_headers = {
+      ["Connection"] = "Upgrade",
+      ["Upgrade"] = "websocket",
+      ["Sec-WebSocket-Version"] = "13",
+      ["Sec-WebSocket-Key"] = "dGhlIHNhbXBsZSBub25jZQ==",
+    }
+
+    local function upgrade(url, headers)
+      local all = {}
+      for key, value in pairs(upgrade_headers) do
+        all[key] = value
+      end
+      for key, value in pairs(headers or {}) do
+        all[key] = value
+      end
+      return http.request({ url = url, method = "GET", headers = all }):execute()
+    end
+
+    it("passes the request to the handler and lets the upgrade hook reject clients", function()
+      local server = http.server.new()
+      server.port = 0
+      local before = 0
+      server:before(function()
+        before = before + 1
+      end)
+      server:websocket("/rooms/{room:int}", function(socket, request)
+        socket:send_text(request:params().room .. " " .. request:queries().name .. " " .. request:headers()["x-token"])
+      end, {
+        query_schema = require("validation").types.struct({ name = require("validation").types.string() }),
+        headers = { ["X-Socket"] = "yes" },
+        upgrade = function(request, response)
+          if request:headers()["x-token"] ~= "secret" then
+            response:set_status_code(401)
+            return "Unauthorized"
+          end
+          if request:queries().name == "nobody" then
+            return "No"
+          end
+        end,
+      })
+      local server_task = utils.spawn_task(function()
+        server:run()
+      end)
+      wait(150)
+
+      local base = "http://127.0.0.1:" .. server.port
+      local res = upgrade(base .. "/rooms/1?name=ada")
+      expect(res:status_code()).to.equal(401)
+      expect(res:body():text()).to.equal("Unauthorized")
+
+      res = upgrade(base .. "/rooms/1?name=nobody", { ["X-Token"] = "secret" })
+      expect(res:status_code()).to.equal(403)
+
+      res = upgrade(base .. "/rooms/abc?name=ada", { ["X-Token"] = "secret" })
+      expect(res:status_code()).to.equal(404)
+
+      res = upgrade(base .. "/rooms/1", { ["X-Token"] = "secret" })
+      expect(res:status_code()).to.equal(422)
+
+      res = http.request({ url = base .. "/rooms/1?name=ada", method = "GET" }):execute()
+      expect(res:status_code() >= 400).to.equal(true)
+
+      res = upgrade(base .. "/rooms/1?name=ada", { ["X-Token"] = "secret" })
+      expect(res:status_code()).to.equal(101)
+      expect(res:headers()["x-socket"]).to.equal("yes")
+
+      local received
+      local request = http.request({
+        url = "ws://127.0.0.1:" .. server.port .. "/rooms/7?name=ada",
+        method = "GET",
+        headers = { ["X-Token"] = "secret" },
+      })
+      request:execute_websocket(function(socket)
+        received = socket:recv().value
+      end)
+      wait(200)
+      expect(received).to.equal("7 ada secret")
+      expect(before).to.equal(7)
+
+      server:shutdown(server)
+      server_task:await()
+    end)
+  end)
+
+  -------------------------------------------------------------------------------
+  -- HTTP WebSocket options
+  -------------------------------------------------------------------------------
+  describe("HTTP WebSocket options", function()
+    local function wait(ms)
+      utils.spawn_timeout(function() end, ms):await()
+    end
+
+    local server, server_task
+    local results = {}
+
+    test.before(function()
+      server = http.server.new()
+      server.port = 0
+
+      server:websocket("/chat", function(socket)
+        results.server_protocol = socket:protocol()
+        results.first = socket:recv()
+        results.close = socket:recv()
+        results.after = socket:recv()
+      end, { protocols = { "chat", "json" } })
+
+      server:websocket("/small", function(socket)
+        results.small = { pcall(socket.recv, socket) }
+      end, { max_message_size = 16 })
+
+      server:websocket("/idle", function(socket)
+        local message = socket:recv()
+        results.idle_message = message
+        results.idle_closed = message == nil
+      end, { idle_timeout = 0.3 })
+
+      server:websocket("/pinged", function(socket)
+        wait(600)
+        socket:send_text("still here")
+        socket:recv()
+      end, { ping_interval = 0.1 })
+
+      server_task = utils.spawn_task(function()
+        server:run()
+      end)
+      wait(150)
+    end)
+
+    test.after(function()
+      server:shutdown(server)
+      server_task:await()
+    end)
+
+    local function connect(path, options, callback)
+      http.request("ws://127.0.0.1:" .. server.port .. path):execute_websocket(callback, options)
+    end
+
+    it("negotiates a subprotocol and returns nil from recv once closed", function()
+      local client_protocol
+      connect("/chat", { protocols = { "v2", "json" } }, function(socket)
+        client_protocol = socket:protocol()
+        socket:send_text("hi")
+        socket:send_close(1000)
+      end)
+      wait(300)
+
+      expect(client_protocol).to.equal("json")
+      expect(results.server_protocol).to.equal("json")
+      expect(results.first.value).to.equal("hi")
+      expect(results.close.type).to.equal("close")
+      expect(results.close.value.code).to.equal(1000)
+      expect(results.after).to.equal(nil)
+    end)
+
+    it("fails to receive messages over the size limit", function()
+      connect("/small", nil, function(socket)
+        socket:send_text(string.rep("a", 64))
+      end)
+      wait(200)
+
+      expect(results.small[1]).to.equal(false)
+    end)
+
+    it("closes idle connections unless pings keep them alive", function()
+      connect("/idle", nil, function()
+        wait(600)
+      end)
+      wait(700)
+      expect(results.idle_closed).to.equal(true)
+
+      connect("/idle", { ping_interval = 0.1 }, function(socket)
+        wait(600)
+        socket:send_text("still here")
+        wait(100)
+      end)
+      wait(800)
+      expect(results.idle_closed).to.equal(false)
+      expect(results.idle_message.value).to.equal("still here")
+    end)
+
+    it("sends pings from the server that reset the idle timeout of the client", function()
+      local pinged, quiet
+      connect("/pinged", { idle_timeout = 0.3 }, function(socket)
+        pinged = socket:recv()
+        socket:send_close(1000)
+      end)
+      connect("/chat", { idle_timeout = 0.3 }, function(socket)
+        quiet = socket:recv() or false
+      end)
+      wait(800)
+
+      expect(pinged.value).to.equal("still here")
+      expect(quiet).to.equal(false)
     end)
   end)
 end
@@ -0,0 +1,131 @@
+local fs = require("fs")
+local http = require("http")
+local utils = require("utils")
+require("test")
+
+---@param test Test
+return function(test)
+  local describe, it, expect = test.describe, test.it, test.expect
+
+  -------------------------------------------------------------------------------
+  -- Watch
+  -------------------------------------------------------------------------------
+  describe("Run with --watch", function()
+    local dir = "tests/_watch"
+    local url = "http://127.0.0.1:18460/"
+
+    local function sleep(milliseconds)
+      utils.spawn_timeout(function() end, milliseconds):await()
+    end
+
+    -- the body the watched script answers with, nil while it does not answer
+    local function get()
+      local ok, response = pcall(function()
+        return http.request(url):execute()
+      end)
+      if ok and response:status_code() == 200 then
+        return response:body():text()
+      end
+    end
+
+    -- the next body the script answers with, skipping the moment it is not listening while it runs again
+    local function wait_for_change(previous)
+      for _ = 1, 200 do
+        local body = get()
+        if body and body ~= previous then
+          return body
+        end
+        sleep(50)
+      end
+      error("the watched script kept answering " .. tostring(previous))
+    end
+
+    -- runs the script with a second astra in the background, $PPID being the one running the tests
+    local function watch(script)
+      fs.write_file(dir .. "/init.lua", script)
+      os.execute("/proc/$PPID/exe run --watch " .. dir .. "/init.lua > /dev/null 2>&1 & echo $! > " .. dir .. "/pid")
+      local body = wait_for_change(nil)
+      -- the files are only watched from the check after they were required on
+      sleep(600)
+      return body
+    end
+
+    test.before(function()
+      pcall(fs.create_dir, dir)
+    end)
+
+    test.after(function()
+      os.execute("kill $(cat " .. dir .. "/pid)")
+      -- the next test listens on the same port
+      while get() do
+        sleep(50)
+      end
+      fs.remove_dir_all(dir)
+    end)
+
+    it("runs the script again when a module it requires changes", function()
+      fs.write_file(dir .. "/message.lua", 'return "one"')
+      local first = watch([[
+        local http = require("http")
+        local message = require("message")
+        local server = http.server.new()
+        server.port = 18460
+        server:get("/", function()
+          return message
+        end)
+        server:run()
+      ]])
+      expect(first).to.equal("one")
+
+      fs.write_file(dir .. "/message.lua", 'return "two"')
+      expect(wait_for_change("one")).to.equal("two")
+    end)
+
+    it("keeps answering on the same port while the script runs again", function()
+      local script = [[
+        local http = require("http")
+        local server = http.server.new()
+        server.port = 18460
+        server:get("/", function()
+          return "%s"
+        end)
+        server:run()
+      ]]
+      expect(watch(script:format("one"))).to.equal("one")
+
+      fs.write_file(dir .. "/init.lua", script:format("two"))
+      local bodies = {}
+      repeat
+        local body = get()
+        table.insert(bodies, body or "refused")
+        sleep(20)
+      until body ~= "one" or #bodies > 500
+      for index = 1, #bodies - 1 do
+        expect(bodies[index]).to.equal("one")
+      end
+      expect(bodies[#bodies]).to.equal("two")
+    end)
+
+    it("closes the database pools before running the script again", function()
+      -- the exclusive lock of a pool that is left open fails the inserts of the next run
+      local script = [[
+        local database = require("database")
+        local http = require("http")
+        local db = database.new("sqlite", "tests/_watch/runs.db", { max_connections = 1 })
+        db:execute("PRAGMA locking_mode = EXCLUSIVE")
+        db:execute("CREATE TABLE IF NOT EXISTS runs (id INTEGER PRIMARY KEY)")
+        db:execute("INSERT INTO runs DEFAULT VALUES")
+        local server = http.server.new()
+        server.port = 18460
+        server:get("/", function()
+          return tostring(db:query_one("SELECT count(*) AS runs FROM runs").runs)
+        end)
+        server:run()
+      ]]
+      expect(watch(script)).to.equal("1")
+
+      fs.write_file(dir .. "/init.lua", script .. "\n-- changed\n")
+      expect(wait_for_change("1")).to.equal("2")
+    end)
+  end)
+end
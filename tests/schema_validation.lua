@@ -261,6 +261,75 @@ return function(test)
     end)
   end)
 
+  describe("errors()", function()
+    it("lists every failing path", function()
+      local User = t.struct({
+        name = t.string(),
+        age = t.integer(),
+        tags = t.array(t.string()),
+        address = t.optional(t.struct({ city = t.string() })),
+      })
+      local errors = t.errors(User, { name = 1, age = 2, tags = { "a", 3 }, address = {}, extra = true })
+      assert(#errors == 4, "expected 4 errors, got " .. #errors)
+      assert(errors[1].path == "address.city", "unexpected path " .. errors[1].path)
+      assert(errors[2].path == "extra", "unexpected path " .. errors[2].path)
+      assert(errors[3].path == "name", "unexpected path " .. errors[3].path)
+      assert(errors[4].path == "tags[2]", "unexpected path " .. errors[4].path)
+    end)
+
+    it("returns an empty list for valid values", function()
+      local Point = t.build(t.struct({ x = t.number(), y = t.number() }))
+      assert(#Point:errors({ x = 1, y = 2 }) == 0, "expected no errors")
+    end)
+  end)
+
+  describe("json_schema()", function()
+    it("describes the schema as a JSON Schema", function()
+      local User = t.struct({
+        name = t.pattern("^%a+$"),
+        age = t.optional(t.range({ min = 0, max = 150, maxExclusive = true })),
+        tags = t.array(t.integer()),
+        role = t.union(t.literal("admin"), t.literal("user")),
+      })
+      local schema = t.json_schema(User)
+      assert(schema.type == "object", "expected an object, got " .. tostring(schema.type))
+      assert(schema.additionalProperties == false, "expected no additional properties")
+      assert(#schema.required == 3, "expected 3 required fields, got " .. #schema.required)
+      assert(schema.required[1] == "name" and schema.required[3] == "tags", "unexpected required fields")
+      assert(schema.properties.name.pattern == "^[A-Za-z]+$", "expected the pattern as a regex")
+      assert(schema.properties.age.minimum == 0, "expected a minimum")
+      assert(schema.properties.age.exclusiveMaximum == 150, "expected an exclusive maximum")
+      assert(schema.properties.tags.items.type == "integer", "expected integer items")
+      assert(schema.properties.role.anyOf[2].const == "user", "expected the union members")
+    end)
+
+    it("translates Lua patterns to regexes", function()
+      local function regex(pattern)
+        return t.json_schema(t.pattern(pattern)).pattern
+      end
+      assert(regex("^%d+$") == "^[0-9]+$", "unexpected regex " .. regex("^%d+$"))
+      assert(regex("%D%.x-$") == "[^0-9]\\.x*?$", "unexpected regex " .. regex("%D%.x-$"))
+      assert(regex("[%w_-]+") == "[A-Za-z0-9_\\-]+", "unexpected regex " .. regex("[%w_-]+"))
+      assert(regex("a.b{c") == "a[\\s\\S]b\\{c", "unexpected regex " .. regex("a.b{c"))
+      assert(regex("(ab)*") == "(ab)\\*", "unexpected regex " .. regex("(ab)*"))
+    end)
+
+    it("fails on Lua patterns without a regex equivalent", function()
+      for _, pattern in ipairs({ "%b()", "%f[%w]", "(a)%1", "()", "[%D]", "[abc" }) do
+        local ok, err = pcall(t.json_schema, t.pattern(pattern))
+        assert(not ok, "expected " .. pattern .. " to fail")
+        assert(tostring(err):find("cannot be described as a regex", 1, true), "unexpected error " .. tostring(err))
+      end
+    end)
+
+    it("works on built structs", function()
+      local Point = t.build(t.struct({ x = t.number({ default = 0 }) }))
+      local schema = Point:json_schema()
+      assert(schema.properties.x.type == "number", "expected a number")
+      assert(schema.properties.x.default == 0, "expected the default to be kept")
+    end)
+  end)
+
   describe("build()", function()
     it("returns validated data on success", function()
       local Point = t.build(t.struct({ x = t.number(), y = t.number() }))
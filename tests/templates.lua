@@ -226,8 +226,15 @@ return function(test)
 
         server = http.server.new()
         server.port = port
+        server.csrf = true
         eng:add_to_server(server, { name = "world" })
 
+        local forms = templates.jinja2.new()
+        forms:add_template("form.html", '<input name="csrf_token" value="{{ csrf_token() }}">')
+        server:get("/form", function()
+          return forms:render("form.html")
+        end)
+
         task = utils.spawn_task(function()
           server:run()
         end)
@@ -237,6 +244,8 @@ return function(test)
       test.after(function()
         ---@diagnostic disable-next-line: need-check-nil
         server:shutdown(server)
+        -- the server lets go of the port once it has stopped
+        utils.spawn_timeout(function() end, 100):await()
       end)
 
       it("serves rendered template via GET", function()
@@ -255,6 +264,22 @@ return function(test)
         expect(body:find("HI") ~= nil).to.be.truthy()
       end)
 
+      it("renders the CSRF token of the request", function()
+        local res = http.request({ url = "http://127.0.0.1:" .. port .. "/form", method = "GET" }):execute()
+        expect(res:status_code()).to.equal(200)
+        local token = res:headers()["set-cookie"]:match("^csrf_token=(%x+)")
+        expect(token).to.be.a("string")
+        expect(res:body():text()).to.equal('<input name="csrf_token" value="' .. token .. '">')
+      end)
+
+      it("fails to render the CSRF token outside of a request", function()
+        local eng = templates.jinja2.new()
+        eng:add_template("form.html", "{{ csrf_token() }}")
+        expect(function()
+          eng:render("form.html")
+        end).to.fail()
+      end)
+
       it("returns 404 for unregistered template route", function()
         local req = http.request({ url = "http://127.0.0.1:" .. port .. "/nonexistent", method = "GET" })
         local res = req:execute()